@@ -0,0 +1,53 @@
+//! Count the allocations of parsing a multi-megabyte part, the figure parsing is tuned by. Run
+//! with `cargo bench --bench allocations`.
+
+mod common;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use docx_cc::get_content_controls;
+
+/// Controls of the part, making a `word/document.xml` of about 10 MB.
+const CONTROLS: usize = 35000;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting allocations and the bytes allocated.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    let data = HashMap::from([("word/document.xml".to_string(), common::part(CONTROLS))]);
+    let size = data["word/document.xml"].len();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let controlled = get_content_controls(&data).unwrap();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated;
+    black_box(controlled);
+    println!(
+        "{} MB, {} controls: {} allocations, {} MB allocated to parse",
+        size / 1_000_000,
+        CONTROLS,
+        allocations,
+        allocated / 1_000_000
+    );
+}
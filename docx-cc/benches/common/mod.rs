@@ -0,0 +1,58 @@
+//! Generated packages for the benchmarks, so they run without large fixtures.
+
+// every benchmark compiles its own copy and uses only some of the functions
+#![allow(dead_code)]
+
+use docx_cc::{Mapping, ZipData};
+
+/// Number of distinct tags of the generated controls.
+const TAGS: usize = 50;
+
+/**
+ * A part with `controls` paragraphs, each holding some text and an inline text control tagged
+ * `Field0` to `Field49`.
+ */
+pub fn part(controls: usize) -> Vec<u8> {
+    let mut xml = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+        r#"<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>"#,
+    ));
+    for i in 0..controls {
+        xml.push_str(&format!(
+            concat!(
+                r#"<w:p><w:pPr><w:pStyle w:val="Normal"/></w:pPr><w:r><w:t xml:space="preserve">Line {}: </w:t></w:r>"#,
+                r#"<w:sdt><w:sdtPr><w:alias w:val="Field{}"/><w:tag w:val="Field{}"/><w:id w:val="{}"/><w:text/></w:sdtPr>"#,
+                r#"<w:sdtContent><w:r><w:rPr><w:b/></w:rPr><w:t>Field{}</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+            ),
+            i,
+            i % TAGS,
+            i % TAGS,
+            i,
+            i % TAGS
+        ));
+    }
+    xml.push_str("</w:body></w:document>");
+    xml.into_bytes()
+}
+
+/**
+ * A package of `parts` parts with `controls` controls each, the main document and headers.
+ */
+pub fn package(parts: usize, controls: usize) -> ZipData {
+    (0..parts)
+        .map(|i| {
+            let name = match i {
+                0 => "word/document.xml".to_string(),
+                i => format!("word/header{}.xml", i),
+            };
+            (name, part(controls))
+        })
+        .collect()
+}
+
+/// A value for every tag of the generated controls.
+pub fn mappings() -> Mapping {
+    (0..TAGS)
+        .map(|i| (format!("Field{}", i), format!("Value {}", i)))
+        .collect()
+}
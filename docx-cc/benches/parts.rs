@@ -0,0 +1,48 @@
+//! Time parsing and filling a package of 30 parts, as a header and footer heavy document would
+//! have. Run with `cargo bench --bench parts`, and with `--features rayon` to compare.
+
+mod common;
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use docx_cc::{get_content_controls, map_content_controls_with, MapOptions, RepeatMapping};
+
+const PARTS: usize = 30;
+const CONTROLS: usize = 2000;
+const RUNS: u32 = 5;
+
+fn main() {
+    let data = common::package(PARTS, CONTROLS);
+    let size: usize = data.values().map(Vec::len).sum();
+    let mappings = common::mappings();
+    let run = || {
+        let controlled = get_content_controls(&data).unwrap();
+        let mapped = map_content_controls_with(
+            &data,
+            &controlled,
+            &mappings,
+            &RepeatMapping::new(),
+            &MapOptions::default(),
+        )
+        .unwrap();
+        black_box(mapped);
+    };
+    // the first run pays for growing the allocator and the thread pool
+    run();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        run();
+    }
+    println!(
+        "{} parts, {} MB: {:?} per run to parse and fill ({})",
+        PARTS,
+        size / 1_000_000,
+        start.elapsed() / RUNS,
+        if cfg!(feature = "rayon") {
+            "rayon"
+        } else {
+            "sequential"
+        }
+    );
+}
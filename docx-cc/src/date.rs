@@ -0,0 +1,276 @@
+//! Values of date content controls, parsed from ISO 8601 and displayed in the `w:dateFormat`
+//! picture of the control, e.g. `dd.MM.yyyy`.
+
+static MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+static WEEKDAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+/**
+ * A date and time of day in the proleptic Gregorian calendar, without a time zone.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl Date {
+    /**
+     * Parse an ISO 8601 date such as `2012-12-24`, optionally followed by a time of day as in
+     * `2012-12-24T18:30:00Z`. A time zone offset is accepted but not applied.
+     */
+    pub fn parse_iso(value: &str) -> Option<Date> {
+        let value = value.trim();
+        let (date, time) = match value.split_once(['T', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (value, None),
+        };
+        let mut parts = date.split('-');
+        let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return None;
+        }
+        let year: i64 = year.parse().ok()?;
+        let month: u32 = month.parse().ok()?;
+        let day: u32 = day.parse().ok()?;
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        let (hour, minute, second) = match time {
+            Some(time) => parse_time(time)?,
+            None => (0, 0, 0),
+        };
+        Some(Date {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        })
+    }
+
+    /**
+     * The date and time `seconds` after 1970-01-01T00:00:00Z, the Unix epoch.
+     */
+    pub fn from_unix(seconds: i64) -> Date {
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+        let time = seconds.rem_euclid(86400) as u32;
+        Date {
+            year,
+            month,
+            day,
+            hour: time / 3600,
+            minute: time % 3600 / 60,
+            second: time % 60,
+        }
+    }
+
+    /**
+     * The date as written to `w:fullDate`, e.g. `2012-12-24T00:00:00Z`.
+     */
+    pub fn full_date(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+
+    /**
+     * Display the date in a Word date picture. `d`, `M`, `y`, `H`, `h`, `m` and `s` runs stand
+     * for day, month, year, hours, 12 hour clock, minutes and seconds, `ddd`/`dddd` and
+     * `MMM`/`MMMM` for the abbreviated and full English day and month names and `AM/PM` for the
+     * half of the day. Text in single quotes and all other characters are copied as they are.
+     */
+    pub fn format(&self, picture: &str) -> String {
+        let chars: Vec<char> = picture.chars().collect();
+        let mut text = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let run = chars[i..].iter().take_while(|&&other| other == c).count();
+            match c {
+                'd' => text.push_str(&match run {
+                    1 => self.day.to_string(),
+                    2 => format!("{:02}", self.day),
+                    3 => WEEKDAYS[self.weekday()][..3].to_string(),
+                    _ => WEEKDAYS[self.weekday()].to_string(),
+                }),
+                'M' => text.push_str(&match run {
+                    1 => self.month.to_string(),
+                    2 => format!("{:02}", self.month),
+                    3 => MONTHS[self.month as usize - 1][..3].to_string(),
+                    _ => MONTHS[self.month as usize - 1].to_string(),
+                }),
+                'y' => text.push_str(&match run {
+                    1 | 2 => format!("{:02}", self.year.rem_euclid(100)),
+                    _ => format!("{:04}", self.year),
+                }),
+                'H' => text.push_str(&pad(self.hour, run)),
+                'h' => text.push_str(&pad((self.hour + 11) % 12 + 1, run)),
+                'm' => text.push_str(&pad(self.minute, run)),
+                's' => text.push_str(&pad(self.second, run)),
+                '\'' => {
+                    let literal: String =
+                        chars[i + 1..].iter().take_while(|&&c| c != '\'').collect();
+                    i += literal.chars().count() + 2;
+                    text.push_str(&literal);
+                    continue;
+                }
+                _ if chars[i..].starts_with(&['A', 'M', '/', 'P', 'M']) => {
+                    text.push_str(if self.hour < 12 { "AM" } else { "PM" });
+                    i += 5;
+                    continue;
+                }
+                _ if chars[i..].starts_with(&['a', 'm', '/', 'p', 'm']) => {
+                    text.push_str(if self.hour < 12 { "am" } else { "pm" });
+                    i += 5;
+                    continue;
+                }
+                _ => {
+                    text.push(c);
+                    i += 1;
+                    continue;
+                }
+            }
+            i += run;
+        }
+        text
+    }
+
+    /**
+     * Day of the week, 0 for Monday, in the proleptic Gregorian calendar.
+     */
+    fn weekday(&self) -> usize {
+        let days = days_from_civil(self.year, self.month, self.day);
+        // 1970-01-01 was a Thursday
+        (days + 3).rem_euclid(7) as usize
+    }
+}
+
+/// Days since 1970-01-01 of a civil date, from Howard Hinnant's date algorithms.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (month as i64 + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Civil date of a number of days since 1970-01-01, the inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn pad(value: u32, run: usize) -> String {
+    if run > 1 {
+        format!("{:02}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+fn parse_time(time: &str) -> Option<(u32, u32, u32)> {
+    let time = time
+        .trim_end_matches('Z')
+        .split(['+', '-'])
+        .next()
+        .unwrap_or_default();
+    let time = time.split('.').next().unwrap_or_default();
+    let mut parts = time.split(':');
+    let hour: u32 = parts.next()?.parse().ok()?;
+    let minute: u32 = parts.next()?.parse().ok()?;
+    let second: u32 = match parts.next() {
+        Some(second) => second.parse().ok()?,
+        None => 0,
+    };
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    Some((hour, minute, second))
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let date = Date::parse_iso("2012-12-24").unwrap();
+        assert_eq!(date.full_date(), "2012-12-24T00:00:00Z");
+        assert_eq!(date.format("dd.MM.yyyy"), "24.12.2012");
+        assert_eq!(
+            date.format("dddd, MMMM d, yyyy"),
+            "Monday, December 24, 2012"
+        );
+        assert_eq!(date.format("ddd d MMM yy"), "Mon 24 Dec 12");
+        assert_eq!(date.format("yyyy-MM-dd'T'HH:mm"), "2012-12-24T00:00");
+
+        let date = Date::parse_iso("2024-02-29T18:05:09Z").unwrap();
+        assert_eq!(date.full_date(), "2024-02-29T18:05:09Z");
+        assert_eq!(date.format("M/d/yyyy h:mm AM/PM"), "2/29/2024 6:05 PM");
+        assert_eq!(date.format("dddd"), "Thursday");
+
+        let date = Date::from_unix(1709229909);
+        assert_eq!(date, Date::parse_iso("2024-02-29T18:05:09Z").unwrap());
+        assert_eq!(Date::from_unix(-1).full_date(), "1969-12-31T23:59:59Z");
+        assert_eq!(
+            Date::from_unix(days_from_civil(2000, 3, 1) * 86400).format("dddd yyyy-MM-dd"),
+            "Wednesday 2000-03-01"
+        );
+
+        assert_eq!(Date::parse_iso("2023-02-29"), None);
+        assert_eq!(Date::parse_iso("12.12.2012"), None);
+        assert_eq!(Date::parse_iso("tomorrow"), None);
+    }
+}
@@ -0,0 +1,500 @@
+//! Reading of password protected documents.
+//!
+//! Encrypted documents are not zip archives but OLE compound files, holding an
+//! `EncryptionInfo` stream describing the key derivation and an `EncryptedPackage` stream with
+//! the encrypted zip archive. Both the agile encryption written by current Office versions and
+//! the older standard (CryptoAPI) AES encryption are supported, see MS-OFFCRYPTO section 2.3.4.
+
+use std::io::prelude::*;
+use std::io::Cursor;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::{Aes128, Aes192, Aes256};
+use base64::Engine;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use sha2::Digest;
+
+use crate::{list_zip_contents, DocxError, ZipData};
+
+const AES_BLOCK_SIZE: usize = 16;
+const SEGMENT_LENGTH: usize = 4096;
+
+const BLOCK_KEY_VERIFIER_INPUT: [u8; 8] = [0xfe, 0xa7, 0xd2, 0x76, 0x3b, 0x4b, 0x9e, 0x79];
+const BLOCK_KEY_VERIFIER_VALUE: [u8; 8] = [0xd7, 0xaa, 0x0f, 0x6d, 0x30, 0x61, 0x34, 0x4e];
+const BLOCK_KEY_ENCRYPTED_KEY: [u8; 8] = [0x14, 0x6e, 0x0b, 0xe7, 0xab, 0xac, 0xd0, 0xd6];
+
+const STANDARD_SPIN_COUNT: u32 = 50000;
+const ALG_ID_AES_128: u32 = 0x660E;
+const ALG_ID_AES_192: u32 = 0x660F;
+const ALG_ID_AES_256: u32 = 0x6610;
+
+fn malformed(reason: &str) -> DocxError {
+    DocxError::Decryption(reason.into())
+}
+
+#[derive(Clone, Copy)]
+enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn parse(name: &str) -> Result<Self, DocxError> {
+        match name {
+            "SHA1" | "SHA-1" => Ok(HashAlgorithm::Sha1),
+            "SHA256" => Ok(HashAlgorithm::Sha256),
+            "SHA384" => Ok(HashAlgorithm::Sha384),
+            "SHA512" => Ok(HashAlgorithm::Sha512),
+            _ => Err(malformed(&format!("unsupported hash algorithm {}", name))),
+        }
+    }
+
+    fn digest(&self, parts: &[&[u8]]) -> Vec<u8> {
+        fn run<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+            let mut hasher = D::new();
+            for part in parts {
+                hasher.update(part);
+            }
+            hasher.finalize().to_vec()
+        }
+        match self {
+            HashAlgorithm::Sha1 => run::<sha1::Sha1>(parts),
+            HashAlgorithm::Sha256 => run::<sha2::Sha256>(parts),
+            HashAlgorithm::Sha384 => run::<sha2::Sha384>(parts),
+            HashAlgorithm::Sha512 => run::<sha2::Sha512>(parts),
+        }
+    }
+
+    /**
+     * Hash the salted password and iterate the hash `spin_count` times.
+     */
+    fn iterated_password_hash(&self, password: &str, salt: &[u8], spin_count: u32) -> Vec<u8> {
+        let password: Vec<u8> = password
+            .encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect();
+        let mut hash = self.digest(&[salt, &password]);
+        for i in 0..spin_count {
+            hash = self.digest(&[&i.to_le_bytes(), &hash]);
+        }
+        hash
+    }
+}
+
+enum AesKey {
+    Aes128(Aes128),
+    Aes192(Aes192),
+    Aes256(Aes256),
+}
+
+impl AesKey {
+    fn new(key: &[u8]) -> Result<Self, DocxError> {
+        let key = match key.len() {
+            16 => AesKey::Aes128(Aes128::new(GenericArray::from_slice(key))),
+            24 => AesKey::Aes192(Aes192::new(GenericArray::from_slice(key))),
+            32 => AesKey::Aes256(Aes256::new(GenericArray::from_slice(key))),
+            _ => return Err(malformed("unsupported key length")),
+        };
+        Ok(key)
+    }
+
+    fn decrypt_block(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            AesKey::Aes128(cipher) => cipher.decrypt_block(block),
+            AesKey::Aes192(cipher) => cipher.decrypt_block(block),
+            AesKey::Aes256(cipher) => cipher.decrypt_block(block),
+        }
+    }
+}
+
+fn check_block_aligned(data: &[u8]) -> Result<(), DocxError> {
+    if !data.len().is_multiple_of(AES_BLOCK_SIZE) {
+        return Err(malformed(
+            "encrypted data is not a multiple of the block size",
+        ));
+    }
+    Ok(())
+}
+
+fn decrypt_cbc(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, DocxError> {
+    check_block_aligned(data)?;
+    let cipher = AesKey::new(key)?;
+    let mut previous = iv
+        .get(..AES_BLOCK_SIZE)
+        .ok_or_else(|| malformed("initialization vector shorter than a block"))?
+        .to_vec();
+    let mut plain = Vec::with_capacity(data.len());
+    for block in data.chunks(AES_BLOCK_SIZE) {
+        let mut decrypted = block.to_vec();
+        cipher.decrypt_block(&mut decrypted);
+        plain.extend(decrypted.iter().zip(&previous).map(|(d, p)| d ^ p));
+        previous = block.to_vec();
+    }
+    Ok(plain)
+}
+
+fn decrypt_ecb(key: &[u8], data: &[u8]) -> Result<Vec<u8>, DocxError> {
+    check_block_aligned(data)?;
+    let cipher = AesKey::new(key)?;
+    let mut plain = data.to_vec();
+    for block in plain.chunks_mut(AES_BLOCK_SIZE) {
+        cipher.decrypt_block(block);
+    }
+    Ok(plain)
+}
+
+/**
+ * Truncate or pad (with 0x36) a derived hash to the required length.
+ */
+fn fit_to_length(mut value: Vec<u8>, length: usize) -> Vec<u8> {
+    value.resize(length, 0x36);
+    value
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, DocxError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| malformed("truncated EncryptionInfo"))
+}
+
+/**
+ * Split the `EncryptedPackage` stream into the declared plain size and the encrypted data.
+ */
+fn split_package(package: &[u8]) -> Result<(usize, &[u8]), DocxError> {
+    if package.len() < 8 {
+        return Err(malformed("truncated EncryptedPackage"));
+    }
+    let mut size = [0u8; 8];
+    size.copy_from_slice(&package[..8]);
+    Ok((u64::from_le_bytes(size) as usize, &package[8..]))
+}
+
+struct AgileParameters {
+    salt: Vec<u8>,
+    key_bits: usize,
+    block_size: usize,
+    hash: Option<HashAlgorithm>,
+}
+
+impl AgileParameters {
+    fn parse(element: &BytesStart) -> Result<Self, DocxError> {
+        let mut parameters = AgileParameters {
+            salt: Vec::new(),
+            key_bits: 0,
+            block_size: AES_BLOCK_SIZE,
+            hash: None,
+        };
+        for attr in element.attributes().flatten() {
+            let value = String::from_utf8_lossy(&attr.value).to_string();
+            match attr.key.local_name().as_ref() {
+                b"saltValue" => parameters.salt = decode_base64(&value)?,
+                b"keyBits" => parameters.key_bits = parse_number(&value)?,
+                b"blockSize" => parameters.block_size = parse_number(&value)?,
+                b"hashAlgorithm" => parameters.hash = Some(HashAlgorithm::parse(&value)?),
+                b"cipherAlgorithm" if value != "AES" => {
+                    return Err(malformed(&format!("unsupported cipher {}", value)))
+                }
+                b"cipherChaining" if value != "ChainingModeCBC" => {
+                    return Err(malformed(&format!("unsupported chaining {}", value)))
+                }
+                _ => {}
+            }
+        }
+        Ok(parameters)
+    }
+
+    fn hash(&self) -> Result<HashAlgorithm, DocxError> {
+        self.hash.ok_or_else(|| malformed("missing hash algorithm"))
+    }
+
+    fn iv(&self, value: Vec<u8>) -> Vec<u8> {
+        fit_to_length(value, self.block_size)
+    }
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>, DocxError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|_| malformed("invalid base64 value in EncryptionInfo"))
+}
+
+fn parse_number(value: &str) -> Result<usize, DocxError> {
+    value
+        .parse()
+        .map_err(|_| malformed("invalid number in EncryptionInfo"))
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<String, DocxError> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attr| attr.key.local_name().as_ref() == name)
+        .map(|attr| String::from_utf8_lossy(&attr.value).to_string())
+        .ok_or_else(|| {
+            malformed(&format!(
+                "missing attribute {}",
+                String::from_utf8_lossy(name)
+            ))
+        })
+}
+
+fn decrypt_agile(info: &[u8], package: &[u8], password: &str) -> Result<Vec<u8>, DocxError> {
+    let xml = info
+        .get(8..)
+        .ok_or_else(|| malformed("truncated EncryptionInfo"))?;
+    let xml = std::str::from_utf8(xml).map_err(|_| malformed("EncryptionInfo is not utf-8"))?;
+    let mut reader = Reader::from_str(xml);
+    let mut key_data = None;
+    let mut password_key = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.local_name().as_ref() {
+                b"keyData" => key_data = Some(AgileParameters::parse(&e)?),
+                b"encryptedKey" => password_key = Some(e.into_owned()),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(_) => return Err(malformed("EncryptionInfo is not well formed xml")),
+        }
+    }
+    let key_data = key_data.ok_or_else(|| malformed("missing keyData"))?;
+    let password_key = password_key.ok_or_else(|| malformed("missing password key encryptor"))?;
+    let encryptor = AgileParameters::parse(&password_key)?;
+    let hash = encryptor.hash()?;
+    let spin_count = parse_number(&attribute(&password_key, b"spinCount")?)? as u32;
+    let key_length = encryptor.key_bits / 8;
+
+    let password_hash = hash.iterated_password_hash(password, &encryptor.salt, spin_count);
+    let block_key = |block: &[u8]| fit_to_length(hash.digest(&[&password_hash, block]), key_length);
+    let decrypt_value = |block: &[u8], name: &[u8]| -> Result<Vec<u8>, DocxError> {
+        let value = decode_base64(&attribute(&password_key, name)?)?;
+        decrypt_cbc(
+            &block_key(block),
+            &encryptor.iv(encryptor.salt.clone()),
+            &value,
+        )
+    };
+
+    let verifier_input = decrypt_value(&BLOCK_KEY_VERIFIER_INPUT, b"encryptedVerifierHashInput")?;
+    let verifier_hash = decrypt_value(&BLOCK_KEY_VERIFIER_VALUE, b"encryptedVerifierHashValue")?;
+    let verifier_input = verifier_input
+        .get(..encryptor.salt.len())
+        .ok_or_else(|| malformed("verifier hash input shorter than the salt"))?;
+    let expected_hash = hash.digest(&[verifier_input]);
+    if verifier_hash.get(..expected_hash.len()) != Some(&expected_hash[..]) {
+        return Err(DocxError::InvalidPassword);
+    }
+    let secret_key = decrypt_value(&BLOCK_KEY_ENCRYPTED_KEY, b"encryptedKeyValue")?;
+    let secret_key = secret_key
+        .get(..key_length)
+        .ok_or_else(|| malformed("encrypted key shorter than the key length"))?;
+
+    let package_hash = key_data.hash()?;
+    let (size, encrypted) = split_package(package)?;
+    let mut plain = Vec::with_capacity(encrypted.len());
+    for (i, segment) in encrypted.chunks(SEGMENT_LENGTH).enumerate() {
+        let iv = key_data.iv(package_hash.digest(&[&key_data.salt, &(i as u32).to_le_bytes()]));
+        plain.extend(decrypt_cbc(secret_key, &iv, segment)?);
+    }
+    plain.truncate(size);
+    Ok(plain)
+}
+
+fn decrypt_standard(info: &[u8], package: &[u8], password: &str) -> Result<Vec<u8>, DocxError> {
+    let header_size = read_u32(info, 8)? as usize;
+    let header = info
+        .get(12..12 + header_size)
+        .ok_or_else(|| malformed("truncated EncryptionInfo"))?;
+    let algorithm = read_u32(header, 8)?;
+    if !matches!(algorithm, ALG_ID_AES_128 | ALG_ID_AES_192 | ALG_ID_AES_256) {
+        return Err(malformed("only AES standard encryption is supported"));
+    }
+    let key_length = read_u32(header, 16)? as usize / 8;
+
+    let verifier = info
+        .get(12 + header_size..)
+        .ok_or_else(|| malformed("truncated EncryptionInfo"))?;
+    let salt_size = read_u32(verifier, 0)? as usize;
+    let salt = verifier
+        .get(4..4 + salt_size)
+        .ok_or_else(|| malformed("truncated EncryptionVerifier"))?;
+    let encrypted_verifier = verifier
+        .get(4 + salt_size..4 + salt_size + AES_BLOCK_SIZE)
+        .ok_or_else(|| malformed("truncated EncryptionVerifier"))?;
+    let hash_offset = 4 + salt_size + AES_BLOCK_SIZE;
+    let hash_size = read_u32(verifier, hash_offset)? as usize;
+    let encrypted_hash = verifier
+        .get(hash_offset + 4..)
+        .ok_or_else(|| malformed("truncated EncryptionVerifier"))?;
+
+    // key derivation of MS-OFFCRYPTO 2.3.4.7, always based on SHA-1
+    let hash = HashAlgorithm::Sha1;
+    let password_hash = hash.iterated_password_hash(password, salt, STANDARD_SPIN_COUNT);
+    let final_hash = hash.digest(&[&password_hash, &0u32.to_le_bytes()]);
+    let derive = |fill: u8| -> Vec<u8> {
+        let mut buffer = [fill; 64];
+        for (b, h) in buffer.iter_mut().zip(&final_hash) {
+            *b ^= h;
+        }
+        hash.digest(&[&buffer])
+    };
+    let mut key = derive(0x36);
+    key.extend(derive(0x5c));
+    key.truncate(key_length);
+
+    let verifier = decrypt_ecb(&key, encrypted_verifier)?;
+    let verifier_hash = decrypt_ecb(&key, encrypted_hash)?;
+    if verifier_hash.get(..hash_size) != Some(&hash.digest(&[&verifier])[..]) {
+        return Err(DocxError::InvalidPassword);
+    }
+
+    let (size, encrypted) = split_package(package)?;
+    let mut plain = decrypt_ecb(&key, encrypted)?;
+    plain.truncate(size);
+    Ok(plain)
+}
+
+fn read_stream<F: Read + Seek>(
+    compound: &mut cfb::CompoundFile<F>,
+    name: &str,
+) -> Result<Vec<u8>, DocxError> {
+    let mut stream = compound
+        .open_stream(name)
+        .map_err(|_| malformed(&format!("missing {} stream", name)))?;
+    let mut buffer = Vec::new();
+    stream
+        .read_to_end(&mut buffer)
+        .map_err(|e| malformed(&e.to_string()))?;
+    Ok(buffer)
+}
+
+/**
+ * Decrypt a password protected document into the bytes of the plain zip archive.
+ */
+pub fn decrypt_docx(reader: impl Read + Seek, password: &str) -> Result<Vec<u8>, DocxError> {
+    let mut compound = cfb::CompoundFile::open(reader)
+        .map_err(|_| malformed("not an encrypted document (no OLE compound file)"))?;
+    let info = read_stream(&mut compound, "/EncryptionInfo")?;
+    let package = read_stream(&mut compound, "/EncryptedPackage")?;
+    let major = info.first().copied().unwrap_or_default();
+    let minor = info.get(2).copied().unwrap_or_default();
+    match (major, minor) {
+        (4, 4) => decrypt_agile(&info, &package, password),
+        (2..=4, 2) => decrypt_standard(&info, &package, password),
+        _ => Err(malformed(&format!(
+            "unsupported encryption version {}.{}",
+            major, minor
+        ))),
+    }
+}
+
+/**
+ * Open a password protected document, decrypting it and reading the contained archive.
+ */
+pub fn open_encrypted_docx(reader: impl Read + Seek, password: &str) -> Result<ZipData, DocxError> {
+    let package = decrypt_docx(reader, password)?;
+    list_zip_contents(Cursor::new(package)).map_err(DocxError::Zip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn open_fixture(path: &str, password: &str) -> Result<ZipData, DocxError> {
+        open_encrypted_docx(fs::File::open(path).unwrap(), password)
+    }
+
+    /**
+     * The encrypted fixture at `path` as a new compound file with its `EncryptionInfo` stream
+     * replaced by `edit` of it.
+     */
+    fn with_encryption_info(path: &str, edit: impl Fn(&[u8]) -> Vec<u8>) -> Cursor<Vec<u8>> {
+        let mut compound = cfb::CompoundFile::open(fs::File::open(path).unwrap()).unwrap();
+        let info = read_stream(&mut compound, "/EncryptionInfo").unwrap();
+        let package = read_stream(&mut compound, "/EncryptedPackage").unwrap();
+        let mut edited = cfb::CompoundFile::create(Cursor::new(Vec::new())).unwrap();
+        let mut stream = edited.create_stream("/EncryptionInfo").unwrap();
+        stream.write_all(&edit(&info)).unwrap();
+        drop(stream);
+        let mut stream = edited.create_stream("/EncryptedPackage").unwrap();
+        stream.write_all(&package).unwrap();
+        drop(stream);
+        edited.flush().unwrap();
+        let mut cursor = edited.into_inner();
+        cursor.set_position(0);
+        cursor
+    }
+
+    fn load_plain() -> ZipData {
+        list_zip_contents(fs::File::open("tests/data/run_with_params.docx").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn agile_encryption() {
+        let data = open_fixture("tests/data/encrypted_agile.docx", "docx-cc").unwrap();
+        assert_eq!(data, load_plain());
+    }
+
+    #[test]
+    fn standard_encryption() {
+        let data = open_fixture("tests/data/encrypted_standard.docx", "docx-cc").unwrap();
+        assert_eq!(data, load_plain());
+    }
+
+    #[test]
+    fn wrong_password() {
+        for path in [
+            "tests/data/encrypted_agile.docx",
+            "tests/data/encrypted_standard.docx",
+        ] {
+            let result = open_fixture(path, "wrong");
+            assert!(matches!(result, Err(DocxError::InvalidPassword)));
+        }
+    }
+
+    #[test]
+    fn truncated_encryption_info() {
+        let standard = "tests/data/encrypted_standard.docx";
+        let mut compound = cfb::CompoundFile::open(fs::File::open(standard).unwrap()).unwrap();
+        let info_length = read_stream(&mut compound, "/EncryptionInfo").unwrap().len();
+        let unchanged = with_encryption_info(standard, |info| info.to_vec());
+        assert_eq!(
+            open_encrypted_docx(unchanged, "docx-cc").unwrap(),
+            load_plain()
+        );
+        for (path, lengths) in [
+            ("tests/data/encrypted_agile.docx", 0..12),
+            (standard, 0..info_length),
+        ] {
+            for length in lengths {
+                let reader = with_encryption_info(path, |info| info[..length].to_vec());
+                assert!(
+                    decrypt_docx(reader, "docx-cc").is_err(),
+                    "{} at {}",
+                    path,
+                    length
+                );
+            }
+        }
+
+        // a password salt longer than the verifier it is checked against
+        let reader = with_encryption_info("tests/data/encrypted_agile.docx", |info| {
+            let xml = String::from_utf8_lossy(info).to_string();
+            let start = xml.rfind(r#"saltValue=""#).unwrap() + r#"saltValue=""#.len();
+            let end = start + xml[start..].find('"').unwrap();
+            let salt = base64::engine::general_purpose::STANDARD.encode([0u8; 64]);
+            format!("{}{}{}", &xml[..start], salt, &xml[end..]).into_bytes()
+        });
+        assert!(matches!(
+            decrypt_docx(reader, "docx-cc"),
+            Err(DocxError::Decryption(_))
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+//! Lightweight inline markup of plain mapping values, `<b>`, `<i>`, `<sub>` and `<sup>` tags and
+//! `**bold**` and `*italic*` spans, e.g. `H<sub>2</sub>O`.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VertAlign {
+    Subscript,
+    Superscript,
+}
+
+impl VertAlign {
+    /// The `w:val` of `w:vertAlign`.
+    pub fn value(&self) -> &'static str {
+        match self {
+            VertAlign::Subscript => "subscript",
+            VertAlign::Superscript => "superscript",
+        }
+    }
+}
+
+/**
+ * Text formatted the same way throughout.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Span {
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub vert_align: Option<VertAlign>,
+}
+
+/**
+ * Split a value into formatted spans, `None` if it has no inline markup. Tags open and close
+ * formatting as they occur, while `**` and `*` only open a span if they are closed later on, so
+ * a lone `*` as in `2 * 3` is kept. Anything else, including unknown tags, is text.
+ */
+pub fn parse(value: &str) -> Option<Vec<Span>> {
+    let mut spans = Vec::new();
+    // formatting of the text being collected in `current`
+    let mut current = Span::default();
+    let mut found = false;
+    let mut strong = false;
+    let mut emphasis = false;
+    let mut rest = value;
+    while let Some(c) = rest.chars().next() {
+        let mut format = Span {
+            text: String::new(),
+            ..current.clone()
+        };
+        let tag = TAGS.iter().find(|tag| rest.starts_with(*tag));
+        let len = if let Some(tag) = tag {
+            match *tag {
+                "<b>" => format.bold = true,
+                "</b>" => format.bold = strong,
+                "<i>" => format.italic = true,
+                "</i>" => format.italic = emphasis,
+                "<sub>" => format.vert_align = Some(VertAlign::Subscript),
+                "<sup>" => format.vert_align = Some(VertAlign::Superscript),
+                _ => format.vert_align = None,
+            }
+            tag.len()
+        } else if rest.starts_with("**") && (strong || rest[2..].contains("**")) {
+            strong = !strong;
+            format.bold = strong;
+            2
+        } else if c == '*' && (emphasis || closes_emphasis(&rest[1..])) {
+            emphasis = !emphasis;
+            format.italic = emphasis;
+            1
+        } else {
+            current.text.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        found = true;
+        let previous = std::mem::replace(&mut current, format);
+        if !previous.text.is_empty() {
+            spans.push(previous);
+        }
+        rest = &rest[len..];
+    }
+    if !current.text.is_empty() {
+        spans.push(current);
+    }
+    found.then_some(spans)
+}
+
+const TAGS: [&str; 8] = [
+    "<b>", "</b>", "<i>", "</i>", "<sub>", "</sub>", "<sup>", "</sup>",
+];
+
+/// Whether a single `*` follows that is not part of a `**`.
+fn closes_emphasis(rest: &str) -> bool {
+    let mut rest = rest;
+    while let Some(index) = rest.find('*') {
+        if rest[index..].starts_with("**") {
+            rest = &rest[index + 2..];
+        } else {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(text: &str, bold: bool, italic: bool, vert_align: Option<VertAlign>) -> Span {
+        Span {
+            text: text.into(),
+            bold,
+            italic,
+            vert_align,
+        }
+    }
+
+    #[test]
+    fn parse_spans() {
+        assert_eq!(
+            parse("H<sub>2</sub>O and E=mc<sup>2</sup>").unwrap(),
+            vec![
+                span("H", false, false, None),
+                span("2", false, false, Some(VertAlign::Subscript)),
+                span("O and E=mc", false, false, None),
+                span("2", false, false, Some(VertAlign::Superscript)),
+            ]
+        );
+        assert_eq!(
+            parse("a **bold *and italic*** <i>text</i>").unwrap(),
+            vec![
+                span("a ", false, false, None),
+                span("bold ", true, false, None),
+                span("and italic", true, true, None),
+                span(" ", false, false, None),
+                span("text", false, true, None),
+            ]
+        );
+        assert_eq!(parse("2 * 3 <u>x</u>"), None);
+        assert_eq!(parse("plain"), None);
+    }
+}
@@ -1,36 +1,254 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
 use std::io::prelude::*;
 use std::io::Cursor;
+use std::path::Path;
 use std::str;
+use std::sync::Arc;
 
-use quick_xml::events::Event;
+use base64::Engine;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::name::QName;
 use quick_xml::Writer;
 use zip::write::FileOptions;
 
 use quick_xml::reader::Reader;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+mod date;
+pub use date::Date;
+
+mod inline;
+
+#[cfg(feature = "decrypt")]
+mod decrypt;
+#[cfg(feature = "decrypt")]
+pub use decrypt::{decrypt_docx, open_encrypted_docx};
+
+#[cfg(feature = "manifest")]
+mod manifest;
+#[cfg(feature = "manifest")]
+pub use manifest::{manifest, Manifest};
+
+mod pdf;
+#[cfg(feature = "libreoffice")]
+pub use pdf::LibreOfficeRenderer;
+pub use pdf::PdfRenderer;
 
 static MISSING_STR: &str = "MISSING";
 
 pub type ZipData = HashMap<String, Vec<u8>>;
 pub type Mapping = HashMap<String, String>;
 pub type RepeatMapping = HashMap<String, Vec<Mapping>>;
+pub type MappingValue = serde_json::Value;
+pub type TypedMapping = HashMap<String, MappingValue>;
+pub type NestedItem = HashMap<String, NestedValue>;
+pub type NestedRepeatMapping = HashMap<String, Vec<NestedItem>>;
+
+/**
+ * Value of a field of a repeating section item in a `NestedRepeatMapping`, either the text of a
+ * control or the items of a repeating section nested in the item.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum NestedValue {
+    Text(String),
+    Items(Vec<NestedItem>),
+}
+
+/**
+ * The text fields of a nested item, with which the controls of the item are filled.
+ */
+fn nested_texts(item: &NestedItem) -> Mapping {
+    item.iter()
+        .filter_map(|(tag, value)| match value {
+            NestedValue::Text(text) => Some((tag.clone(), text.clone())),
+            NestedValue::Items(_) => None,
+        })
+        .collect()
+}
+
+/**
+ * The text fields of nested items and of all items nested within them.
+ */
+fn flatten_nested<'n>(items: impl IntoIterator<Item = &'n NestedItem>, rows: &mut Vec<Mapping>) {
+    for item in items {
+        rows.push(nested_texts(item));
+        for value in item.values() {
+            if let NestedValue::Items(nested) = value {
+                flatten_nested(nested, rows);
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
-struct ParserError {}
+pub enum DocxError {
+    /// A part is not valid UTF-8.
+    Encoding {
+        part: String,
+        source: str::Utf8Error,
+    },
+    /// A part is not well formed XML.
+    Xml {
+        part: String,
+        position: usize,
+        source: quick_xml::Error,
+    },
+    /// The package is not a readable zip archive.
+    Zip(zip::result::ZipError),
+    /// Reading or writing files failed.
+    Io(std::io::Error),
+    /// Converting a document to PDF failed.
+    Render(String),
+    /// A mapping value for a tag cannot be used, such as a malformed image.
+    InvalidValue { tag: String, reason: String },
+    /// An encrypted package is malformed or uses an unsupported encryption.
+    Decryption(String),
+    /// The password of an encrypted package does not match.
+    InvalidPassword,
+    /// A mapping value is longer than the maximum length configured for its tag.
+    ValueTooLong { tag: String, len: usize, max: usize },
+    /// The tag of a repeating section has a single value instead of repeat data.
+    ExpectedRepeatData { tag: String },
+    /// A content control is not structured as the schema requires, such as having more than one
+    /// `w:sdtContent`. Parts with such a control are rejected rather than guessing how its
+    /// content is meant.
+    MalformedControl {
+        part: String,
+        tag: String,
+        reason: String,
+    },
+}
 
-impl fmt::Display for ParserError {
+impl fmt::Display for DocxError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Parser error, probably malformed xml tags")
+        match self {
+            DocxError::Encoding { part, source } => {
+                write!(f, "Part '{}' is not utf-8 encoded: {}", part, source)
+            }
+            DocxError::Xml {
+                part,
+                position,
+                source,
+            } => write!(
+                f,
+                "Malformed xml in part '{}' at position {}: {}",
+                part, position, source
+            ),
+            DocxError::Zip(source) => write!(f, "Invalid zip archive: {}", source),
+            DocxError::Io(source) => write!(f, "I/O error: {}", source),
+            DocxError::Render(reason) => write!(f, "Could not render document: {}", reason),
+            DocxError::InvalidValue { tag, reason } => {
+                write!(f, "Invalid value for tag {}: {}", tag, reason)
+            }
+            DocxError::Decryption(reason) => write!(f, "Could not decrypt package: {}", reason),
+            DocxError::InvalidPassword => write!(f, "Invalid password for encrypted package"),
+            DocxError::ValueTooLong { tag, len, max } => write!(
+                f,
+                "Value for '{}' has {} characters, exceeding the maximum of {}",
+                tag, len, max
+            ),
+            DocxError::ExpectedRepeatData { tag } => write!(
+                f,
+                "Tag '{}' is a repeating section and needs a list of items, not a single value",
+                tag
+            ),
+            DocxError::MalformedControl { part, tag, reason } => write!(
+                f,
+                "Malformed content control '{}' in part '{}': {}",
+                tag, part, reason
+            ),
+        }
+    }
+}
+
+impl Error for DocxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DocxError::Encoding { source, .. } => Some(source),
+            DocxError::Xml { source, .. } => Some(source),
+            DocxError::Zip(source) => Some(source),
+            DocxError::Io(source) => Some(source),
+            DocxError::Decryption(_)
+            | DocxError::InvalidPassword
+            | DocxError::Render(_)
+            | DocxError::InvalidValue { .. }
+            | DocxError::ValueTooLong { .. }
+            | DocxError::ExpectedRepeatData { .. }
+            | DocxError::MalformedControl { .. } => None,
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for DocxError {
+    fn from(source: zip::result::ZipError) -> Self {
+        DocxError::Zip(source)
+    }
+}
+
+impl From<std::io::Error> for DocxError {
+    fn from(source: std::io::Error) -> Self {
+        DocxError::Io(source)
+    }
+}
+
+/**
+ * Write every part as a file below `dir`, creating directories for the paths within the package.
+ */
+pub fn write_unzipped(data: &ZipData, dir: &Path) -> Result<(), DocxError> {
+    for (name, content) in data {
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(DocxError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Part name '{}' leaves the output directory", name),
+            )));
+        }
+        let path = dir.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, content)?;
     }
+    Ok(())
 }
 
-impl Error for ParserError {}
+/**
+ * Read all files below `dir` as parts, named by their path relative to `dir`.
+ */
+pub fn read_unzipped(dir: &Path) -> Result<ZipData, DocxError> {
+    let mut data = ZipData::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let name = path
+                .strip_prefix(dir)
+                .expect("entries are below the read directory")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            data.insert(name, std::fs::read(&path)?);
+        }
+    }
+    Ok(data)
+}
 
 pub fn list_zip_contents(reader: impl Read + Seek) -> zip::result::ZipResult<ZipData> {
     let mut zip = zip::ZipArchive::new(reader)?;
@@ -57,8 +275,9 @@ pub fn zip_dir<W: Write + Seek>(
 
     for (key, value) in data {
         writer.start_file(key, options)?;
-        let _ = writer.write_all(value);
+        writer.write_all(value)?;
     }
+    writer.finish()?;
     Ok(())
 }
 
@@ -69,13 +288,14 @@ fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
 }
 
 /**
- * Check if the string contains an sdt tag (Ruby Inline-Level Structured Document Tag)
+ * Check if the string contains an sdt tag (Ruby Inline-Level Structured Document Tag), which
+ * may carry namespace declarations.
  */
 fn has_content_control(text: &[u8]) -> bool {
-    find_subsequence(text, b"<w:sdt>").is_some()
+    find_subsequence(text, b"<w:sdt>").is_some() || find_subsequence(text, b"<w:sdt ").is_some()
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum ContentControlType {
     Unsupported,
     RichText,
@@ -85,6 +305,7 @@ pub enum ContentControlType {
     Date,
     RepeatingSection,
     RepeatingSectionItem,
+    Checkbox,
 }
 
 impl ContentControlType {
@@ -97,11 +318,26 @@ impl ContentControlType {
             "w:date" => Some(ContentControlType::Date),
             "w15:repeatingSection" => Some(ContentControlType::RepeatingSection),
             "w15:repeatingSectionItem" => Some(ContentControlType::RepeatingSectionItem),
+            "w14:checkbox" => Some(ContentControlType::Checkbox),
             _ => None,
         }
     }
 }
 
+/**
+ * Content control types defined by the standard that are not handled specifically, a control
+ * declaring one of these is reported as `ContentControlType::Unsupported`.
+ */
+static UNSUPPORTED_TYPE_ELEMENTS: &[&str] = &[
+    "w:picture",
+    "w:docPartObj",
+    "w:docPartList",
+    "w:group",
+    "w:citation",
+    "w:bibliography",
+    "w:equation",
+];
+
 impl fmt::Display for ContentControlType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -115,19 +351,64 @@ impl fmt::Display for ContentControlType {
                 ContentControlType::Date => "w:date".to_string(),
                 ContentControlType::RepeatingSection => "w15:repeatingSection".to_string(),
                 ContentControlType::RepeatingSectionItem => "w15:repeatingSectionItem".to_string(),
+                ContentControlType::Checkbox => "w14:checkbox".to_string(),
                 ContentControlType::Unsupported => "unsupported".to_string(),
             }
         )
     }
 }
 
+/**
+ * Level at which a content control appears in the document structure, this determines what
+ * content the control may hold.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ControlLevel {
+    /// Control within a paragraph, holds runs.
+    Inline,
+    /// Control in the body, a table cell or similar, holds paragraphs and tables.
+    Block,
+    /// Control within a table, holds table rows.
+    Row,
+    /// Control within a table row, holds table cells.
+    Cell,
+}
+
+impl ControlLevel {
+    /**
+     * Derive the level from the open elements enclosing a new `w:sdt`, innermost last.
+     */
+    fn from_ancestors(ancestors: &[String]) -> ControlLevel {
+        for name in ancestors.iter().rev() {
+            match name.as_str() {
+                "w:p" => return ControlLevel::Inline,
+                "w:tr" => return ControlLevel::Cell,
+                "w:tbl" => return ControlLevel::Row,
+                "w:body" | "w:tc" | "w:txbxContent" | "w:hdr" | "w:ftr" | "w:footnote"
+                | "w:endnote" | "w:comment" | "w:docPartBody" => return ControlLevel::Block,
+                _ => {}
+            }
+        }
+        ControlLevel::Block
+    }
+}
+
+/// Children of `w:sdtPr` captured into the metadata of a control: the alias, tag, id, label,
+/// color and lock.
+pub const METADATA_ELEMENTS: &[&str] =
+    &["w:alias", "w:tag", "w:id", "w:label", "w15:color", "w:lock"];
+
+/**
+ * Names of the elements in an XML fragment. Text that is not well-formed XML has none, as it is
+ * plain text rather than markup.
+ */
 fn get_tag_types(content: &str) -> HashSet<String> {
     let mut content_reader = Reader::from_str(content);
     let mut tag_names = HashSet::new();
     loop {
-        let event = content_reader
-            .read_event()
-            .expect("should be a well formatted xml");
+        let Ok(event) = content_reader.read_event() else {
+            return HashSet::new();
+        };
         match event {
             Event::Eof => break,
             Event::Start(e) => {
@@ -142,46 +423,229 @@ fn get_tag_types(content: &str) -> HashSet<String> {
     tag_names
 }
 
-fn get_intersecting_control_position(
-    index: i64,
-    controls: &[ContentControlPosition],
-) -> Option<&ContentControlPosition> {
-    controls
-        .iter()
-        .find(|&control| control.intersects_content(index as i32))
-}
-
-fn get_contained_control_at<'a>(
-    controls: &'a [ContentControlPosition],
-    control: &'a ContentControlPosition,
-    index: i32,
-) -> Option<&'a ContentControlPosition> {
-    get_contained_control(controls, control).find(|&c| c.intersects_content(index))
-}
-
 fn write_parsed_content<W>(writer: &mut Writer<W>, content: &str) -> Result<(), quick_xml::Error>
 where
     W: std::io::Write,
 {
     let mut content_reader = Reader::from_str(content);
     loop {
-        let event = content_reader
-            .read_event()
-            .expect("should be a well formatted xml");
-        let _ = match event {
+        match content_reader.read_event()? {
             Event::Eof => break,
-            _ => writer.write_event(event),
-        };
+            event => writer.write_event(event)?,
+        }
     }
     Ok(())
 }
 
+/// Children of `w:rPr` in the order of the schema.
+const RUN_PROPERTY_ORDER: &[&str] = &[
+    "w:rStyle",
+    "w:rFonts",
+    "w:b",
+    "w:bCs",
+    "w:i",
+    "w:iCs",
+    "w:caps",
+    "w:smallCaps",
+    "w:strike",
+    "w:dstrike",
+    "w:outline",
+    "w:shadow",
+    "w:emboss",
+    "w:imprint",
+    "w:noProof",
+    "w:snapToGrid",
+    "w:vanish",
+    "w:webHidden",
+    "w:color",
+    "w:spacing",
+    "w:w",
+    "w:kern",
+    "w:position",
+    "w:sz",
+    "w:szCs",
+    "w:highlight",
+    "w:u",
+    "w:effect",
+    "w:bdr",
+    "w:shd",
+    "w:fitText",
+    "w:vertAlign",
+    "w:rtl",
+    "w:cs",
+    "w:em",
+    "w:lang",
+    "w:eastAsianLayout",
+    "w:specVanish",
+    "w:oMath",
+    "w:rPrChange",
+];
+
+/**
+ * The children of `w:rPr` before and after `element` in the schema.
+ */
+fn run_property_neighbours(element: &str) -> (&'static [&'static str], &'static [&'static str]) {
+    let index = RUN_PROPERTY_ORDER
+        .iter()
+        .position(|name| *name == element)
+        .expect("should be a run property");
+    (
+        &RUN_PROPERTY_ORDER[..index],
+        &RUN_PROPERTY_ORDER[index + 1..],
+    )
+}
+
+/// Children of `w:pPr` ordered after `w:bidi` by the schema.
+const AFTER_BIDI: &[&str] = &[
+    "w:adjustRightInd",
+    "w:snapToGrid",
+    "w:spacing",
+    "w:ind",
+    "w:contextualSpacing",
+    "w:mirrorIndents",
+    "w:suppressOverlap",
+    "w:jc",
+    "w:textDirection",
+    "w:textAlignment",
+    "w:textboxTightWrap",
+    "w:outlineLvl",
+    "w:divId",
+    "w:cnfStyle",
+    "w:rPr",
+    "w:sectPr",
+    "w:pPrChange",
+];
+
+/**
+ * Properties element such as `w:rPr` from its events, with the empty `element` added as a direct
+ * child before the first of `followers` unless it is already present. Without events a
+ * properties element holding only `element` is returned.
+ */
+fn with_property<'a>(
+    properties: &[Event<'a>],
+    container: &str,
+    element: &str,
+    followers: &[&str],
+) -> Vec<Event<'a>> {
+    let marker = Event::Empty(BytesStart::new(element.to_string()));
+    if properties.is_empty() {
+        return vec![
+            Event::Start(BytesStart::new(container.to_string())),
+            marker,
+            Event::End(BytesEnd::new(container.to_string())),
+        ];
+    }
+    let present = properties.iter().any(|ev| match ev {
+        Event::Start(e) | Event::Empty(e) => e.name() == QName(element.as_bytes()),
+        _ => false,
+    });
+    let mut inserted = present;
+    let mut depth = 0;
+    let mut result = Vec::with_capacity(properties.len() + 1);
+    for ev in properties {
+        match ev {
+            Event::Start(e) | Event::Empty(e) if depth == 1 && !inserted => {
+                let name = String::from_utf8_lossy(e.name().into_inner()).to_string();
+                if followers.contains(&name.as_str()) {
+                    result.push(marker.clone());
+                    inserted = true;
+                }
+            }
+            Event::End(_) if depth == 1 && !inserted => {
+                result.push(marker.clone());
+                inserted = true;
+            }
+            _ => {}
+        }
+        match ev {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        result.push(ev.clone());
+    }
+    result
+}
+
+/**
+ * Properties element such as `w:rPr` from its events, with `element` set to `value` in its
+ * `w:val`, replacing the element if present. It is placed before the first child not among
+ * `preceding`, the children ordered before it by the schema. Without events, or for an empty
+ * properties element, a properties element holding only `element` is returned.
+ */
+fn with_val_property<'a>(
+    properties: &[Event<'a>],
+    container: &str,
+    element: &str,
+    value: &str,
+    preceding: &[&str],
+) -> Vec<Event<'a>> {
+    let mut property = BytesStart::new(element.to_string());
+    property.push_attribute(("w:val", value));
+    let property = Event::Empty(property);
+    if properties.len() < 2 {
+        return vec![
+            Event::Start(BytesStart::new(container.to_string())),
+            property,
+            Event::End(BytesEnd::new(container.to_string())),
+        ];
+    }
+    let mut result = Vec::with_capacity(properties.len() + 1);
+    let mut depth = 0;
+    let mut skipped_depth = None;
+    let mut inserted = false;
+    for ev in properties {
+        if let Some(skipped) = skipped_depth {
+            match ev {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                _ => {}
+            }
+            if depth == skipped {
+                skipped_depth = None;
+            }
+            continue;
+        }
+        match ev {
+            Event::Start(e) | Event::Empty(e) if depth == 1 => {
+                if e.name() == QName(element.as_bytes()) {
+                    if let Event::Start(_) = ev {
+                        skipped_depth = Some(depth);
+                        depth += 1;
+                    }
+                    continue;
+                }
+                let name = String::from_utf8_lossy(e.name().into_inner()).to_string();
+                if !inserted && !preceding.contains(&name.as_str()) {
+                    result.push(property.clone());
+                    inserted = true;
+                }
+            }
+            Event::End(_) if depth == 1 && !inserted => {
+                result.push(property.clone());
+                inserted = true;
+            }
+            _ => {}
+        }
+        match ev {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        result.push(ev.clone());
+    }
+    result
+}
+
+/// Children of `w:sdtPr` ordered before `w:lock` by the schema.
+const BEFORE_LOCK: &[&str] = &["w:rPr", "w:alias", "w:tag", "w:id"];
+
 fn write_wrap_tags<W>(
     writer: &mut Writer<W>,
-    control: &ContentControlPosition,
     content: &str,
     tags: &[&str],
-    events: &[Event],
+    paragraph_properties: &[Event],
+    run_properties: &[Event],
 ) -> Result<(), quick_xml::Error>
 where
     W: std::io::Write,
@@ -192,29 +656,27 @@ where
         if content_tags.contains(tag) {
             write_parsed_content(writer, content)?
         } else {
-            let _ = writer.create_element(tag).write_inner_content(|writer| {
-                match tag {
-                    "w:p" => {
-                        if control.has_paragraph_params() {
-                            for ev in &events[control.paragraph_params_start as usize
-                                ..control.paragraph_params_end as usize]
-                            {
-                                let _ = writer.write_event(ev.clone());
-                            }
-                        }
-                    }
-                    "w:r" => {
-                        if control.has_run_params() {
-                            for ev in &events
-                                [control.run_params_start as usize..control.run_params_end as usize]
-                            {
-                                let _ = writer.write_event(ev.clone());
-                            }
-                        }
-                    }
-                    _ => {}
+            let mut element = writer.create_element(tag);
+            // whitespace at the edges of text is dropped by Word unless marked as significant
+            if tag == "w:t" && content.trim() != content {
+                element = element.with_attribute(("xml:space", "preserve"));
+            }
+            let _ = element.write_inner_content(|writer| {
+                let properties = match tag {
+                    "w:p" => paragraph_properties,
+                    "w:r" => run_properties,
+                    _ => &[],
+                };
+                for ev in properties {
+                    let _ = writer.write_event(ev.clone());
                 }
-                write_wrap_tags(writer, control, content, &tags[1..], events)
+                write_wrap_tags(
+                    writer,
+                    content,
+                    &tags[1..],
+                    paragraph_properties,
+                    run_properties,
+                )
             });
         }
     } else {
@@ -223,670 +685,8572 @@ where
     Ok(())
 }
 
+/**
+ * Adjustments to the paragraph and run generated for a filled value.
+ */
+#[derive(Default)]
+struct GeneratedFormat<'a> {
+    inherit_run_properties: bool,
+    run_format: Option<&'a RunFormat>,
+    rtl: bool,
+    run_style: Option<&'a str>,
+    no_proof: bool,
+    /// Spans of a value with inline markup, written as one run each.
+    inline_spans: Option<Vec<inline::Span>>,
+}
+
+/**
+ * A run of `span` with its formatting added to `run_properties`.
+ */
+fn inline_run(
+    control: &ContentControlPosition,
+    span: &inline::Span,
+    run_properties: &[Event],
+) -> String {
+    let mut properties = run_properties.to_vec();
+    if span.bold {
+        properties = with_property(
+            &properties,
+            "w:rPr",
+            "w:b",
+            run_property_neighbours("w:b").1,
+        );
+    }
+    if span.italic {
+        properties = with_property(
+            &properties,
+            "w:rPr",
+            "w:i",
+            run_property_neighbours("w:i").1,
+        );
+    }
+    if let Some(vert_align) = span.vert_align {
+        properties = with_val_property(
+            &properties,
+            "w:rPr",
+            "w:vertAlign",
+            vert_align.value(),
+            run_property_neighbours("w:vertAlign").0,
+        );
+    }
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    for ev in properties {
+        let _ = writer.write_event(ev);
+    }
+    let properties = String::from_utf8_lossy(&writer.into_inner().into_inner()).into_owned();
+    let escaped = quick_xml::escape::escape(&span.text);
+    let text = text_control_value(control, &escaped);
+    if get_tag_types(&text).is_empty() {
+        format!(
+            r#"<w:r>{}<w:t xml:space="preserve">{}</w:t></w:r>"#,
+            properties, text
+        )
+    } else {
+        format!("<w:r>{}{}</w:r>", properties, text)
+    }
+}
+
+/**
+ * Events of the first `w:rPr` that is a direct child of a `parent` element.
+ */
+fn first_properties<'e, 'a>(events: &'e [Event<'a>], parent: &[u8]) -> Option<&'e [Event<'a>]> {
+    let mut ancestors: Vec<&[u8]> = Vec::new();
+    let mut start = None;
+    for (i, ev) in events.iter().enumerate() {
+        match ev {
+            Event::Start(e) => {
+                if start.is_none()
+                    && e.name() == QName(b"w:rPr")
+                    && ancestors.last() == Some(&parent)
+                {
+                    start = Some((i, ancestors.len()));
+                }
+                ancestors.push(e.name().into_inner());
+            }
+            Event::End(_) => {
+                ancestors.pop();
+                if let Some((start, depth)) = start {
+                    if ancestors.len() == depth {
+                        return Some(&events[start..=i]);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
 fn write_content<'a, W>(
     control: &ContentControlPosition,
     writer: &'a mut Writer<W>,
     content: &'a str,
     events: &[Event],
+    format: &GeneratedFormat,
 ) -> Result<(), &'a str>
 where
     W: std::io::Write,
 {
-    if control.contains_paragraph {
-        let _ = write_wrap_tags(writer, control, content, &["w:p", "w:r", "w:t"], events);
+    let mut paragraph_properties = if control.has_paragraph_params() {
+        events[control.paragraph_params_start as usize..control.paragraph_params_end as usize]
+            .to_vec()
     } else {
-        let _ = write_wrap_tags(writer, control, content, &["w:r", "w:t"], events);
-    }
-    Ok(())
-}
-
-pub struct DocumentData<'a> {
-    events: Vec<Event<'a>>,
-    pub control_positions: Vec<ContentControlPosition>,
-}
-
-type ParsedDocuments<'a> = HashMap<String, DocumentData<'a>>;
-
-#[derive(Debug, Serialize)]
-pub struct ContentControlPosition {
-    r#type: ContentControlType,
-    tag: String,
-    begin: i32,
-    end: i32,
-    content_begin: i32,
-    content_end: i32,
-    paragraph_params_start: i32,
-    paragraph_params_end: i32,
-    contains_paragraph: bool,
-    run_params_start: i32,
-    run_params_end: i32,
-}
-
-impl ContentControlPosition {
-    fn new() -> Self {
-        ContentControlPosition {
-            r#type: ContentControlType::Unsupported,
-            tag: "".into(),
-            begin: -1,
-            end: -1,
-            content_begin: -1,
-            content_end: -1,
-            paragraph_params_start: -1,
-            paragraph_params_end: -1,
-            contains_paragraph: false,
-            run_params_start: -1,
-            run_params_end: -1,
-        }
+        Vec::new()
+    };
+    let mut run_properties = if control.has_run_params() {
+        events[control.run_params_start as usize..control.run_params_end as usize].to_vec()
+    } else if format.inherit_run_properties {
+        let content = &events[control.content_begin as usize + 1..control.content_end as usize];
+        let header = &events[control.begin as usize..control.content_begin as usize];
+        first_properties(content, b"w:r")
+            .or_else(|| first_properties(header, b"w:sdtPr"))
+            .map(<[Event]>::to_vec)
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    if let Some(run_format) = format.run_format {
+        run_properties = run_format.run_properties();
     }
-
-    fn intersects_header(&self, index: i32) -> bool {
-        self.begin != -1 && index > self.begin && self.content_begin == -1 && self.end == -1
+    if let Some(style) = format.run_style {
+        run_properties = with_val_property(&run_properties, "w:rPr", "w:rStyle", style, &[]);
     }
-
-    fn intersects_content(&self, index: i32) -> bool {
-        index >= self.content_begin && index < self.content_end
+    if format.rtl {
+        paragraph_properties = with_property(&paragraph_properties, "w:pPr", "w:bidi", AFTER_BIDI);
+        run_properties = with_property(
+            &run_properties,
+            "w:rPr",
+            "w:rtl",
+            run_property_neighbours("w:rtl").1,
+        );
     }
-
-    fn content_opened(&self) -> bool {
-        self.content_begin != -1
+    if format.no_proof {
+        run_properties = with_property(
+            &run_properties,
+            "w:rPr",
+            "w:noProof",
+            run_property_neighbours("w:noProof").1,
+        );
     }
-
-    fn closed(&self) -> bool {
-        self.end != -1
+    let tags: &[&str] = if control.contains_paragraph || control.level == ControlLevel::Block {
+        &["w:p", "w:r", "w:t"]
+    } else {
+        &["w:r", "w:t"]
+    };
+    if let Some(spans) = &format.inline_spans {
+        let runs: String = spans
+            .iter()
+            .map(|span| inline_run(control, span, &run_properties))
+            .collect();
+        let _ = write_wrap_tags(writer, &runs, tags, &paragraph_properties, &run_properties);
+        return Ok(());
     }
-
-    fn content_closed(&self) -> bool {
-        self.content_end != -1
+    // blank lines of plain values for rich text separate paragraphs
+    let blocks = match tags[0] == "w:p" && control.r#type == ContentControlType::RichText {
+        true => paragraph_blocks(content),
+        false => None,
+    };
+    for block in blocks.unwrap_or_else(|| vec![content]) {
+        let _ = write_wrap_tags(
+            writer,
+            &text_control_value(control, block),
+            tags,
+            &paragraph_properties,
+            &run_properties,
+        );
     }
+    Ok(())
+}
 
-    fn has_paragraph_params(&self) -> bool {
-        self.paragraph_params_start >= 0 && self.paragraph_params_end >= 0
+/**
+ * The blocks of a plain value separated by blank lines, `None` for values without markup that
+ * have at most one block.
+ */
+fn paragraph_blocks(value: &str) -> Option<Vec<&str>> {
+    if !value.contains('\n') || !get_tag_types(value).is_empty() {
+        return None;
     }
-
-    fn has_run_params(&self) -> bool {
-        self.run_params_start >= 0 && self.run_params_end >= 0
+    let mut blocks = Vec::new();
+    let mut block_start = None;
+    let mut block_end = 0;
+    let mut offset = 0;
+    for line in value.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            if let Some(start) = block_start.take() {
+                blocks.push(&value[start..block_end]);
+            }
+        } else {
+            block_start.get_or_insert(offset);
+            block_end = offset + line.trim_end_matches(['\n', '\r']).len();
+        }
+        offset += line.len();
     }
-
-    pub fn get_tag(&self) -> &str {
-        &self.tag
+    if let Some(start) = block_start {
+        blocks.push(&value[start..block_end]);
     }
+    (blocks.len() > 1).then_some(blocks)
+}
 
-    pub fn get_type(&self) -> &ContentControlType {
-        &self.r#type
-    }
+/**
+ * Whether an `ST_OnOff` attribute value is set.
+ */
+fn is_on(value: &str) -> bool {
+    matches!(value, "1" | "true" | "on")
 }
 
-impl Default for ContentControlPosition {
-    fn default() -> Self {
-        Self::new()
+/**
+ * Turn line breaks and tabs of a plain value into `w:br` and `w:tab`, which Word would otherwise
+ * show as spaces, with the text around them in `w:t` elements. Single line plain text controls
+ * do not allow line breaks and get newlines collapsed to spaces instead. Values containing markup
+ * are left as they are.
+ */
+fn text_control_value<'v>(control: &ContentControlPosition, value: &'v str) -> Cow<'v, str> {
+    if !value.contains(['\n', '\t']) || !get_tag_types(value).is_empty() {
+        return Cow::Borrowed(value);
     }
+    let lines = value.split('\n').map(|line| line.trim_end_matches('\r'));
+    let lines: Vec<String> = if control.r#type == ContentControlType::Text && !control.multi_line {
+        let line = lines.collect::<Vec<_>>().join(" ");
+        if !line.contains('\t') {
+            return Cow::Owned(line);
+        }
+        vec![line]
+    } else {
+        lines.map(String::from).collect()
+    };
+    Cow::Owned(
+        lines
+            .iter()
+            .map(|line| {
+                line.split('\t')
+                    .map(|text| format!(r#"<w:t xml:space="preserve">{}</w:t>"#, text))
+                    .collect::<Vec<_>>()
+                    .join("<w:tab/>")
+            })
+            .collect::<Vec<_>>()
+            .join("<w:br/>"),
+    )
 }
 
-pub struct DocumentState {
-    states: HashMap<String, i32>,
-    positions: HashMap<String, i32>,
-    controls: Vec<ContentControlPosition>,
-    is_eof: bool,
-    last_seen_closed: String,
-    counter: i32,
+/**
+ * State of a checkbox given by a mapping value, `None` for values other than `true`, `false`,
+ * `1` and `0`.
+ */
+fn parse_checked(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
 }
 
-impl DocumentState {
-    fn new() -> DocumentState {
-        DocumentState {
-            states: HashMap::new(),
-            positions: HashMap::new(),
-            controls: Vec::new(),
-            is_eof: false,
-            last_seen_closed: "".into(),
-            counter: 0,
-        }
+/**
+ * The events of a checkbox control before its `w:sdtContent` with `w14:checked` set, along with
+ * the symbol to display, from `w14:checkedState` or `w14:uncheckedState` and defaulting to a
+ * ballot box with or without an X.
+ */
+fn checkbox_header<'a>(events: &[Event<'a>], checked: bool) -> (Vec<Event<'a>>, String) {
+    let state_element: &[u8] = if checked {
+        b"w14:checkedState"
+    } else {
+        b"w14:uncheckedState"
+    };
+    let mut symbol = if checked { '\u{2612}' } else { '\u{2610}' };
+    let checked_element = || {
+        Event::Empty(
+            BytesStart::new("w14:checked")
+                .with_attributes([("w14:val", if checked { "1" } else { "0" })]),
+        )
+    };
+    let mut header = Vec::with_capacity(events.len() + 2);
+    let mut has_checked = false;
+    for ev in events {
+        match ev {
+            Event::Empty(e) | Event::Start(e) if e.name().into_inner() == state_element => {
+                if let Some(code) = e
+                    .try_get_attribute("w14:val")
+                    .ok()
+                    .flatten()
+                    .and_then(|attr| u32::from_str_radix(&attr.unescape_value().ok()?, 16).ok())
+                    .and_then(char::from_u32)
+                {
+                    symbol = code;
+                }
+                header.push(ev.clone());
+            }
+            Event::Empty(e) if e.name() == QName(b"w14:checked") => {
+                has_checked = true;
+                header.push(checked_element());
+            }
+            Event::Empty(e) if e.name() == QName(b"w14:checkbox") => {
+                has_checked = true;
+                header.push(Event::Start(e.clone()));
+                header.push(checked_element());
+                header.push(Event::End(e.to_end().into_owned()));
+            }
+            Event::Start(e) if e.name() == QName(b"w14:checked") => {
+                has_checked = true;
+                header.push(checked_element());
+            }
+            Event::End(e) if e.name() == QName(b"w14:checked") => {}
+            _ => header.push(ev.clone()),
+        }
     }
-
-    fn is_in(&self, key: &str) -> bool {
-        self.states.get(key).unwrap_or(&0) > &0
+    if !has_checked {
+        if let Some(position) = header
+            .iter()
+            .position(|ev| matches!(ev, Event::Start(e) if e.name() == QName(b"w14:checkbox")))
+        {
+            header.insert(position + 1, checked_element());
+        }
     }
+    (header, symbol.to_string())
+}
 
-    fn is_at(&self, key: &str) -> bool {
-        self.is_in(key) || key == self.last_seen_closed
-    }
+/**
+ * List item of a control whose display text or, if none has it, whose value is `value`.
+ */
+fn find_list_item<'c>(
+    control: &'c ContentControlPosition,
+    value: &str,
+) -> Option<&'c (String, String)> {
+    control
+        .list_items
+        .iter()
+        .find(|(display_text, _)| display_text == value)
+        .or_else(|| control.list_items.iter().find(|(_, item)| item == value))
+}
 
-    fn consume(&mut self, event: &Event) {
-        // reset last seen closing tag, as we only want that to cover the closing tag
-        if !self.last_seen_closed.is_empty() {
-            self.last_seen_closed = "".into();
+/**
+ * The events of a list control before its `w:sdtContent` with the `w:lastValue` of its
+ * `w:dropDownList` or `w:comboBox` set to the value of the selected item.
+ */
+fn list_header<'a>(events: &[Event<'a>], value: &str) -> Vec<Event<'a>> {
+    let with_value = |e: &BytesStart| {
+        if e.try_get_attribute("w:lastValue").ok().flatten().is_some() {
+            with_attribute(e, |key| key == QName(b"w:lastValue"), value)
+        } else {
+            let mut element = e.to_owned();
+            element.push_attribute(("w:lastValue", value));
+            element
+        }
+    };
+    events
+        .iter()
+        .map(|ev| match ev {
+            Event::Start(e) if is_list_type(e.name()) => Event::Start(with_value(e)),
+            Event::Empty(e) if is_list_type(e.name()) => Event::Empty(with_value(e)),
+            _ => ev.clone(),
+        })
+        .collect()
+}
+
+/**
+ * Whether the events of a control before its `w:sdtContent` have `w:showingPlcHdr`, so its
+ * content is placeholder text rather than a value.
+ */
+fn shows_placeholder(events: &[Event]) -> bool {
+    events.iter().any(|ev| match ev {
+        Event::Empty(e) | Event::Start(e) => e.name() == QName(b"w:showingPlcHdr"),
+        _ => false,
+    })
+}
+
+/**
+ * The events of a control before its `w:sdtContent` without `w:showingPlcHdr`, which would
+ * have Word treat filled content as placeholder text and discard it on the first edit.
+ */
+fn without_placeholder_flag<'a>(events: &[Event<'a>]) -> Vec<Event<'a>> {
+    let is_flag = |e: &BytesStart| e.name() == QName(b"w:showingPlcHdr");
+    let mut in_flag = false;
+    events
+        .iter()
+        .filter(|ev| match ev {
+            Event::Empty(e) => !is_flag(e),
+            Event::Start(e) if is_flag(e) => {
+                in_flag = true;
+                false
+            }
+            Event::End(e) if e.name() == QName(b"w:showingPlcHdr") => {
+                in_flag = false;
+                false
+            }
+            _ => !in_flag,
+        })
+        .cloned()
+        .collect()
+}
+
+/**
+ * The events of a date control before its `w:sdtContent` with the `w:fullDate` of its `w:date`
+ * set to the date.
+ */
+fn date_header<'a>(events: &[Event<'a>], date: &Date) -> Vec<Event<'a>> {
+    let full_date = date.full_date();
+    events
+        .iter()
+        .map(|ev| {
+            let with_date = |e: &BytesStart| {
+                let mut element = BytesStart::new("w:date");
+                element.push_attribute(("w:fullDate", full_date.as_str()));
+                element.extend_attributes(
+                    e.attributes()
+                        .flatten()
+                        .filter(|attr| attr.key != QName(b"w:fullDate")),
+                );
+                element.into_owned()
+            };
+            match ev {
+                Event::Start(e) if e.name() == QName(b"w:date") => Event::Start(with_date(e)),
+                Event::Empty(e) if e.name() == QName(b"w:date") => Event::Empty(with_date(e)),
+                _ => ev.clone(),
+            }
+        })
+        .collect()
+}
+
+/**
+ * Whether a value is an XML fragment with elements, such as runs or a table, rather than plain
+ * text. Text that is not well-formed XML, e.g. with a bare `&` or an unclosed element, or with
+ * anything but prefixed element names like `w:r` between `<` and `>`, is plain text.
+ */
+fn is_markup(value: &str) -> bool {
+    let is_name = |name: &[u8]| {
+        name.first()
+            .is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_')
+            && name
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'_' | b'-' | b'.'))
+    };
+    let is_element = |e: &BytesStart| {
+        let qualified = e
+            .name()
+            .into_inner()
+            .split(|c| *c == b':')
+            .collect::<Vec<_>>();
+        qualified.len() == 2 && qualified.iter().all(|part| is_name(part))
+    };
+    let mut reader = Reader::from_str(value);
+    let mut has_elements = false;
+    // elements still open, as the reader does not complain about those at the end
+    let mut depth = 0;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) if is_element(&e) => {
+                has_elements = true;
+                depth += 1;
+            }
+            Ok(Event::Empty(e)) if is_element(&e) => has_elements = true,
+            Ok(Event::Start(_)) | Ok(Event::Empty(_)) => return false,
+            Ok(Event::End(_)) if depth == 0 => return false,
+            Ok(Event::End(_)) => depth -= 1,
+            Ok(Event::Text(text)) if text.unescape().is_err() => return false,
+            Ok(Event::Eof) => return has_elements && depth == 0,
+            Err(_) => return false,
+            Ok(_) => {}
         }
+    }
+}
+
+/**
+ * Current text of a control, joining its `w:t` elements with tabs and breaks as `\t` and `\n`.
+ */
+fn control_text(control: &ContentControlPosition, events: &[Event]) -> String {
+    let mut text = String::new();
+    let mut in_text = false;
+    for event in &events[(control.content_begin + 1) as usize..control.content_end as usize] {
         match event {
-            Event::Start(e) => {
-                let name = String::from_utf8_lossy(e.name().into_inner()).to_string();
-                let current = self.states.get(&name).unwrap_or(&0);
-                self.states.insert(name.clone(), current + 1);
-                self.positions.insert(name.clone(), self.counter);
-                match name.as_str() {
-                    "w:sdt" => {
-                        self.controls.push(ContentControlPosition {
-                            begin: self.counter,
-                            ..Default::default()
-                        });
-                    }
-                    "w:sdtContent" => {
-                        for ctrl in self.controls.iter_mut().rev() {
-                            if !ctrl.content_opened() {
-                                ctrl.content_begin = self.counter;
-                                break;
-                            }
-                        }
-                    }
-                    "w:p" => {
-                        if self.is_in("w:sdtContent") {
-                            if let Some(ctrl) = self.controls.iter_mut().next_back() {
-                                ctrl.contains_paragraph = true;
-                            }
-                        }
-                    }
-                    "w:rPr" => {
-                        if self.is_in("w:sdtContent") && self.is_in("w:r") {
-                            if let Some(ctrl) = self.controls.iter_mut().next_back() {
-                                if ctrl.run_params_start < 0 {
-                                    ctrl.run_params_start = self.counter;
-                                }
-                            }
-                        }
-                    }
-                    "w:pPr" => {
-                        if self.is_in("w:sdtContent") && self.is_in("w:p") {
-                            if let Some(ctrl) = self.controls.iter_mut().next_back() {
-                                if ctrl.paragraph_params_start < 0 {
-                                    ctrl.paragraph_params_start = self.counter;
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
+            Event::Start(e) if e.name() == QName(b"w:t") => in_text = true,
+            Event::End(e) if e.name() == QName(b"w:t") => in_text = false,
+            Event::Empty(e) if e.name() == QName(b"w:tab") => text.push('\t'),
+            Event::Empty(e) if e.name() == QName(b"w:br") => text.push('\n'),
+            Event::Text(e) if in_text => {
+                if let Ok(t) = e.unescape() {
+                    text.push_str(&t);
                 }
             }
-            Event::End(e) => {
-                let name = String::from_utf8_lossy(e.name().into_inner()).to_string();
-                let current = self.states.get(&name).unwrap_or(&0);
-                self.last_seen_closed = name.clone();
-                match name.as_str() {
-                    "w:sdt" => {
-                        for ctrl in self.controls.iter_mut().rev() {
-                            if !ctrl.closed() {
-                                ctrl.end = self.counter;
-                                // Content Control defaults to RichText if no type has been given.
-                                if let ContentControlType::Unsupported = ctrl.r#type {
-                                    ctrl.r#type = ContentControlType::RichText
-                                }
-                                break;
-                            }
-                        }
-                    }
-                    "w:sdtContent" => {
-                        for ctrl in self.controls.iter_mut().rev() {
-                            if !ctrl.content_closed() {
-                                ctrl.content_end = self.counter;
-                                break;
-                            }
-                        }
-                    }
-                    "w:rPr" => {
-                        if self.is_in("w:sdtContent") && self.is_in("w:r") {
-                            if let Some(ctrl) = self.controls.iter_mut().next_back() {
-                                if ctrl.run_params_end < 0 {
-                                    ctrl.run_params_end = self.counter + 1;
-                                }
-                            }
-                        }
-                    }
-                    "w:pPr" => {
-                        if self.is_in("w:sdtContent") && self.is_in("w:p") {
-                            if let Some(ctrl) = self.controls.iter_mut().next_back() {
-                                if ctrl.paragraph_params_end < 0 {
-                                    ctrl.paragraph_params_end = self.counter + 1;
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
+            _ => {}
+        }
+    }
+    text
+}
+
+/**
+ * Current text of every tagged control of a part, including controls nested in others, keyed by
+ * tag. The text of a control holding other controls includes theirs, controls without text map
+ * to an empty string and of controls sharing a tag the first one in document order wins.
+ */
+pub fn get_control_values(doc: &DocumentData) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for control in &doc.control_positions {
+        if control.tag.is_empty() || values.contains_key(&control.tag) {
+            continue;
+        }
+        values.insert(control.tag.clone(), doc.get_text(control));
+    }
+    values
+}
+
+fn is_paragraph_break_property(name: QName) -> bool {
+    name == QName(b"w:pageBreakBefore") || name == QName(b"w:sectPr")
+}
+
+/**
+ * Write a fragment while dropping paragraphs containing no runs. Paragraphs whose properties
+ * carry a page or section break are kept, as is the first paragraph if all of them are empty.
+ */
+fn write_without_empty_paragraphs<W>(writer: &mut Writer<W>, fragment: &str)
+where
+    W: std::io::Write,
+{
+    let mut reader = Reader::from_str(fragment);
+    let mut paragraph: Vec<Event> = Vec::new();
+    let mut first_empty: Option<Vec<Event>> = None;
+    let mut depth = 0;
+    let mut keep = false;
+    let mut written = false;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(e) => e,
+        };
+        if depth == 0 {
+            match &event {
+                Event::Empty(e) if e.name() == QName(b"w:p") => {
+                    first_empty.get_or_insert_with(|| vec![event.clone()]);
+                }
+                Event::Start(e) if e.name() == QName(b"w:p") => {
+                    depth = 1;
+                    keep = false;
+                    paragraph.push(event);
+                }
+                _ => {
+                    let _ = writer.write_event(event);
                 }
-                self.states.insert(name, current - 1);
             }
-            Event::Empty(e) => {
-                let name = String::from_utf8_lossy(e.name().into_inner()).to_string();
-                if self.is_in("w:sdtPr") {
-                    if let Some(t) = ContentControlType::parse_string(&name) {
-                        for ctrl in self.controls.iter_mut().rev() {
-                            if ctrl.intersects_header(self.counter) {
-                                ctrl.r#type = t;
-                                break;
-                            }
-                        }
-                    } else if name == "w:tag" {
-                        if let Some(ctrl) = self.controls.iter_mut().next_back() {
-                            for attr in e.attributes().flatten() {
-                                if attr.key == QName(b"w:val") {
-                                    let vwal = String::from_utf8_lossy(&attr.value).into();
-                                    ctrl.tag = vwal;
-                                }
-                            }
-                        }
-                    }
+            continue;
+        }
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                if e.name() == QName(b"w:r") || is_paragraph_break_property(e.name()) {
+                    keep = true;
+                }
+                if matches!(event, Event::Start(_)) && e.name() == QName(b"w:p") {
+                    depth += 1;
                 }
             }
-            Event::Eof => self.is_eof = true,
+            Event::End(e) if e.name() == QName(b"w:p") => depth -= 1,
             _ => {}
         }
-        self.counter += 1;
+        paragraph.push(event);
+        if depth == 0 {
+            let events = std::mem::take(&mut paragraph);
+            if keep {
+                for ev in events {
+                    let _ = writer.write_event(ev);
+                }
+                written = true;
+            } else {
+                first_empty.get_or_insert(events);
+            }
+        }
+    }
+    if !written {
+        for ev in first_empty.unwrap_or_default() {
+            let _ = writer.write_event(ev);
+        }
     }
 }
 
-pub fn get_content_controls(data: &ZipData) -> ParsedDocuments {
-    let mut documents = HashMap::new();
-    for (filename, string) in data {
-        if has_content_control(string) {
-            let enc_str = str::from_utf8(string).expect("should be utf-8 encoded string");
-            let mut reader = Reader::from_str(enc_str);
-            let mut state = DocumentState::new();
-            let mut events: Vec<Event> = Vec::new();
-            while !state.is_eof {
-                let event = reader.read_event();
-                match event {
-                    Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                    Ok(e) => {
-                        state.consume(&e);
-                        events.push(e.clone());
+pub struct DocumentData<'a> {
+    events: Vec<Event<'a>>,
+    pub control_positions: Vec<ContentControlPosition>,
+    /// Prefixes bound to the WordprocessingML namespace in the part.
+    w_prefixes: HashSet<String>,
+    /// Encoding other than UTF-8 declared by the part, which it is written back in.
+    #[cfg(feature = "encoding")]
+    encoding: Option<&'static encoding_rs::Encoding>,
+}
+
+impl DocumentData<'_> {
+    /**
+     * Serialized events of the part in the encoding it was read in.
+     */
+    fn encode(&self, xml: Vec<u8>) -> Vec<u8> {
+        #[cfg(feature = "encoding")]
+        if let Some(encoding) = self.encoding {
+            return encoding
+                .encode(&String::from_utf8_lossy(&xml))
+                .0
+                .into_owned();
+        }
+        xml
+    }
+
+    /**
+     * The part with the `w:lock` elements of its controls blanked out, keeping the positions of
+     * all other events.
+     */
+    fn without_locks(&self) -> DocumentData<'_> {
+        DocumentData {
+            events: self
+                .events
+                .iter()
+                .map(|ev| match ev {
+                    Event::Empty(e) if is_w_name(&self.w_prefixes, e.name(), b"lock") => {
+                        Event::Text(BytesText::from_escaped(""))
                     }
-                }
-            }
-            documents.insert(
-                filename.into(),
-                DocumentData {
-                    events,
-                    control_positions: state.controls,
-                },
-            );
+                    _ => ev.borrow(),
+                })
+                .collect(),
+            control_positions: self.control_positions.clone(),
+            w_prefixes: self.w_prefixes.clone(),
+            #[cfg(feature = "encoding")]
+            encoding: self.encoding,
+        }
+    }
+
+    /**
+     * Summaries of all controls of the part in document order.
+     */
+    pub fn summary(&self) -> Vec<ControlSummary> {
+        self.control_positions
+            .iter()
+            .map(|c| c.summary(&self.control_positions))
+            .collect()
+    }
+
+    /**
+     * Current text of a control of the part, see `get_control_values`. Controls without
+     * content have an empty text.
+     */
+    pub fn get_text(&self, control: &ContentControlPosition) -> String {
+        if control.content_opened() && control.content_closed() {
+            control_text(control, &self.events)
+        } else {
+            String::new()
         }
     }
-    documents
 }
 
+type ParsedDocuments<'a> = HashMap<String, DocumentData<'a>>;
+
 /**
- * Remove all content controls while retaining content.
+ * Description of a control without its event positions, which shift with unrelated markup
+ * changes, making it suitable for snapshots of templates.
  */
-pub fn remove_content_controls(data: &ZipData) -> ZipData {
-    let mut cleared_data = ZipData::new();
-    for (filename, doc_string) in data {
-        if has_content_control(doc_string) {
-            let mut writer = Writer::new(Cursor::new(Vec::new()));
-            let doc_string_enc =
-                str::from_utf8(doc_string).expect("should be utf-8 encoded string");
-            let mut reader = Reader::from_str(doc_string_enc);
-            let mut state = DocumentState::new();
-            while !state.is_eof {
-                let event = reader.read_event();
-                match event {
-                    Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                    Ok(e) => {
-                        state.consume(&e);
-                        match &e {
-                            Event::Start(v) => {
-                                if v.name() != QName(b"w:sdtContent")
-                                    && v.name() != QName(b"w:sdt")
-                                    && !state.is_at("w:sdtPr")
-                                {
-                                    let _ = writer.write_event(e);
-                                }
-                            }
-                            Event::End(v) => {
-                                if v.name() != QName(b"w:sdtContent")
-                                    && v.name() != QName(b"w:sdt")
-                                    && !state.is_at("w:sdtPr")
-                                {
-                                    let _ = writer.write_event(e);
-                                }
-                            }
-                            _ => {
-                                if !state.is_at("w:sdtPr") {
-                                    let _ = writer.write_event(e);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            cleared_data.insert(filename.into(), writer.into_inner().into_inner());
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ControlSummary {
+    pub tag: String,
+    pub alias: String,
+    pub control_type: ContentControlType,
+    pub level: ControlLevel,
+    /// Tags of the controls directly nested within the control.
+    pub child_tags: Vec<String>,
+}
+
+/**
+ * Entry of a map for a control, looked up by its tag and, if the tag has none, by its alias, so
+ * controls that their author only gave a title can be filled by it.
+ */
+fn control_entry<'m, T>(
+    map: &'m HashMap<String, T>,
+    control: &ContentControlPosition,
+) -> Option<&'m T> {
+    map.get(&control.tag).or_else(|| {
+        if control.alias.is_empty() {
+            None
         } else {
-            cleared_data.insert(filename.into(), doc_string.clone());
+            map.get(&control.alias)
         }
-    }
-    cleared_data
+    })
 }
 
-pub fn get_contained_control<'a>(
+/**
+ * Innermost control enclosing the given one.
+ */
+fn parent_control<'a>(
     controls: &'a [ContentControlPosition],
-    control: &'a ContentControlPosition,
-) -> impl Iterator<Item = &'a ContentControlPosition> + 'a {
+    control: &ContentControlPosition,
+) -> Option<&'a ContentControlPosition> {
     controls
         .iter()
-        .filter(|c| c.begin >= control.content_begin && c.end <= control.content_end)
+        .filter(|c| c.begin < control.begin && control.end < c.end)
+        .max_by_key(|c| c.begin)
 }
 
-pub fn map_content_controls(
-    data: &ZipData,
-    controlled: &ParsedDocuments,
-    mappings: &Mapping,
-    repeat_mappings: &RepeatMapping,
-) -> ZipData {
-    let mut mapped_data = ZipData::new();
-    for (filename, data) in data {
-        if let Some(doc) = controlled.get(filename) {
-            let mut writer = Writer::new(Cursor::new(Vec::new()));
-            for (i, event) in doc.events.iter().enumerate() {
-                if let Some(control) =
-                    get_intersecting_control_position(i as i64, &doc.control_positions)
-                {
-                    if control.content_begin == i as i32 {
-                        let _ = writer.write_event(event);
-                        match control.r#type {
-                            ContentControlType::RepeatingSection => {
-                                let default_values = Vec::new();
-                                let new_values = repeat_mappings.get(control.tag.as_str()).unwrap_or(&default_values);
-                                for new_value in new_values.iter() {
-                                    if let Some(section_item) = get_contained_control(
-                                        &doc.control_positions,
-                                        control,
-                                    )
-                                    .find(|c| c.r#type == ContentControlType::RepeatingSectionItem)
-                                    {
-                                        for i_item in section_item.begin..section_item.end + 1 {
-                                            let ev_item = &doc.events[i_item as usize];
-                                            if let Some(ctrl_item) = get_contained_control_at(
-                                                &doc.control_positions,
-                                                section_item,
-                                                i_item,
-                                            ) {
-                                                if ctrl_item.content_begin == i_item {
-                                                    let _ = writer.write_event(ev_item);
-                                                    let new_value = new_value
-                                                        .get(&ctrl_item.tag).map(String::as_str)
-                                                        .unwrap_or(MISSING_STR);
-                                                    let _ = write_content(
-                                                        ctrl_item,
-                                                        &mut writer,
-                                                        new_value,
-                                                        &doc.events,
-                                                    );
-                                                }
-                                            } else {
-                                                let _ = writer.write_event(ev_item);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {
-                                let new_value =
-                                    mappings.get(&control.tag).map(String::as_str).unwrap_or(MISSING_STR);
-                                let _ = write_content(control, &mut writer, new_value, &doc.events);
-                            }
-                        }
-                    }
-                } else {
-                    let _ = writer.write_event(event);
-                }
-            }
-            mapped_data.insert(filename.into(), writer.into_inner().into_inner());
-        } else {
-            mapped_data.insert(filename.into(), data.clone());
-        }
+/**
+ * Binding of a control to a node of a custom XML part, from the `w:dataBinding` of its
+ * `w:sdtPr`. Word refreshes the content of bound controls from the node when the document is
+ * opened.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DataBinding {
+    /// XPath of the bound node, e.g. `/ns0:invoice[1]/ns0:customer[1]`.
+    pub xpath: String,
+    /// Id of the custom XML part, e.g. `{B2B1E9C1-5B56-4F8A-9C47-0F0BD3E5D1A3}`.
+    pub store_item_id: String,
+    /// Namespace prefixes used in the XPath, as `xmlns:ns0='...'` declarations.
+    pub prefix_mappings: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentControlPosition {
+    r#type: ContentControlType,
+    type_element: Option<String>,
+    tag: String,
+    alias: String,
+    level: ControlLevel,
+    list_items: Vec<(String, String)>,
+    multi_line: bool,
+    depth: usize,
+    metadata: HashMap<String, String>,
+    data_binding: Option<DataBinding>,
+    // Spans are indices into the events of the part, -1 while unset. With `i64` they cannot
+    // overflow in practice: every event takes at least a few bytes of XML, far below the
+    // 2^63 events a part would need, so the size of a document is bounded by memory alone.
+    begin: i64,
+    end: i64,
+    content_begin: i64,
+    content_end: i64,
+    paragraph_params_start: i64,
+    paragraph_params_end: i64,
+    contains_paragraph: bool,
+    run_params_start: i64,
+    run_params_end: i64,
+    /// Whether the run params were taken from a run holding text, rather than the first run.
+    text_run_params: bool,
+    /// Span of the `w:rPr` in the `w:sdtEndPr`, formatting the end of the control.
+    end_params_start: i64,
+    end_params_end: i64,
+}
+
+impl ContentControlPosition {
+    fn new() -> Self {
+        ContentControlPosition {
+            r#type: ContentControlType::Unsupported,
+            type_element: None,
+            tag: "".into(),
+            alias: "".into(),
+            level: ControlLevel::Block,
+            list_items: Vec::new(),
+            multi_line: false,
+            depth: 1,
+            metadata: HashMap::new(),
+            data_binding: None,
+            begin: -1,
+            end: -1,
+            content_begin: -1,
+            content_end: -1,
+            paragraph_params_start: -1,
+            paragraph_params_end: -1,
+            contains_paragraph: false,
+            run_params_start: -1,
+            run_params_end: -1,
+            text_run_params: false,
+            end_params_start: -1,
+            end_params_end: -1,
+        }
+    }
+
+    fn is_open_at(&self, index: i64) -> bool {
+        self.begin != -1 && index > self.begin && self.end == -1
+    }
+
+    fn content_opened(&self) -> bool {
+        self.content_begin != -1
+    }
+
+    fn closed(&self) -> bool {
+        self.end != -1
+    }
+
+    fn content_closed(&self) -> bool {
+        self.content_end != -1
+    }
+
+    fn has_paragraph_params(&self) -> bool {
+        self.paragraph_params_start >= 0 && self.paragraph_params_end >= 0
+    }
+
+    fn has_run_params(&self) -> bool {
+        self.run_params_start >= 0 && self.run_params_end >= 0
+    }
+
+    pub fn get_tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn get_alias(&self) -> &str {
+        &self.alias
+    }
+
+    pub fn get_type(&self) -> &ContentControlType {
+        &self.r#type
+    }
+
+    /**
+     * Name of the `w:sdtPr` child declaring the type, if the control declares one.
+     */
+    pub fn get_type_element(&self) -> Option<&str> {
+        self.type_element.as_deref()
+    }
+
+    /**
+     * Choices of a combo box or dropdown list as (display text, value) pairs.
+     */
+    pub fn get_list_items(&self) -> &[(String, String)] {
+        &self.list_items
+    }
+
+    pub fn get_level(&self) -> ControlLevel {
+        self.level
+    }
+
+    /**
+     * Nesting depth of the control, 1 for controls not contained in any other control.
+     */
+    pub fn get_depth(&self) -> usize {
+        self.depth
+    }
+
+    /**
+     * Descriptive properties of the control, the `w:val` of the `w:sdtPr` children listed in
+     * `METADATA_ELEMENTS` keyed by element name, e.g. `w:id` or `w15:color`.
+     */
+    pub fn get_metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /**
+     * The `w:val` of the `w:lock` of the control, e.g. `sdtLocked` or `contentLocked`.
+     */
+    pub fn get_lock(&self) -> Option<&str> {
+        self.metadata.get("w:lock").map(String::as_str)
+    }
+
+    /**
+     * Whether Word prevents editing the content of the control, with a lock of `contentLocked`
+     * or `sdtContentLocked`. A lock of `sdtLocked` only prevents deleting the control.
+     */
+    pub fn is_locked(&self) -> bool {
+        matches!(self.get_lock(), Some("contentLocked" | "sdtContentLocked"))
+    }
+
+    /**
+     * Custom XML node the control is bound to. A `w15:dataBinding`, which Word 2013 writes for
+     * repeating sections, is used if the control has no `w:dataBinding`.
+     */
+    pub fn get_data_binding(&self) -> Option<&DataBinding> {
+        self.data_binding.as_ref()
+    }
+
+    /**
+     * Summarize the control, with `controls` being all controls of its part.
+     */
+    pub fn summary(&self, controls: &[ContentControlPosition]) -> ControlSummary {
+        ControlSummary {
+            tag: self.tag.clone(),
+            alias: self.alias.clone(),
+            control_type: self.r#type.clone(),
+            level: self.level,
+            child_tags: get_direct_children(controls, self)
+                .map(|c| c.tag.clone())
+                .collect(),
+        }
+    }
+
+    /**
+     * Whether a plain text control allows line breaks, set by `w:multiLine` on `w:text`.
+     */
+    pub fn is_multi_line(&self) -> bool {
+        self.multi_line
+    }
+}
+
+impl Default for ContentControlPosition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct DocumentState {
+    states: HashMap<String, i64>,
+    positions: HashMap<String, i64>,
+    ancestors: Vec<String>,
+    controls: Vec<ContentControlPosition>,
+    is_eof: bool,
+    counter: i64,
+    /// Prefixes bound to the WordprocessingML namespace, besides the usual `w`.
+    w_prefixes: HashSet<String>,
+    /// Span of the `w:rPr` of the run currently open within a `w:sdtContent`.
+    current_run_params: (i64, i64),
+    /// Index of the first control found with a second `w:sdtContent`.
+    repeated_content: Option<usize>,
+}
+
+impl DocumentState {
+    fn new() -> DocumentState {
+        DocumentState {
+            states: HashMap::new(),
+            positions: HashMap::new(),
+            ancestors: Vec::new(),
+            controls: Vec::new(),
+            is_eof: false,
+            counter: 0,
+            w_prefixes: HashSet::from(["w".to_string()]),
+            current_run_params: (-1, -1),
+            repeated_content: None,
+        }
+    }
+
+    fn is_in(&self, key: &str) -> bool {
+        self.states.get(key).unwrap_or(&0) > &0
+    }
+
+    fn set_control_type(&mut self, name: &str) {
+        let control_type = match ContentControlType::parse_string(name) {
+            Some(t) => t,
+            None if UNSUPPORTED_TYPE_ELEMENTS.contains(&name) => ContentControlType::Unsupported,
+            None => return,
+        };
+        let counter = self.counter;
+        if let Some(ctrl) = self.innermost_open_control(counter) {
+            ctrl.r#type = control_type;
+            ctrl.type_element = Some(name.into());
+        }
+    }
+
+    /**
+     * The control a `w:sdtPr` being read belongs to. This is the innermost open control rather
+     * than the last one started, as some producers write the `w:sdtPr` after the
+     * `w:sdtContent`, and thus after any controls nested in the content.
+     */
+    fn innermost_open_control(&mut self, index: i64) -> Option<&mut ContentControlPosition> {
+        self.controls.iter_mut().rev().find(|c| c.is_open_at(index))
+    }
+
+    fn bind_prefixes(&mut self, element: &BytesStart) {
+        for attr in element.attributes().flatten() {
+            if let Some(prefix) = attr.key.as_ref().strip_prefix(b"xmlns:") {
+                if attr.value.as_ref() == W_NAMESPACE.as_bytes() {
+                    self.w_prefixes
+                        .insert(String::from_utf8_lossy(prefix).to_string());
+                }
+            }
+        }
+    }
+
+    fn consume(&mut self, event: &Event) {
+        match event {
+            Event::Start(e) => {
+                self.bind_prefixes(e);
+                let name = String::from_utf8_lossy(e.name().into_inner()).to_string();
+                // only the first occurrence of a name allocates keys
+                match self.states.get_mut(&name) {
+                    Some(count) => *count += 1,
+                    None => {
+                        self.states.insert(name.clone(), 1);
+                    }
+                }
+                match self.positions.get_mut(&name) {
+                    Some(position) => *position = self.counter,
+                    None => {
+                        self.positions.insert(name.clone(), self.counter);
+                    }
+                }
+                // types with children such as list items open as a start tag
+                if self.is_in("w:sdtPr") {
+                    self.set_control_type(&name);
+                }
+                match name.as_str() {
+                    "w:rPr" if self.is_in("w:sdtEndPr") => {
+                        let counter = self.counter;
+                        if let Some(ctrl) = self.innermost_open_control(counter) {
+                            ctrl.end_params_start = counter;
+                        }
+                    }
+                    "w:sdt" => {
+                        let open_controls = self.controls.iter().filter(|c| !c.closed()).count();
+                        self.controls.push(ContentControlPosition {
+                            begin: self.counter,
+                            level: ControlLevel::from_ancestors(&self.ancestors),
+                            depth: open_controls + 1,
+                            ..Default::default()
+                        });
+                    }
+                    "w:sdtContent" => {
+                        let counter = self.counter;
+                        let index = self.controls.iter().rposition(|c| c.is_open_at(counter));
+                        if let Some(index) = index {
+                            if self.controls[index].content_opened() {
+                                self.repeated_content.get_or_insert(index);
+                            } else {
+                                self.controls[index].content_begin = counter;
+                            }
+                        }
+                    }
+                    "w:p" if self.is_in("w:sdtContent") => {
+                        if let Some(ctrl) = self.controls.iter_mut().next_back() {
+                            ctrl.contains_paragraph = true;
+                        }
+                    }
+                    "w:r" if self.is_in("w:sdtContent") => {
+                        self.current_run_params = (-1, -1);
+                    }
+                    "w:rPr" if self.is_in("w:sdtContent") && self.is_in("w:r") => {
+                        self.current_run_params.0 = self.counter;
+                        if let Some(ctrl) = self.controls.iter_mut().next_back() {
+                            if ctrl.run_params_start < 0 {
+                                ctrl.run_params_start = self.counter;
+                            }
+                        }
+                    }
+                    // the params of the first run holding text win over those of
+                    // leading runs, e.g. empty runs around bookmarks
+                    "w:t" if self.is_in("w:sdtContent") && self.is_in("w:r") => {
+                        if let Some(ctrl) = self.controls.iter_mut().next_back() {
+                            if !ctrl.text_run_params {
+                                ctrl.text_run_params = true;
+                                (ctrl.run_params_start, ctrl.run_params_end) =
+                                    self.current_run_params;
+                            }
+                        }
+                    }
+                    "w:pPr" if self.is_in("w:sdtContent") && self.is_in("w:p") => {
+                        if let Some(ctrl) = self.controls.iter_mut().next_back() {
+                            if ctrl.paragraph_params_start < 0 {
+                                ctrl.paragraph_params_start = self.counter;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                self.ancestors.push(name);
+            }
+            Event::End(e) => {
+                let name = String::from_utf8_lossy(e.name().into_inner());
+                match name.as_ref() {
+                    "w:rPr" if self.is_in("w:sdtEndPr") => {
+                        let counter = self.counter;
+                        if let Some(ctrl) = self.innermost_open_control(counter) {
+                            ctrl.end_params_end = counter + 1;
+                        }
+                    }
+                    "w:sdt" => {
+                        for ctrl in self.controls.iter_mut().rev() {
+                            if !ctrl.closed() {
+                                ctrl.end = self.counter;
+                                // Content Control defaults to RichText if no type has been given.
+                                if ctrl.type_element.is_none() {
+                                    ctrl.r#type = ContentControlType::RichText
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    "w:sdtContent" => {
+                        for ctrl in self.controls.iter_mut().rev() {
+                            if !ctrl.content_closed() {
+                                ctrl.content_end = self.counter;
+                                break;
+                            }
+                        }
+                    }
+                    "w:rPr" if self.is_in("w:sdtContent") && self.is_in("w:r") => {
+                        self.current_run_params.1 = self.counter + 1;
+                        if let Some(ctrl) = self.controls.iter_mut().next_back() {
+                            if ctrl.run_params_end < 0 {
+                                ctrl.run_params_end = self.counter + 1;
+                            }
+                        }
+                    }
+                    "w:pPr" if self.is_in("w:sdtContent") && self.is_in("w:p") => {
+                        if let Some(ctrl) = self.controls.iter_mut().next_back() {
+                            if ctrl.paragraph_params_end < 0 {
+                                ctrl.paragraph_params_end = self.counter + 1;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                match self.states.get_mut(name.as_ref()) {
+                    Some(count) => *count -= 1,
+                    None => {
+                        self.states.insert(name.to_string(), -1);
+                    }
+                }
+                self.ancestors.pop();
+            }
+            Event::Empty(e) => {
+                self.bind_prefixes(e);
+                let name = String::from_utf8_lossy(e.name().into_inner());
+                if self.is_in("w:sdtPr") {
+                    self.set_control_type(&name);
+                    let prefixes = &self.w_prefixes;
+                    let in_properties =
+                        self.ancestors.last().map(String::as_str) == Some("w:sdtPr");
+                    let counter = self.counter;
+                    if let Some(ctrl) = self
+                        .controls
+                        .iter_mut()
+                        .rev()
+                        .find(|c| c.is_open_at(counter))
+                    {
+                        if in_properties && METADATA_ELEMENTS.contains(&name.as_ref()) {
+                            ctrl.metadata.insert(
+                                name.to_string(),
+                                w_attribute(prefixes, e, b"val").unwrap_or_default(),
+                            );
+                        }
+                        match name.as_ref() {
+                            "w:tag" => {
+                                if let Some(value) = w_attribute(prefixes, e, b"val") {
+                                    ctrl.tag = value;
+                                }
+                            }
+                            "w:alias" => {
+                                if let Some(value) = w_attribute(prefixes, e, b"val") {
+                                    ctrl.alias = value;
+                                }
+                            }
+                            "w:text" => {
+                                ctrl.multi_line = w_attribute(prefixes, e, b"multiLine")
+                                    .is_some_and(|value| is_on(&value));
+                            }
+                            "w:dataBinding" | "w15:dataBinding"
+                                if in_properties
+                                    && (name == "w:dataBinding" || ctrl.data_binding.is_none()) =>
+                            {
+                                ctrl.data_binding = Some(DataBinding {
+                                    xpath: w_attribute(prefixes, e, b"xpath").unwrap_or_default(),
+                                    store_item_id: w_attribute(prefixes, e, b"storeItemID")
+                                        .unwrap_or_default(),
+                                    prefix_mappings: w_attribute(prefixes, e, b"prefixMappings")
+                                        .unwrap_or_default(),
+                                });
+                            }
+                            "w:listItem" => ctrl.list_items.push((
+                                w_attribute(prefixes, e, b"displayText").unwrap_or_default(),
+                                w_attribute(prefixes, e, b"value").unwrap_or_default(),
+                            )),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            Event::Eof => self.is_eof = true,
+            _ => {}
+        }
+        self.counter += 1;
+    }
+}
+
+/**
+ * Parse the content controls of every part containing a `w:sdt` element. No part is special
+ * cased, so the main document, headers, footers, footnotes, endnotes, comments and the glossary
+ * document are all covered. Parts without `w:sdt` are skipped unparsed.
+ *
+ * Fails on the first part that is not UTF-8 or not well-formed XML, naming the part and, for
+ * XML errors, the position in it. With the `rayon` feature parts are parsed in parallel, and of
+ * several malformed parts any one may be reported. A control with more than one `w:sdtContent` fails with
+ * `DocxError::MalformedControl`.
+ */
+pub fn get_content_controls(data: &ZipData) -> Result<ParsedDocuments<'_>, DocxError> {
+    fn parse<'a>(
+        (filename, string): (&String, &'a Vec<u8>),
+    ) -> Option<Result<(String, DocumentData<'a>), DocxError>> {
+        if has_content_control(string) {
+            Some(parse_document(filename, string).map(|doc| (filename.clone(), doc)))
+        } else {
+            None
+        }
+    }
+    #[cfg(feature = "rayon")]
+    let documents = {
+        use rayon::prelude::*;
+        data.par_iter().filter_map(parse).collect()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let documents = data.iter().filter_map(parse).collect();
+    documents
+}
+
+fn parse_document<'a>(filename: &str, part: &'a [u8]) -> Result<DocumentData<'a>, DocxError> {
+    #[cfg(feature = "encoding")]
+    if let Some(encoding) = declared_encoding(part) {
+        let decoded = encoding.decode_without_bom_handling(part).0;
+        let doc = parse_str(filename, &decoded)?;
+        return Ok(DocumentData {
+            events: doc.events.into_iter().map(Event::into_owned).collect(),
+            control_positions: doc.control_positions,
+            w_prefixes: doc.w_prefixes,
+            encoding: Some(encoding),
+        });
+    }
+    let enc_str = str::from_utf8(part).map_err(|e| DocxError::Encoding {
+        part: filename.into(),
+        source: e,
+    })?;
+    parse_str(filename, enc_str)
+}
+
+/**
+ * Encoding named by the XML declaration of a part, unless it is UTF-8. Encodings that cannot
+ * be written back, such as UTF-16, are not returned either.
+ */
+#[cfg(feature = "encoding")]
+fn declared_encoding(part: &[u8]) -> Option<&'static encoding_rs::Encoding> {
+    if !part.starts_with(b"<?xml") {
+        return None;
+    }
+    let mut reader = Reader::from_reader(part);
+    let Ok(Event::Decl(declaration)) = reader.read_event() else {
+        return None;
+    };
+    let label = declaration.encoding()?.ok()?;
+    encoding_rs::Encoding::for_label(&label).filter(|encoding| {
+        *encoding != encoding_rs::UTF_8 && encoding.output_encoding() == *encoding
+    })
+}
+
+fn parse_str<'a>(filename: &str, text: &'a str) -> Result<DocumentData<'a>, DocxError> {
+    let mut reader = Reader::from_str(text);
+    let mut state = DocumentState::new();
+    let mut events: Vec<Event> = Vec::new();
+    while !state.is_eof {
+        match reader.read_event() {
+            Err(e) => {
+                return Err(DocxError::Xml {
+                    part: filename.into(),
+                    position: reader.buffer_position(),
+                    source: e,
+                })
+            }
+            Ok(e) => {
+                state.consume(&e);
+                events.push(e);
+            }
+        }
+    }
+    if let Some(index) = state.repeated_content {
+        return Err(DocxError::MalformedControl {
+            part: filename.into(),
+            tag: state.controls[index].tag.clone(),
+            reason: "more than one w:sdtContent".into(),
+        });
+    }
+    Ok(DocumentData {
+        events,
+        control_positions: state.controls,
+        w_prefixes: state.w_prefixes,
+        #[cfg(feature = "encoding")]
+        encoding: None,
+    })
+}
+
+/**
+ * Position of an event relative to the content of the innermost enclosing content control.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMarker {
+    /// The `w:sdtContent` start of the control with the given tag.
+    ContentStart(String),
+    /// An event within the content of the control with the given tag.
+    Inside(String),
+    /// The `w:sdtContent` end of the control with the given tag.
+    ContentEnd(String),
+}
+
+/**
+ * Parse a single part into its events, each annotated with the content control it belongs to.
+ * Events outside of any control content are not annotated.
+ */
+pub fn annotated_events(
+    part: &[u8],
+) -> Result<Vec<(Event<'static>, Option<ControlMarker>)>, DocxError> {
+    let document = parse_document("", part)?;
+    let content_starts: HashMap<i64, &ContentControlPosition> = document
+        .control_positions
+        .iter()
+        .filter(|c| c.content_opened())
+        .map(|c| (c.content_begin, c))
+        .collect();
+    let mut open: Vec<&ContentControlPosition> = Vec::new();
+    let mut annotated = Vec::with_capacity(document.events.len());
+    for (i, event) in document.events.iter().enumerate() {
+        let i = i as i64;
+        let marker = if let Some(control) = content_starts.get(&i) {
+            open.push(control);
+            Some(ControlMarker::ContentStart(control.tag.clone()))
+        } else if let Some(control) = open.last().copied() {
+            if control.content_end == i {
+                open.pop();
+                Some(ControlMarker::ContentEnd(control.tag.clone()))
+            } else {
+                Some(ControlMarker::Inside(control.tag.clone()))
+            }
+        } else {
+            None
+        };
+        annotated.push((event.clone().into_owned(), marker));
+    }
+    Ok(annotated)
+}
+
+/**
+ * Stream every part containing content controls through `rewrite`, which receives the parser
+ * state after the event has been consumed and decides what to emit. Other parts are copied as-is.
+ * Parts are read and written back in their declared encoding like when filling, and fail as in
+ * `get_content_controls`.
+ */
+fn rewrite_content_controls<F>(data: &ZipData, mut rewrite: F) -> Result<ZipData, DocxError>
+where
+    F: FnMut(&DocumentState, Event, &mut Writer<Cursor<Vec<u8>>>),
+{
+    let mut rewritten_data = ZipData::new();
+    for (filename, part) in data {
+        if has_content_control(part) {
+            let doc = parse_document(filename, part)?;
+            let mut writer = Writer::new(Cursor::new(Vec::new()));
+            let mut state = DocumentState::new();
+            for event in &doc.events {
+                state.consume(event);
+                rewrite(&state, event.borrow(), &mut writer);
+            }
+            rewritten_data.insert(
+                filename.into(),
+                doc.encode(writer.into_inner().into_inner()),
+            );
+        } else {
+            rewritten_data.insert(filename.into(), part.clone());
+        }
+    }
+    Ok(rewritten_data)
+}
+
+const W_NAMESPACE: &str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+
+/**
+ * Whether a qualified name is the WordprocessingML name with the given local name, accepting
+ * any prefix bound to the namespace.
+ */
+fn is_w_name(prefixes: &HashSet<String>, name: QName, local: &[u8]) -> bool {
+    name.local_name().as_ref() == local
+        && name.prefix().is_some_and(|prefix| {
+            prefixes.contains(String::from_utf8_lossy(prefix.as_ref()).as_ref())
+        })
+}
+
+/**
+ * Value of a WordprocessingML attribute by local name, accepting any prefix bound to the
+ * namespace so `w:val` is found even if the document binds the namespace to another prefix.
+ */
+fn w_attribute(prefixes: &HashSet<String>, element: &BytesStart, local: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attr| is_w_name(prefixes, attr.key, local))
+        .map(|attr| String::from_utf8_lossy(&attr.value).into())
+}
+
+fn attribute_value(element: &BytesStart, key: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attr| attr.key == QName(key))
+        .map(|attr| String::from_utf8_lossy(&attr.value).into())
+}
+
+/**
+ * Copy an element, replacing the value of the attributes whose name matches while keeping
+ * attribute order and their qualified names.
+ */
+fn with_attribute<'a>(
+    element: &BytesStart,
+    matches: impl Fn(QName) -> bool,
+    value: &str,
+) -> BytesStart<'a> {
+    let name = String::from_utf8_lossy(element.name().into_inner()).to_string();
+    let mut updated = BytesStart::new(name);
+    for attr in element.attributes().flatten() {
+        if matches(attr.key) {
+            updated.push_attribute((attr.key.as_ref(), value.as_bytes()));
+        } else {
+            updated.push_attribute(attr);
+        }
+    }
+    updated
+}
+
+/**
+ * Remove all content controls while retaining content. The `w:sdtPr` and `w:sdtEndPr` of the
+ * controls are removed with them, see `remove_content_controls_matching`. Fails on parts that
+ * cannot be parsed, like `get_content_controls`.
+ */
+pub fn remove_content_controls(data: &ZipData) -> Result<ZipData, DocxError> {
+    remove_content_controls_matching(data, |_| true)
+}
+
+/**
+ * Remove the content controls for which `predicate` holds while retaining their content, like
+ * `remove_content_controls`. Other controls, including those nested in removed ones, are kept
+ * as they are. Fails on parts that cannot be parsed, like `get_content_controls`.
+ *
+ * The `w:sdtEndPr` is not allowed outside of a control, so the run properties it gives the end
+ * of a removed control are moved to a run directly following it, which Word formats text typed
+ * after the control with. A following run with properties of its own keeps them.
+ */
+pub fn remove_content_controls_matching(
+    data: &ZipData,
+    predicate: impl Fn(&ContentControlPosition) -> bool,
+) -> Result<ZipData, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut updated_data = ZipData::new();
+    for (filename, data) in data {
+        if let Some(doc) = controlled.get(filename) {
+            // the wrapper around the content, which holds the properties wherever they are
+            let mut skipped = vec![false; doc.events.len()];
+            let mut skip = |start: i64, end: i64| {
+                for index in start.max(0)..=end.min(skipped.len() as i64 - 1) {
+                    skipped[index as usize] = true;
+                }
+            };
+            // end run properties by the index of the end of their control
+            let mut end_params = HashMap::new();
+            for c in doc.control_positions.iter().filter(|c| predicate(c)) {
+                if c.content_opened() && c.content_closed() {
+                    skip(c.begin, c.content_begin);
+                    skip(c.content_end, c.end);
+                } else {
+                    skip(c.begin, c.end);
+                }
+                if c.end_params_start >= 0 && c.end_params_end > c.end_params_start {
+                    end_params.insert(
+                        c.end,
+                        c.end_params_start as usize..c.end_params_end as usize,
+                    );
+                }
+            }
+            let mut writer = Writer::new(Cursor::new(Vec::new()));
+            let mut pending = None;
+            for (i, event) in doc.events.iter().enumerate() {
+                if skipped[i] {
+                    if let Some(params) = end_params.get(&(i as i64)) {
+                        pending = Some(params.clone());
+                    }
+                    continue;
+                }
+                let _ = writer.write_event(event);
+                match event {
+                    Event::Text(_) => continue,
+                    Event::Start(e) if e.name() == QName(b"w:r") => {
+                        let has_params = matches!(
+                            doc.events.get(i + 1),
+                            Some(Event::Start(e) | Event::Empty(e)) if e.name() == QName(b"w:rPr")
+                        );
+                        if let Some(params) = pending.as_ref().filter(|_| !has_params) {
+                            for event in &doc.events[params.clone()] {
+                                let _ = writer.write_event(event);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                pending = None;
+            }
+            updated_data.insert(
+                filename.into(),
+                doc.encode(writer.into_inner().into_inner()),
+            );
+        } else {
+            updated_data.insert(filename.into(), data.clone());
+        }
+    }
+    Ok(updated_data)
+}
+
+/**
+ * Drop the `w:lock` of every content control, so the controls and their content can be edited
+ * and deleted in Word. Removing the controls with `remove_content_controls` drops their locks
+ * along with their other properties.
+ */
+pub fn strip_locks(data: &ZipData) -> Result<ZipData, DocxError> {
+    rewrite_content_controls(data, |state, e, writer| match &e {
+        Event::Empty(v)
+            if is_w_name(&state.w_prefixes, v.name(), b"lock") && state.is_in("w:sdtPr") => {}
+        _ => {
+            let _ = writer.write_event(e);
+        }
+    })
+}
+
+/**
+ * Rename the tags of content controls according to `renames` (old tag -> new tag).
+ * Only the `w:tag` value inside `w:sdtPr` is rewritten, content is left untouched.
+ */
+pub fn rename_tags(
+    data: &ZipData,
+    renames: &HashMap<String, String>,
+) -> Result<ZipData, DocxError> {
+    rewrite_content_controls(data, |state, e, writer| match &e {
+        Event::Empty(v)
+            if is_w_name(&state.w_prefixes, v.name(), b"tag") && state.is_in("w:sdtPr") =>
+        {
+            let prefixes = &state.w_prefixes;
+            match w_attribute(prefixes, v, b"val").and_then(|tag| renames.get(&tag)) {
+                Some(new_tag) => {
+                    let renamed =
+                        with_attribute(v, |key| is_w_name(prefixes, key, b"val"), new_tag);
+                    let _ = writer.write_event(Event::Empty(renamed));
+                }
+                None => {
+                    let _ = writer.write_event(e);
+                }
+            }
+        }
+        _ => {
+            let _ = writer.write_event(e);
+        }
+    })
+}
+
+/**
+ * Rename the tag of every content control tagged `from` to `to`, see `rename_tags`.
+ */
+pub fn rename_tag(data: &ZipData, from: &str, to: &str) -> Result<ZipData, DocxError> {
+    rename_tags(data, &HashMap::from([(from.to_string(), to.to_string())]))
+}
+
+fn is_list_type(name: QName) -> bool {
+    name == QName(b"w:comboBox") || name == QName(b"w:dropDownList")
+}
+
+fn write_list_items<W>(writer: &mut Writer<W>, items: &[(String, String)])
+where
+    W: std::io::Write,
+{
+    for (display_text, value) in items {
+        let item = BytesStart::new("w:listItem").with_attributes([
+            ("w:displayText", display_text.as_str()),
+            ("w:value", value.as_str()),
+        ]);
+        let _ = writer.write_event(Event::Empty(item));
+    }
+}
+
+/**
+ * Replace the choices of the combo box or dropdown list controls with the given tag by the
+ * provided (display text, value) pairs.
+ */
+pub fn set_list_items(
+    data: &ZipData,
+    tag: &str,
+    items: &[(String, String)],
+) -> Result<ZipData, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut updated_data = ZipData::new();
+    for (filename, data) in data {
+        if let Some(doc) = controlled.get(filename) {
+            let list_controls: Vec<&ContentControlPosition> = doc
+                .control_positions
+                .iter()
+                .filter(|c| {
+                    c.tag == tag
+                        && matches!(
+                            c.r#type,
+                            ContentControlType::ComboBox | ContentControlType::DropdownList
+                        )
+                })
+                .collect();
+            let mut writer = Writer::new(Cursor::new(Vec::new()));
+            for (i, event) in doc.events.iter().enumerate() {
+                let in_header = list_controls
+                    .iter()
+                    .any(|c| i as i64 > c.begin && (i as i64) < c.content_begin);
+                if !in_header {
+                    let _ = writer.write_event(event);
+                    continue;
+                }
+                match event {
+                    Event::Empty(e) | Event::Start(e) if e.name() == QName(b"w:listItem") => {}
+                    Event::End(e) if e.name() == QName(b"w:listItem") => {}
+                    Event::Start(e) if is_list_type(e.name()) => {
+                        let _ = writer.write_event(event);
+                        write_list_items(&mut writer, items);
+                    }
+                    Event::Empty(e) if is_list_type(e.name()) => {
+                        let _ = writer.write_event(Event::Start(e.clone()));
+                        write_list_items(&mut writer, items);
+                        let _ = writer.write_event(Event::End(e.to_end()));
+                    }
+                    _ => {
+                        let _ = writer.write_event(event);
+                    }
+                }
+            }
+            updated_data.insert(
+                filename.into(),
+                doc.encode(writer.into_inner().into_inner()),
+            );
+        } else {
+            updated_data.insert(filename.into(), data.clone());
+        }
+    }
+    Ok(updated_data)
+}
+
+fn insert_at_controls(
+    data: &ZipData,
+    tag: &str,
+    fragment: &str,
+    after: bool,
+) -> Result<ZipData, DocxError> {
+    if !is_markup(fragment) {
+        return Err(DocxError::InvalidValue {
+            tag: tag.into(),
+            reason: "the fragment is not well formed XML with balanced elements".into(),
+        });
+    }
+    let controlled = get_content_controls(data)?;
+    let mut updated_data = ZipData::new();
+    for (filename, data) in data {
+        if let Some(doc) = controlled.get(filename) {
+            let positions: HashSet<i64> = doc
+                .control_positions
+                .iter()
+                .filter(|c| c.tag == tag)
+                .map(|c| if after { c.end } else { c.begin })
+                .collect();
+            let mut writer = Writer::new(Cursor::new(Vec::new()));
+            for (i, event) in doc.events.iter().enumerate() {
+                let at_control = positions.contains(&(i as i64));
+                if at_control && !after {
+                    let _ = write_parsed_content(&mut writer, fragment);
+                }
+                let _ = writer.write_event(event);
+                if at_control && after {
+                    let _ = write_parsed_content(&mut writer, fragment);
+                }
+            }
+            updated_data.insert(
+                filename.into(),
+                doc.encode(writer.into_inner().into_inner()),
+            );
+        } else {
+            updated_data.insert(filename.into(), data.clone());
+        }
+    }
+    Ok(updated_data)
+}
+
+/**
+ * Insert an XML fragment directly before every control with the given tag, leaving the
+ * controls themselves unchanged. Fails with `DocxError::InvalidValue` if the fragment is not
+ * well formed XML of balanced, prefixed elements such as `<w:r><w:t>*</w:t></w:r>`.
+ */
+pub fn insert_before_control(
+    data: &ZipData,
+    tag: &str,
+    fragment: &str,
+) -> Result<ZipData, DocxError> {
+    insert_at_controls(data, tag, fragment, false)
+}
+
+/**
+ * Insert an XML fragment directly after every control with the given tag, such as a footnote
+ * reference or an annotation following the filled value. Fails like `insert_before_control`.
+ */
+pub fn insert_after_control(
+    data: &ZipData,
+    tag: &str,
+    fragment: &str,
+) -> Result<ZipData, DocxError> {
+    insert_at_controls(data, tag, fragment, true)
+}
+
+/**
+ * The content of the first control with the given tag, the elements within its `w:sdtContent`,
+ * serialized as they are. Parts are searched in the order of their names. Namespace
+ * declarations stay on the root element of the source part, so the fragment is meant to be
+ * injected into another WordprocessingML part, e.g. with `insert_before_control` or wrapped in
+ * a `w:sdtContent` for `MapOptions::raw_content_replace`.
+ */
+pub fn extract_control_content(data: &ZipData, tag: &str) -> Result<Option<Vec<u8>>, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut filenames: Vec<&String> = controlled.keys().collect();
+    filenames.sort();
+    Ok(filenames.into_iter().find_map(|filename| {
+        let doc = &controlled[filename];
+        let control = doc
+            .control_positions
+            .iter()
+            .find(|c| c.tag == tag && c.content_opened() && c.content_closed())?;
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        for ev in &doc.events[control.content_begin as usize + 1..control.content_end as usize] {
+            let _ = writer.write_event(ev.clone());
+        }
+        Some(writer.into_inner().into_inner())
+    }))
+}
+
+/**
+ * A run holding only optional properties and text.
+ */
+struct TextRun {
+    /// Index of the first event after the run properties.
+    properties_end: usize,
+    /// Index of the closing `w:r`.
+    end: usize,
+    properties: Vec<u8>,
+    text: String,
+}
+
+fn text_run(events: &[Event], start: usize) -> Option<TextRun> {
+    let mut i = start + 1;
+    let mut properties = Writer::new(Cursor::new(Vec::new()));
+    if matches!(&events.get(i)?, Event::Start(e) if e.name() == QName(b"w:rPr")) {
+        let mut depth = 0;
+        loop {
+            let event = events.get(i)?;
+            match event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                _ => {}
+            }
+            let _ = properties.write_event(event);
+            i += 1;
+            if depth == 0 {
+                break;
+            }
+        }
+    }
+    let properties_end = i;
+    let mut text = String::new();
+    loop {
+        match events.get(i)? {
+            Event::Start(e) if e.name() == QName(b"w:t") => {}
+            Event::End(e) if e.name() == QName(b"w:t") => {}
+            Event::Empty(e) if e.name() == QName(b"w:t") => {}
+            Event::Text(t) => text.push_str(&t.unescape().ok()?),
+            Event::End(e) if e.name() == QName(b"w:r") => {
+                return Some(TextRun {
+                    properties_end,
+                    end: i,
+                    properties: properties.into_inner().into_inner(),
+                    text,
+                });
+            }
+            _ => return None,
+        }
+        i += 1;
+    }
+}
+
+/**
+ * Coalesce consecutive runs with identical run properties within the content of controls into
+ * a single run, undoing Word splitting text into several runs, e.g. for spell checking or
+ * revision tracking. Only runs holding nothing but text are merged, and `w:proofErr` markers
+ * between merged runs are dropped. The merged run keeps the attributes of the first run.
+ */
+pub fn merge_adjacent_runs_in_controls(data: &ZipData) -> Result<ZipData, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut merged_data = ZipData::new();
+    for (filename, data) in data {
+        let Some(doc) = controlled.get(filename) else {
+            merged_data.insert(filename.into(), data.clone());
+            continue;
+        };
+        let events = &doc.events;
+        let in_control = |i: usize| {
+            doc.control_positions
+                .iter()
+                .any(|c| c.content_begin < i as i64 && (i as i64) < c.content_end)
+        };
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        let mut i = 0;
+        while i < events.len() {
+            let run = match &events[i] {
+                Event::Start(e) if e.name() == QName(b"w:r") && in_control(i) => {
+                    text_run(events, i)
+                }
+                _ => None,
+            };
+            if let Some(run) = run {
+                let mut text = run.text.clone();
+                let mut end = run.end;
+                loop {
+                    let mut next = end + 1;
+                    while matches!(&events.get(next), Some(Event::Empty(e)) if e.name() == QName(b"w:proofErr"))
+                    {
+                        next += 1;
+                    }
+                    let following = match events.get(next) {
+                        Some(Event::Start(e)) if e.name() == QName(b"w:r") => {
+                            text_run(events, next)
+                        }
+                        _ => None,
+                    };
+                    match following {
+                        Some(following) if following.properties == run.properties => {
+                            text.push_str(&following.text);
+                            end = following.end;
+                        }
+                        _ => break,
+                    }
+                }
+                if end != run.end {
+                    for event in &events[i..run.properties_end] {
+                        let _ = writer.write_event(event);
+                    }
+                    let mut start = BytesStart::new("w:t");
+                    if text.trim() != text {
+                        start.push_attribute(("xml:space", "preserve"));
+                    }
+                    let _ = writer.write_event(Event::Start(start));
+                    let _ = writer.write_event(Event::Text(BytesText::new(&text)));
+                    let _ = writer.write_event(Event::End(BytesEnd::new("w:t")));
+                    let _ = writer.write_event(&events[end]);
+                    i = end + 1;
+                    continue;
+                }
+            }
+            let _ = writer.write_event(&events[i]);
+            i += 1;
+        }
+        merged_data.insert(
+            filename.into(),
+            doc.encode(writer.into_inner().into_inner()),
+        );
+    }
+    Ok(merged_data)
+}
+
+/**
+ * All controls within the content of `control`, including those nested in other contained
+ * controls. See `get_direct_children` for only the next level.
+ */
+pub fn get_contained_control<'a>(
+    controls: &'a [ContentControlPosition],
+    control: &'a ContentControlPosition,
+) -> impl Iterator<Item = &'a ContentControlPosition> + 'a {
+    controls
+        .iter()
+        .filter(|c| c.begin >= control.content_begin && c.end <= control.content_end)
+}
+
+/**
+ * The controls directly nested in `control`, excluding those within another nested control,
+ * with `controls` being all controls of its part.
+ */
+pub fn get_direct_children<'a>(
+    controls: &'a [ContentControlPosition],
+    control: &'a ContentControlPosition,
+) -> impl Iterator<Item = &'a ContentControlPosition> + 'a {
+    controls
+        .iter()
+        .filter(|c| parent_control(controls, c).is_some_and(|p| p.begin == control.begin))
+}
+
+/**
+ * Shape of the data filling one repeating section item.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SectionSchema {
+    /// Tags of the controls filled from each item's mapping, in document order.
+    pub fields: Vec<String>,
+    /// Repeating sections nested within the item, keyed by their tag.
+    pub sections: HashMap<String, SectionSchema>,
+}
+
+fn innermost_section<'a>(
+    controls: &'a [ContentControlPosition],
+    control: &ContentControlPosition,
+) -> Option<&'a ContentControlPosition> {
+    controls
+        .iter()
+        .filter(|c| {
+            c.r#type == ContentControlType::RepeatingSection
+                && c.begin < control.begin
+                && control.end < c.end
+        })
+        .max_by_key(|c| c.begin)
+}
+
+fn section_schema(
+    controls: &[ContentControlPosition],
+    section: &ContentControlPosition,
+) -> SectionSchema {
+    let mut schema = SectionSchema::default();
+    let Some(item) = get_contained_control(controls, section)
+        .find(|c| c.r#type == ContentControlType::RepeatingSectionItem)
+    else {
+        return schema;
+    };
+    for control in get_contained_control(controls, item) {
+        if innermost_section(controls, control).map(|c| c.begin) != Some(section.begin) {
+            continue;
+        }
+        match control.r#type {
+            ContentControlType::RepeatingSection => {
+                schema
+                    .sections
+                    .insert(control.tag.clone(), section_schema(controls, control));
+            }
+            ContentControlType::RepeatingSectionItem => {}
+            _ => {
+                if !schema.fields.contains(&control.tag) {
+                    schema.fields.push(control.tag.clone());
+                }
+            }
+        }
+    }
+    schema
+}
+
+/**
+ * Describe the repeating sections of all parts, keyed by the tag of top level sections, giving
+ * the shape a `RepeatMapping` needs to fill them. Only the first item of a section is used, as
+ * that is the one repeated when filling.
+ */
+pub fn repeating_section_schema(
+    data: &ZipData,
+) -> Result<HashMap<String, SectionSchema>, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut schemas = HashMap::new();
+    for doc in controlled.values() {
+        let controls = &doc.control_positions;
+        for section in controls.iter().filter(|c| {
+            c.r#type == ContentControlType::RepeatingSection
+                && innermost_section(controls, c).is_none()
+        }) {
+            schemas.insert(section.tag.clone(), section_schema(controls, section));
+        }
+    }
+    Ok(schemas)
+}
+
+/**
+ * Data filling a template, the values for the controls and the items of repeating sections.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct TemplateData {
+    pub mappings: Mapping,
+    pub repeat_mappings: RepeatMapping,
+}
+
+/**
+ * Mismatches between template data and the controls of one or several templates.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ValidationReport {
+    /// Tags of controls and repeating sections without data, sorted.
+    pub unmapped_tags: Vec<String>,
+    /// Keys of the data not matching any control, sorted. Keys of repeating section items are
+    /// given as `Section.Key`.
+    pub unused_keys: Vec<String>,
+}
+
+#[derive(Default)]
+struct TemplateTags {
+    /// Tags filled from the mappings.
+    fields: BTreeSet<String>,
+    /// Repeating section tags with the tags filled from each item.
+    sections: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl TemplateTags {
+    fn add(&mut self, data: &ZipData) -> Result<(), DocxError> {
+        self.add_parsed(&get_content_controls(data)?);
+        Ok(())
+    }
+
+    fn add_parsed(&mut self, controlled: &ParsedDocuments) {
+        for doc in controlled.values() {
+            let controls = &doc.control_positions;
+            for control in controls {
+                match control.r#type {
+                    ContentControlType::RepeatingSection => {
+                        self.sections.entry(control.tag.clone()).or_default();
+                    }
+                    ContentControlType::RepeatingSectionItem => {}
+                    _ => match innermost_section(controls, control) {
+                        Some(section) => {
+                            self.sections
+                                .entry(section.tag.clone())
+                                .or_default()
+                                .insert(control.tag.clone());
+                        }
+                        None => {
+                            self.fields.insert(control.tag.clone());
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    fn validate(&self, data: &TemplateData) -> ValidationReport {
+        let mut unmapped_tags: BTreeSet<String> = self
+            .fields
+            .iter()
+            .filter(|tag| !data.mappings.contains_key(*tag))
+            .cloned()
+            .collect();
+        unmapped_tags.extend(
+            self.sections
+                .keys()
+                .filter(|tag| !data.repeat_mappings.contains_key(*tag))
+                .cloned(),
+        );
+        let mut unused_keys: BTreeSet<String> = data
+            .mappings
+            .keys()
+            .filter(|key| !self.fields.contains(*key))
+            .cloned()
+            .collect();
+        for (section, rows) in &data.repeat_mappings {
+            match self.sections.get(section) {
+                Some(fields) => unused_keys.extend(
+                    rows.iter()
+                        .flat_map(|row| row.keys())
+                        .filter(|key| !fields.contains(*key))
+                        .map(|key| format!("{}.{}", section, key)),
+                ),
+                None => {
+                    unused_keys.insert(section.clone());
+                }
+            }
+        }
+        ValidationReport {
+            unmapped_tags: unmapped_tags.into_iter().collect(),
+            unused_keys: unused_keys.into_iter().collect(),
+        }
+    }
+}
+
+/**
+ * Check that every key of the mappings is the tag or alias of a control, in any of the parsed
+ * parts and at any nesting, failing with the unknown keys, sorted. A typo in a tag thus fails
+ * instead of silently leaving the control unfilled.
+ */
+pub fn validate_mappings(
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+) -> Result<(), Vec<String>> {
+    let tags: HashSet<&str> = controlled
+        .values()
+        .flat_map(|doc| doc.control_positions.iter())
+        .flat_map(|c| [c.tag.as_str(), c.alias.as_str()])
+        .collect();
+    let mut unknown: Vec<String> = mappings
+        .keys()
+        .filter(|key| !tags.contains(key.as_str()))
+        .cloned()
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    unknown.sort();
+    Err(unknown)
+}
+
+/**
+ * Tags of the controls outside of repeating sections without an entry in the mappings, sorted,
+ * the other direction of `validate_mappings`. Untagged controls are not reported.
+ */
+pub fn unmapped_tags(controlled: &ParsedDocuments, mappings: &Mapping) -> Vec<String> {
+    let mut tags = TemplateTags::default();
+    tags.add_parsed(controlled);
+    tags.fields
+        .into_iter()
+        .filter(|tag| !tag.is_empty() && !mappings.contains_key(tag))
+        .collect()
+}
+
+/**
+ * Files and tags of the controls the mappings would fill, without computing any values, sorted
+ * by file and in document order within a file. Repeating sections are matched against
+ * `repeat_mappings`, controls within them against the keys of their items and all other controls
+ * against `mappings`.
+ */
+pub fn affected_controls(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+) -> Vec<(String, String)> {
+    let mut files: Vec<&String> = data
+        .keys()
+        .filter(|name| controlled.contains_key(*name))
+        .collect();
+    files.sort();
+    let mut affected = Vec::new();
+    for file in files {
+        let controls = &controlled[file].control_positions;
+        for control in controls {
+            let section = innermost_section(controls, control);
+            let is_affected = match (&control.r#type, section) {
+                (ContentControlType::RepeatingSection, _) => {
+                    control_entry(repeat_mappings, control).is_some()
+                }
+                (ContentControlType::RepeatingSectionItem, Some(section)) => {
+                    control_entry(repeat_mappings, section).is_some()
+                }
+                (_, Some(section)) => {
+                    control_entry(repeat_mappings, section).is_some_and(|items| {
+                        items
+                            .iter()
+                            .any(|item| control_entry(item, control).is_some())
+                    })
+                }
+                (_, None) => control_entry(mappings, control).is_some(),
+            };
+            if is_affected {
+                affected.push((file.clone(), control.tag.clone()));
+            }
+        }
+    }
+    affected
+}
+
+/**
+ * Number of items each repeating section would expand to with `repeat_mappings`, keyed by the
+ * tag of the section and summed over all parts. A section nested in another one is repeated
+ * with every item of the outer section, so its count is multiplied by that of the enclosing
+ * sections. Sections without an entry count zero items.
+ */
+pub fn repeat_expansion_counts(
+    controlled: &ParsedDocuments,
+    repeat_mappings: &RepeatMapping,
+) -> HashMap<String, usize> {
+    fn expansions(
+        controls: &[ContentControlPosition],
+        section: &ContentControlPosition,
+        repeat_mappings: &RepeatMapping,
+    ) -> usize {
+        let items = control_entry(repeat_mappings, section).map_or(0, Vec::len);
+        match innermost_section(controls, section) {
+            Some(outer) => items * expansions(controls, outer, repeat_mappings),
+            None => items,
+        }
+    }
+
+    let mut counts = HashMap::new();
+    for doc in controlled.values() {
+        let controls = &doc.control_positions;
+        for section in controls
+            .iter()
+            .filter(|c| c.r#type == ContentControlType::RepeatingSection)
+        {
+            *counts.entry(section.tag.clone()).or_insert(0) +=
+                expansions(controls, section, repeat_mappings);
+        }
+    }
+    counts
+}
+
+/**
+ * Check template data against the controls of a template.
+ */
+pub fn validate_template(
+    template: &ZipData,
+    data: &TemplateData,
+) -> Result<ValidationReport, DocxError> {
+    validate_against_templates(std::slice::from_ref(template), data)
+}
+
+/**
+ * Check template data shared by a pack of templates against the controls of all of them. A tag
+ * counts as mapped and a key as used if it is in any of the templates, so each tag is reported
+ * once however many templates contain it.
+ */
+pub fn validate_against_templates(
+    templates: &[ZipData],
+    data: &TemplateData,
+) -> Result<ValidationReport, DocxError> {
+    let mut tags = TemplateTags::default();
+    for template in templates {
+        tags.add(template)?;
+    }
+    Ok(tags.validate(data))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryControl {
+    pub tag: String,
+    pub alias: String,
+    pub r#type: ContentControlType,
+    pub type_element: Option<String>,
+    pub level: ControlLevel,
+    pub list_items: Vec<(String, String)>,
+    pub depth: usize,
+}
+
+impl InventoryControl {
+    fn new(control: &ContentControlPosition) -> Self {
+        InventoryControl {
+            tag: control.tag.clone(),
+            alias: control.alias.clone(),
+            r#type: control.r#type.clone(),
+            type_element: control.type_element.clone(),
+            level: control.level,
+            list_items: control.list_items.clone(),
+            depth: control.depth,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InventoryReference {
+    pub part: String,
+    #[serde(flatten)]
+    pub control: InventoryControl,
+}
+
+/**
+ * Summary of a template for review: the parts of the package, the controls found in each part
+ * and the findings worth a second look.
+ */
+#[derive(Debug, Serialize)]
+pub struct Inventory {
+    pub parts: Vec<String>,
+    pub controls: BTreeMap<String, Vec<InventoryControl>>,
+    /// Tags used by more than one control, with the number of controls using them.
+    pub duplicate_tags: BTreeMap<String, usize>,
+    pub untagged_controls: Vec<InventoryReference>,
+    pub unsupported_controls: Vec<InventoryReference>,
+    /// Controls in SmartArt diagram data or chart parts, which Word may reject once filled.
+    pub diagram_controls: Vec<InventoryReference>,
+    /// Deepest nesting of controls within each other, 0 without controls.
+    pub max_depth: usize,
+}
+
+/**
+ * Whether a part holds SmartArt diagram data or a chart rather than WordprocessingML. Controls
+ * in these parts are filled like any other, but the DrawingML schemas of the parts are stricter
+ * than the document's, so Word may reject the filled part.
+ */
+fn is_drawing_part(name: &str) -> bool {
+    name.starts_with("word/diagrams/") || name.starts_with("word/charts/")
+}
+
+/**
+ * Tags used by more than one control across all parts, with the number of controls using them.
+ * All controls with a tag are filled with the same value, which may or may not be intended.
+ * Untagged controls are not counted.
+ */
+pub fn find_duplicate_tags(controlled: &ParsedDocuments) -> HashMap<String, usize> {
+    let mut tag_counts: HashMap<String, usize> = HashMap::new();
+    for control in controlled.values().flat_map(|doc| &doc.control_positions) {
+        if !control.tag.is_empty() {
+            *tag_counts.entry(control.tag.clone()).or_insert(0) += 1;
+        }
+    }
+    tag_counts.retain(|_, count| *count > 1);
+    tag_counts
+}
+
+pub fn inventory(data: &ZipData) -> Result<Inventory, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut parts: Vec<String> = data.keys().cloned().collect();
+    parts.sort();
+
+    let mut controls = BTreeMap::new();
+    let mut untagged_controls = Vec::new();
+    let mut unsupported_controls = Vec::new();
+    let mut diagram_controls = Vec::new();
+    for (filename, doc) in &controlled {
+        let part_controls: Vec<InventoryControl> = doc
+            .control_positions
+            .iter()
+            .map(InventoryControl::new)
+            .collect();
+        for control in &part_controls {
+            let reference = || InventoryReference {
+                part: filename.clone(),
+                control: control.clone(),
+            };
+            if control.tag.is_empty() {
+                untagged_controls.push(reference());
+            }
+            if control.r#type == ContentControlType::Unsupported {
+                unsupported_controls.push(reference());
+            }
+            if is_drawing_part(filename) {
+                diagram_controls.push(reference());
+            }
+        }
+        controls.insert(filename.clone(), part_controls);
+    }
+    untagged_controls.sort_by(|a, b| a.part.cmp(&b.part));
+    unsupported_controls.sort_by(|a, b| a.part.cmp(&b.part));
+    diagram_controls.sort_by(|a, b| a.part.cmp(&b.part));
+    let max_depth = controls
+        .values()
+        .flatten()
+        .map(|c| c.depth)
+        .max()
+        .unwrap_or(0);
+
+    Ok(Inventory {
+        parts,
+        controls,
+        duplicate_tags: find_duplicate_tags(&controlled).into_iter().collect(),
+        untagged_controls,
+        unsupported_controls,
+        diagram_controls,
+        max_depth,
+    })
+}
+
+/**
+ * Deepest nesting of content controls within each other over all parts, with 1 for controls not
+ * contained in another control and 0 if there are no controls.
+ */
+pub fn max_control_depth(data: &ZipData) -> Result<usize, DocxError> {
+    Ok(get_content_controls(data)?
+        .values()
+        .flat_map(|doc| doc.control_positions.iter())
+        .map(|c| c.depth)
+        .max()
+        .unwrap_or(0))
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/**
+ * Fingerprint of the content controls of a template, changing only when controls are added,
+ * removed or changed in tag, type, level or children, but not when their content changes.
+ * FNV-1a is used so the value is stable across runs, platforms and Rust versions.
+ */
+pub fn control_structure_hash(data: &ZipData) -> Result<u64, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut entries: Vec<Vec<String>> = Vec::new();
+    for (filename, doc) in &controlled {
+        for control in &doc.control_positions {
+            let mut entry = vec![
+                filename.clone(),
+                control.tag.clone(),
+                control.r#type.to_string(),
+                format!("{:?}", control.level),
+            ];
+            entry.extend(
+                get_contained_control(&doc.control_positions, control).map(|c| c.tag.clone()),
+            );
+            entries.push(entry);
+        }
+    }
+    entries.sort();
+    Ok(entries.iter().fold(FNV_OFFSET_BASIS, |hash, entry| {
+        let hash = entry.iter().fold(hash, |hash, field| {
+            fnv1a(fnv1a(hash, field.as_bytes()), &[0x1f])
+        });
+        fnv1a(hash, &[0x1e])
+    }))
+}
+
+/**
+ * Build a mapping from the variables whose name starts with `prefix`, keyed by the name with the
+ * prefix stripped. Pass `std::env::vars()` to fill controls from the environment, so `CC_Title`
+ * with the prefix `CC_` fills the control tagged `Title`.
+ */
+pub fn mapping_from_env<I>(prefix: &str, vars: I) -> Mapping
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    vars.into_iter()
+        .filter_map(|(name, value)| {
+            name.strip_prefix(prefix)
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| (tag.to_string(), value))
+        })
+        .collect()
+}
+
+/**
+ * A relationship declared in one of the `_rels` parts of the package.
+ */
+#[derive(Debug, Clone)]
+struct Relationship {
+    /// Part declaring the relationship, empty for the package relationships.
+    source: String,
+    /// Target resolved to a part name for internal targets, verbatim for external ones.
+    target: String,
+    external: bool,
+}
+
+/**
+ * Part a relationships part belongs to, `word/_rels/document.xml.rels` belongs to
+ * `word/document.xml` and `_rels/.rels` to the package itself.
+ */
+fn relationships_source(rels_part: &str) -> Option<String> {
+    let (dir, file) = rels_part.rsplit_once('/').unwrap_or(("", rels_part));
+    let name = file.strip_suffix(".rels")?;
+    let dir = if dir == "_rels" {
+        ""
+    } else {
+        dir.strip_suffix("/_rels")?
+    };
+    if name.is_empty() {
+        Some(String::new())
+    } else if dir.is_empty() {
+        Some(name.into())
+    } else {
+        Some(format!("{}/{}", dir, name))
+    }
+}
+
+/**
+ * Resolve a relationship target relative to the directory of its source part.
+ */
+fn resolve_target(source: &str, target: &str) -> String {
+    let mut segments: Vec<&str> = match target.strip_prefix('/') {
+        Some(_) => Vec::new(),
+        None => source.split('/').collect(),
+    };
+    segments.pop();
+    for segment in target.trim_start_matches('/').split('/') {
+        match segment {
+            "." | "" => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+fn relationships(data: &ZipData) -> Vec<Relationship> {
+    let mut rels = Vec::new();
+    for (filename, content) in data {
+        let Some(source) = relationships_source(filename) else {
+            continue;
+        };
+        let Ok(content) = str::from_utf8(content) else {
+            continue;
+        };
+        let mut reader = Reader::from_str(content);
+        loop {
+            match reader.read_event() {
+                Ok(Event::Eof) | Err(_) => break,
+                Ok(Event::Start(e)) | Ok(Event::Empty(e))
+                    if e.local_name().as_ref() == b"Relationship" =>
+                {
+                    let external =
+                        attribute_value(&e, b"TargetMode").as_deref() == Some("External");
+                    let target = attribute_value(&e, b"Target").unwrap_or_default();
+                    rels.push(Relationship {
+                        target: if external {
+                            target
+                        } else {
+                            resolve_target(&source, &target)
+                        },
+                        source: source.clone(),
+                        external,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    rels
+}
+
+/**
+ * A media file of the package, such as an embedded image.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MediaPart {
+    pub name: String,
+    pub bytes_len: usize,
+    /// Parts with a relationship to the media file, empty for orphaned media.
+    pub referenced_by: Vec<String>,
+}
+
+/**
+ * List the files in `media` directories of the package with the parts referencing them, sorted by
+ * name.
+ */
+pub fn media_parts(data: &ZipData) -> Vec<MediaPart> {
+    let rels = relationships(data);
+    let mut media: Vec<MediaPart> = data
+        .iter()
+        .filter(|(name, _)| name.starts_with("media/") || name.contains("/media/"))
+        .map(|(name, content)| {
+            let mut referenced_by: Vec<String> = rels
+                .iter()
+                .filter(|rel| !rel.external && &rel.target == name)
+                .map(|rel| rel.source.clone())
+                .collect();
+            referenced_by.sort();
+            referenced_by.dedup();
+            MediaPart {
+                name: name.clone(),
+                bytes_len: content.len(),
+                referenced_by,
+            }
+        })
+        .collect();
+    media.sort_by(|a, b| a.name.cmp(&b.name));
+    media
+}
+
+/**
+ * How content controls are filled when their tag has no entry in the mapping.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum MissingBehavior {
+    /// Replace the content with the missing placeholder.
+    #[default]
+    Placeholder,
+    /// Keep the original content of the control, still filling any controls nested within it.
+    /// Mapping values equal to the placeholder are treated as missing too, so values read back
+    /// from a previously filled document do not overwrite the original content. Repeating
+    /// sections without an entry in the repeat mappings keep their items exactly as they are.
+    KeepOriginal,
+    /// Fill the current text of the control back in, such as a default it holds in the
+    /// template, written like a mapping value and thus with the formatting of generated runs.
+    /// Controls holding other controls, controls showing their placeholder text and repeating
+    /// sections are kept as with `KeepOriginal`.
+    CurrentValue,
+}
+
+/**
+ * Parts of a package filled by the `map_content_controls` functions.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FileFilter {
+    /// Every part with content controls, including headers, footers and footnotes.
+    #[default]
+    All,
+    /// Only the main document part `word/document.xml`, leaving the other parts unchanged.
+    DocumentOnly,
+}
+
+impl FileFilter {
+    fn includes(&self, filename: &str) -> bool {
+        match self {
+            FileFilter::All => true,
+            FileFilter::DocumentOnly => filename == "word/document.xml",
+        }
+    }
+}
+
+/**
+ * Options of the `map_content_controls` functions. Start from `MapOptions::new()` and adjust it
+ * with the builder methods, e.g. `MapOptions::new().escape(true).files(FileFilter::DocumentOnly)`,
+ * or set the fields directly.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MapOptions {
+    pub missing: MissingBehavior,
+    /// Escape every mapping value as plain text, so values that look like WordprocessingML
+    /// markup are shown as typed instead of being inserted as runs and paragraphs.
+    pub escape: bool,
+    /// Parts whose content controls are filled.
+    pub files: FileFilter,
+    /// Format plain values with inline markup, `<b>`, `<i>`, `<sub>` and `<sup>` tags and
+    /// `**bold**` and `*italic*` spans, e.g. `H<sub>2</sub>O`, writing a run for each formatted
+    /// span. Without it such values are written as they are typed.
+    pub inline_markup: bool,
+    /// Text filled into controls without a mapping value, `MISSING` if unset. An empty text
+    /// leaves such controls empty.
+    pub missing_placeholder: Option<String>,
+    /// Drop paragraphs without runs from the content of filled controls.
+    pub remove_empty_paragraphs: bool,
+    /// Write mapping values containing a `w:sdtContent` element verbatim in place of the
+    /// control's `w:sdtContent`, instead of filling them into the existing one. Values without
+    /// a `w:sdtContent` are filled as usual.
+    pub raw_content_replace: bool,
+    /// Options for repeating sections, keyed by the tag of the section.
+    pub sections: HashMap<String, SectionOptions>,
+    /// Maximum length in characters of the values for a tag, checked before anything is filled.
+    pub max_lengths: HashMap<String, usize>,
+    /// Tags whose filled content is right-to-left, marking the generated run with `w:rtl` and
+    /// the generated paragraph with `w:bidi`. Values bringing their own runs are not changed.
+    pub rtl: HashMap<String, bool>,
+    /// Character style id set as `w:rStyle` of the generated runs, replacing the style of the
+    /// original content. Values bringing their own runs are not changed.
+    pub run_style: Option<String>,
+    /// Character style ids for the generated runs of single tags, taking precedence over
+    /// `run_style`.
+    pub run_styles: HashMap<String, String>,
+    /// Format the generated runs of controls whose first text has no run properties with the first
+    /// `w:rPr` of any run in the content and, failing that, with the `w:rPr` of the `w:sdtPr`,
+    /// which Word applies to text typed into the control.
+    pub inherit_run_properties: bool,
+    /// Formatting of the generated runs of single tags, replacing the run properties of the
+    /// original content. `run_style`, `run_styles` and the other run options still apply on top.
+    /// Values bringing their own runs are not changed.
+    pub run_formats: HashMap<String, RunFormat>,
+    /// Mark the generated runs with `w:noProof`, so codes, ids and names are not flagged by
+    /// spelling and grammar checks. Values bringing their own runs are not changed.
+    pub no_proof: bool,
+    /// Whether to mark the generated runs of single tags with `w:noProof`, taking precedence
+    /// over `no_proof`.
+    pub no_proof_tags: HashMap<String, bool>,
+    /// Leave controls whose current text already equals the mapping value untouched, keeping
+    /// any manual formatting and minimizing differences to the input.
+    pub skip_unchanged: bool,
+    /// Lock written to the `w:sdtPr` of every control filled from a mapping value, e.g.
+    /// `sdtContentLocked` so recipients cannot alter the filled values. Controls keeping their
+    /// content and repeating sections are not changed.
+    pub lock_after_fill: Option<String>,
+    /// Drop the `w:lock` of every control, so the filled document can be edited freely. Locks
+    /// of `lock_after_fill` are still written.
+    pub strip_locks: bool,
+    /// Remove inline plain and rich text controls with an empty value entirely, wrapper and
+    /// content, instead of leaving an empty control in the sentence. A space following the
+    /// control is dropped as well if the text before it already ends with one or the control
+    /// started its paragraph.
+    pub drop_empty: bool,
+    /// Format of number values of typed mappings, written as their JSON text if unset.
+    pub number_format: Option<NumberFormat>,
+    /// Transformation of every mapping value filled into a control, such as trimming or
+    /// redaction. It runs first, before line breaks are adjusted to the control and before the
+    /// value is written, and its result is what `skip_unchanged` compares.
+    pub value_transform: Option<ValueTransform>,
+}
+
+/**
+ * Function mapping the value for a control to the value written, see
+ * `MapOptions::value_transform`.
+ */
+#[derive(Clone)]
+pub struct ValueTransform(pub Arc<TransformFn>);
+
+pub type TransformFn = dyn Fn(&str, &ContentControlPosition) -> String + Send + Sync;
+
+impl ValueTransform {
+    pub fn new<F>(transform: F) -> Self
+    where
+        F: Fn(&str, &ContentControlPosition) -> String + Send + Sync + 'static,
+    {
+        ValueTransform(Arc::new(transform))
+    }
+}
+
+impl fmt::Debug for ValueTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueTransform")
+    }
+}
+
+/**
+ * Direct formatting of the runs generated for the values of a tag, see
+ * `MapOptions::run_formats`.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunFormat {
+    pub bold: bool,
+    pub italic: bool,
+    /// Single underline.
+    pub underline: bool,
+    /// Font size in half points, e.g. 24 for 12pt.
+    pub size_half_points: Option<u32>,
+    /// Text color as hex RGB, e.g. `C00000`.
+    pub color: Option<String>,
+}
+
+impl RunFormat {
+    /**
+     * The `w:rPr` of the format, its children in schema order. Without any formatting set there
+     * are no events.
+     */
+    fn run_properties(&self) -> Vec<Event<'static>> {
+        let with_val = |name: &str, value: &str| {
+            Event::Empty(BytesStart::new(name.to_string()).with_attributes([("w:val", value)]))
+        };
+        let mut children = Vec::new();
+        if self.bold {
+            children.push(Event::Empty(BytesStart::new("w:b")));
+        }
+        if self.italic {
+            children.push(Event::Empty(BytesStart::new("w:i")));
+        }
+        if let Some(color) = &self.color {
+            children.push(with_val("w:color", color));
+        }
+        if let Some(size) = self.size_half_points {
+            children.push(with_val("w:sz", &size.to_string()));
+            children.push(with_val("w:szCs", &size.to_string()));
+        }
+        if self.underline {
+            children.push(with_val("w:u", "single"));
+        }
+        if children.is_empty() {
+            return children;
+        }
+        let mut properties = vec![Event::Start(BytesStart::new("w:rPr"))];
+        properties.extend(children);
+        properties.push(Event::End(BytesEnd::new("w:rPr")));
+        properties
+    }
+}
+
+/**
+ * Separators for writing numbers, e.g. `1,234.5` in en-US and `1.234,5` in de-DE.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumberFormat {
+    /// Separator between groups of three integer digits, none to leave digits ungrouped.
+    pub thousands_separator: Option<char>,
+    pub decimal_separator: char,
+}
+
+impl NumberFormat {
+    pub fn en_us() -> Self {
+        NumberFormat {
+            thousands_separator: Some(','),
+            decimal_separator: '.',
+        }
+    }
+
+    pub fn de_de() -> Self {
+        NumberFormat {
+            thousands_separator: Some('.'),
+            decimal_separator: ',',
+        }
+    }
+
+    /**
+     * Write a number with the separators of the format. Numbers in exponent notation are
+     * written as they are.
+     */
+    pub fn format(&self, number: &serde_json::Number) -> String {
+        let text = number.to_string();
+        if text.contains(['e', 'E']) {
+            return text;
+        }
+        let (sign, digits) = match text.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", text.as_str()),
+        };
+        let (integer, fraction) = match digits.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (digits, None),
+        };
+        let mut formatted = String::from(sign);
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                if let Some(separator) = self.thousands_separator {
+                    formatted.push(separator);
+                }
+            }
+            formatted.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            formatted.push(self.decimal_separator);
+            formatted.push_str(fraction);
+        }
+        formatted
+    }
+}
+
+impl MapOptions {
+    /**
+     * Options filling like `map_content_controls`.
+     */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn missing(mut self, missing: MissingBehavior) -> Self {
+        self.missing = missing;
+        self
+    }
+
+    pub fn missing_placeholder(mut self, placeholder: impl Into<String>) -> Self {
+        self.missing_placeholder = Some(placeholder.into());
+        self
+    }
+
+    pub fn escape(mut self, escape: bool) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    pub fn files(mut self, files: FileFilter) -> Self {
+        self.files = files;
+        self
+    }
+
+    pub fn inline_markup(mut self, inline_markup: bool) -> Self {
+        self.inline_markup = inline_markup;
+        self
+    }
+
+    fn placeholder(&self) -> &str {
+        self.missing_placeholder.as_deref().unwrap_or(MISSING_STR)
+    }
+
+    fn check_lengths(
+        &self,
+        mappings: &Mapping,
+        repeat_mappings: &RepeatMapping,
+    ) -> Result<(), DocxError> {
+        let rows = repeat_mappings.values().flatten();
+        self.check_value_lengths(std::iter::once(mappings).chain(rows))
+    }
+
+    fn check_value_lengths<'m>(
+        &self,
+        values: impl IntoIterator<Item = &'m Mapping>,
+    ) -> Result<(), DocxError> {
+        for (tag, value) in values.into_iter().flatten() {
+            if let Some(&max) = self.max_lengths.get(tag) {
+                let len = value.chars().count();
+                if len > max {
+                    return Err(DocxError::ValueTooLong {
+                        tag: tag.clone(),
+                        len,
+                        max,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Options applying to a single repeating section.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SectionOptions {
+    /// Insert a page break between expanded items, but not before the first. Breaks are written
+    /// as a paragraph for block level items and as a run for inline items, other levels get none.
+    pub page_break_between: bool,
+    /// Tag of a control within the section but outside its items, such as a table header row,
+    /// written once before the items if there is at least one item and dropped otherwise. Its
+    /// content is kept, with nested controls filled from the mappings of the section's scope.
+    pub header_item_tag: Option<String>,
+    /// Field of the items naming the tag or alias of the item template to copy for them, for
+    /// sections with several templates such as a detail and a subtotal row. Items without the
+    /// field, or naming none of the templates, use the first one.
+    pub item_template_field: Option<String>,
+}
+
+/**
+ * The item templates of a repeating section in document order, leaving out the items of
+ * sections nested in it.
+ */
+fn item_templates<'c>(
+    controls: &'c [ContentControlPosition],
+    section: &'c ContentControlPosition,
+) -> Vec<&'c ContentControlPosition> {
+    get_contained_control(controls, section)
+        .filter(|c| {
+            c.r#type == ContentControlType::RepeatingSectionItem
+                && innermost_section(controls, c).is_some_and(|s| s.begin == section.begin)
+        })
+        .collect()
+}
+
+/**
+ * The template named by the `field` of an item, see `SectionOptions::item_template_field`.
+ */
+fn named_template<'c>(
+    templates: &[&'c ContentControlPosition],
+    item: &Mapping,
+    field: Option<&str>,
+) -> Option<&'c ContentControlPosition> {
+    let name = item.get(field?)?;
+    templates
+        .iter()
+        .find(|t| &t.tag == name || (!t.alias.is_empty() && &t.alias == name))
+        .copied()
+}
+
+fn write_page_break<W>(writer: &mut Writer<W>, level: &ControlLevel)
+where
+    W: std::io::Write,
+{
+    let run = r#"<w:r><w:br w:type="page"/></w:r>"#;
+    let fragment = match level {
+        ControlLevel::Inline => run.to_string(),
+        ControlLevel::Block => format!("<w:p>{}</w:p>", run),
+        ControlLevel::Row | ControlLevel::Cell => return,
+    };
+    let _ = write_parsed_content(writer, &fragment);
+}
+
+struct MapContext<'a, 'd> {
+    doc: &'a DocumentData<'d>,
+    controls_at: HashMap<i64, &'a ContentControlPosition>,
+    repeat_mappings: &'a RepeatMapping,
+    options: &'a MapOptions,
+    preserved_at: HashSet<i64>,
+    /// Values for controls by their start, taking precedence over the values by tag.
+    positional: HashMap<i64, &'a str>,
+    /// Items of repeating sections given with the items nested in them.
+    nested: Option<&'a NestedRepeatMapping>,
+    /// Nested items being written, innermost last.
+    scopes: RefCell<Vec<&'a NestedItem>>,
+    /// Markup generated for typed values by tag, see `TypedTags`.
+    generated: Option<&'a Mapping>,
+    /// Part being filled, named in diagnostics.
+    file: &'a str,
+    /// Issues found while filling, see `map_content_controls_with_diagnostics`.
+    diagnostics: RefCell<Vec<Diagnostic>>,
+}
+
+impl<'a, 'd> MapContext<'a, 'd> {
+    fn new(
+        doc: &'a DocumentData<'d>,
+        repeat_mappings: &'a RepeatMapping,
+        options: &'a MapOptions,
+    ) -> Self {
+        MapContext {
+            doc,
+            controls_at: doc.control_positions.iter().map(|c| (c.begin, c)).collect(),
+            repeat_mappings,
+            options,
+            preserved_at: HashSet::new(),
+            positional: HashMap::new(),
+            nested: None,
+            scopes: RefCell::new(Vec::new()),
+            generated: None,
+            file: "",
+            diagnostics: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn file(mut self, file: &'a str) -> Self {
+        self.file = file;
+        self
+    }
+
+    /**
+     * Record an issue with a control, once even if the control is filled for several items.
+     */
+    fn diagnose(&self, control: &ContentControlPosition, message: String) {
+        let diagnostic = Diagnostic {
+            file: self.file.to_string(),
+            tag: control.tag.clone(),
+            message,
+        };
+        let mut diagnostics = self.diagnostics.borrow_mut();
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    fn nested(mut self, nested: &'a NestedRepeatMapping) -> Self {
+        self.nested = Some(nested);
+        self
+    }
+
+    fn generated(mut self, generated: &'a Mapping) -> Self {
+        self.generated = Some(generated);
+        self
+    }
+
+    /**
+     * Whether a value is the markup generated for the typed value of the control, rather than
+     * a value given by the caller.
+     */
+    fn is_generated(&self, control: &ContentControlPosition, value: &str) -> bool {
+        self.generated
+            .and_then(|generated| control_entry(generated, control))
+            .is_some_and(|markup| markup == value)
+    }
+
+    /**
+     * Nested items of a repeating section, from the innermost item being written with a list for
+     * the section and failing that from the nested repeat mappings.
+     */
+    fn nested_items(&self, control: &ContentControlPosition) -> Option<&'a [NestedItem]> {
+        let scoped =
+            self.scopes
+                .borrow()
+                .iter()
+                .rev()
+                .find_map(|item| match control_entry(item, control) {
+                    Some(NestedValue::Items(items)) => Some(items.as_slice()),
+                    _ => None,
+                });
+        scoped.or_else(|| control_entry(self.nested?, control).map(Vec::as_slice))
+    }
+
+    /**
+     * Keep the controls with one of the given tags exactly as they are, unless they are within a
+     * repeating section and thus filled from the repeat mappings.
+     */
+    fn preserve(mut self, tags: &HashSet<String>) -> Self {
+        let controls = &self.doc.control_positions;
+        self.preserved_at = controls
+            .iter()
+            .filter(|control| tags.contains(&control.tag))
+            .filter(|control| {
+                !controls.iter().any(|c| {
+                    c.r#type == ContentControlType::RepeatingSection
+                        && c.begin < control.begin
+                        && control.end < c.end
+                })
+            })
+            .map(|control| control.begin)
+            .collect();
+        self
+    }
+
+    fn resolve_value<'v>(
+        &'v self,
+        values: &'v Mapping,
+        control: &ContentControlPosition,
+    ) -> Option<&'v str> {
+        if let Some(value) = self.positional.get(&control.begin) {
+            return Some(value);
+        }
+        let value = control_entry(values, control)?;
+        if self.options.missing == MissingBehavior::KeepOriginal
+            && value == self.options.placeholder()
+        {
+            return None;
+        }
+        Some(value)
+    }
+
+    fn transform<'v>(&self, value: &'v str, control: &ContentControlPosition) -> Cow<'v, str> {
+        match &self.options.value_transform {
+            Some(ValueTransform(transform)) => Cow::Owned(transform(value, control)),
+            None => Cow::Borrowed(value),
+        }
+    }
+
+    fn raw_content<'v>(
+        &'v self,
+        values: &'v Mapping,
+        control: &ContentControlPosition,
+    ) -> Option<&'v str> {
+        if !self.options.raw_content_replace
+            || matches!(
+                control.r#type,
+                ContentControlType::RepeatingSection | ContentControlType::RepeatingSectionItem
+            )
+        {
+            return None;
+        }
+        self.resolve_value(values, control)
+            .filter(|value| is_markup(value) && get_tag_types(value).contains("w:sdtContent"))
+    }
+
+    fn write_events<W>(&self, writer: &mut Writer<W>, start: i64, end: i64)
+    where
+        W: std::io::Write,
+    {
+        for event in &self.doc.events[start as usize..end as usize] {
+            let _ = writer.write_event(event);
+        }
+    }
+
+    /**
+     * Write the events in `start..end`, filling every control beginning in that span from
+     * `values`.
+     */
+    fn write_span<W>(&self, writer: &mut Writer<W>, start: i64, end: i64, values: &Mapping)
+    where
+        W: std::io::Write,
+    {
+        let mut i = start;
+        let mut trim_at = None;
+        while i < end {
+            if let Some(control) = self.controls_at.get(&i) {
+                if self.preserved_at.contains(&i) {
+                    self.write_events(writer, control.begin, control.end + 1);
+                } else if self.is_dropped(values, control) {
+                    trim_at = self.doubled_space_after(control);
+                } else {
+                    self.write_control(writer, control, values);
+                }
+                i = control.end + 1;
+            } else {
+                match &self.doc.events[i as usize] {
+                    Event::Text(text) if trim_at == Some(i) => {
+                        let text = String::from_utf8_lossy(text);
+                        let _ = writer
+                            .write_event(Event::Text(BytesText::from_escaped(text.trim_start())));
+                    }
+                    _ => self.write_events(writer, i, i + 1),
+                }
+                i += 1;
+            }
+        }
+    }
+
+    /**
+     * Whether a control is removed along with its content, being an inline text control with an
+     * empty value while `drop_empty` is set.
+     */
+    fn is_dropped(&self, values: &Mapping, control: &ContentControlPosition) -> bool {
+        self.options.drop_empty
+            && control.level == ControlLevel::Inline
+            && matches!(
+                control.r#type,
+                ContentControlType::Text | ContentControlType::RichText
+            )
+            && self
+                .resolve_value(values, control)
+                .is_some_and(|value| self.transform(value, control).is_empty())
+    }
+
+    /**
+     * Index of the text event following a removed control whose leading whitespace would double
+     * the whitespace before the control, also if the control started its paragraph. Only the
+     * text of `w:t` elements counts, not whitespace between the elements of an indented part.
+     */
+    fn doubled_space_after(&self, control: &ContentControlPosition) -> Option<i64> {
+        let is_paragraph = |ev: &Event| match ev {
+            Event::Start(e) => e.name() == QName(b"w:p"),
+            Event::End(e) => e.name() == QName(b"w:p"),
+            _ => false,
+        };
+        let events = &self.doc.events;
+        let run_text = |i: usize| match (&events[i], i.checked_sub(1).map(|i| &events[i])) {
+            (Event::Text(text), Some(Event::Start(e))) if e.name() == QName(b"w:t") => Some(text),
+            _ => None,
+        };
+        let before = (0..control.begin as usize)
+            .rev()
+            .take_while(|&i| !is_paragraph(&events[i]))
+            .find_map(run_text);
+        if before.is_some_and(|text| !text.ends_with(b" ")) {
+            return None;
+        }
+        let (index, after) = (control.end as usize + 1..events.len())
+            .take_while(|&i| !is_paragraph(&events[i]))
+            .find_map(|i| Some((i, run_text(i)?)))?;
+        after.starts_with(b" ").then_some(index as i64)
+    }
+
+    fn fill<W>(&self, writer: &mut Writer<W>, control: &ContentControlPosition, value: &str)
+    where
+        W: std::io::Write,
+    {
+        let markup = is_markup(value);
+        let inline_spans = match self.options.inline_markup && !markup {
+            true => inline::parse(value),
+            false => None,
+        };
+        // plain text is escaped so `&`, `<` and `>` are written literally, while markup of typed
+        // values is always written as such
+        let value = if (!self.options.escape || self.is_generated(control, value)) && markup {
+            Cow::Borrowed(value)
+        } else {
+            quick_xml::escape::escape(value)
+        };
+        let format = GeneratedFormat {
+            inherit_run_properties: self.options.inherit_run_properties,
+            run_format: self.options.run_formats.get(&control.tag),
+            rtl: self.options.rtl.get(&control.tag).copied().unwrap_or(false),
+            run_style: self
+                .options
+                .run_styles
+                .get(&control.tag)
+                .or(self.options.run_style.as_ref())
+                .map(String::as_str),
+            no_proof: self
+                .options
+                .no_proof_tags
+                .get(&control.tag)
+                .copied()
+                .unwrap_or(self.options.no_proof),
+            inline_spans,
+        };
+        if self.options.remove_empty_paragraphs {
+            let mut content_writer = Writer::new(Cursor::new(Vec::new()));
+            let _ = write_content(
+                control,
+                &mut content_writer,
+                &value,
+                &self.doc.events,
+                &format,
+            );
+            let content = content_writer.into_inner().into_inner();
+            write_without_empty_paragraphs(writer, &String::from_utf8_lossy(&content));
+        } else {
+            let _ = write_content(control, writer, &value, &self.doc.events, &format);
+        }
+    }
+
+    /**
+     * Write the events of a control before its `w:sdtContent` with the `w:lock` of its
+     * `w:sdtPr` set to `lock`.
+     */
+    fn write_locked_header<W>(&self, writer: &mut Writer<W>, events: &[Event], lock: &str)
+    where
+        W: std::io::Write,
+    {
+        let properties_start = events.iter().position(|ev| match ev {
+            Event::Start(e) | Event::Empty(e) => e.name() == QName(b"w:sdtPr"),
+            _ => false,
+        });
+        let Some(properties_start) = properties_start else {
+            let _ = writer.write_event(events[0].clone());
+            for ev in with_val_property(&[], "w:sdtPr", "w:lock", lock, BEFORE_LOCK) {
+                let _ = writer.write_event(ev);
+            }
+            for ev in &events[1..] {
+                let _ = writer.write_event(ev.clone());
+            }
+            return;
+        };
+        let properties_end = match events[properties_start] {
+            Event::Start(_) => events
+                .iter()
+                .rposition(|ev| matches!(ev, Event::End(e) if e.name() == QName(b"w:sdtPr")))
+                .unwrap_or(properties_start),
+            _ => properties_start,
+        };
+        for ev in &events[..properties_start] {
+            let _ = writer.write_event(ev.clone());
+        }
+        for ev in with_val_property(
+            &events[properties_start..=properties_end],
+            "w:sdtPr",
+            "w:lock",
+            lock,
+            BEFORE_LOCK,
+        ) {
+            let _ = writer.write_event(ev);
+        }
+        for ev in &events[properties_end + 1..] {
+            let _ = writer.write_event(ev.clone());
+        }
+    }
+
+    /**
+     * Check or uncheck a checkbox control by a value of `true`/`1` or `false`/`0`, setting its
+     * `w14:checked` and swapping the displayed symbol. Controls with any other or no value are
+     * kept as they are.
+     */
+    fn write_checkbox<W>(
+        &self,
+        writer: &mut Writer<W>,
+        control: &ContentControlPosition,
+        values: &Mapping,
+    ) where
+        W: std::io::Write,
+    {
+        let checked = self
+            .resolve_value(values, control)
+            .and_then(|value| parse_checked(&self.transform(value, control)));
+        let Some(checked) = checked else {
+            self.write_events(writer, control.begin, control.end + 1);
+            return;
+        };
+        let header = &self.doc.events[control.begin as usize..control.content_begin as usize];
+        let (header, symbol) = checkbox_header(&without_placeholder_flag(header), checked);
+        match self.options.lock_after_fill.as_deref() {
+            Some(lock) => self.write_locked_header(writer, &header, lock),
+            None => {
+                for ev in header {
+                    let _ = writer.write_event(ev);
+                }
+            }
+        }
+        // the symbol goes into the first text of the content, any further text is dropped
+        let mut in_text = false;
+        let mut written = false;
+        for ev in &self.doc.events[control.content_begin as usize..=control.end as usize] {
+            match ev {
+                Event::Start(e) if e.name() == QName(b"w:t") => in_text = true,
+                Event::End(e) if e.name() == QName(b"w:t") => {
+                    if in_text && !written {
+                        let _ = writer.write_event(Event::Text(BytesText::new(&symbol)));
+                        written = true;
+                    }
+                    in_text = false;
+                }
+                Event::Text(_) if in_text => continue,
+                _ => {}
+            }
+            let _ = writer.write_event(ev.clone());
+        }
+    }
+
+    /**
+     * Date of the value for a date control along with its text in the `w:dateFormat` of the
+     * control, ISO 8601 without one. `None` for other controls and for values that are not an
+     * ISO 8601 date, these are filled verbatim.
+     */
+    fn date_value(
+        &self,
+        values: &Mapping,
+        control: &ContentControlPosition,
+    ) -> Option<(Date, String)> {
+        if control.r#type != ContentControlType::Date {
+            return None;
+        }
+        let value = self.resolve_value(values, control)?;
+        let date = Date::parse_iso(&self.transform(value, control))?;
+        let picture = self.doc.events[control.begin as usize..control.content_begin as usize]
+            .iter()
+            .find_map(|ev| match ev {
+                Event::Empty(e) | Event::Start(e) if e.name() == QName(b"w:dateFormat") => e
+                    .try_get_attribute("w:val")
+                    .ok()
+                    .flatten()
+                    .and_then(|attr| attr.unescape_value().ok().map(Cow::into_owned)),
+                _ => None,
+            })
+            .unwrap_or_else(|| "yyyy-MM-dd".into());
+        let text = date.format(&picture);
+        Some((date, text))
+    }
+
+    /**
+     * List item of a dropdown list or combo box selected by the value for the control, matching
+     * the display text of an item or, failing that, its value.
+     */
+    fn list_item<'c>(
+        &self,
+        values: &Mapping,
+        control: &'c ContentControlPosition,
+    ) -> Option<&'c (String, String)> {
+        if !matches!(
+            control.r#type,
+            ContentControlType::DropdownList | ContentControlType::ComboBox
+        ) {
+            return None;
+        }
+        let value = self.transform(self.resolve_value(values, control)?, control);
+        let item = find_list_item(control, &value);
+        if item.is_none() {
+            self.diagnose(
+                control,
+                format!(
+                    "value '{}' is not one of the list items, filled as plain text",
+                    value
+                ),
+            );
+        }
+        item
+    }
+
+    fn write_control<W>(
+        &self,
+        writer: &mut Writer<W>,
+        control: &ContentControlPosition,
+        values: &Mapping,
+    ) where
+        W: std::io::Write,
+    {
+        if !control.content_opened() || !control.content_closed() {
+            self.write_events(writer, control.begin, control.end + 1);
+            return;
+        }
+        let raw_content = self.raw_content(values, control);
+        let filled = raw_content.is_some()
+            || (!matches!(
+                control.r#type,
+                ContentControlType::RepeatingSection | ContentControlType::RepeatingSectionItem
+            ) && self.resolve_value(values, control).is_some());
+        if let Some(binding) = control.get_data_binding().filter(|_| filled) {
+            self.diagnose(
+                control,
+                format!(
+                    "control is bound to {} in custom XML part {}, \
+                     which Word may restore unless it is updated too",
+                    binding.xpath, binding.store_item_id
+                ),
+            );
+        }
+        if control.r#type == ContentControlType::Checkbox {
+            self.write_checkbox(writer, control, values);
+            return;
+        }
+        let date = self.date_value(values, control);
+        let list_item = self.list_item(values, control);
+        let header = &self.doc.events[control.begin as usize..control.content_begin as usize];
+        let header = match (&date, list_item) {
+            (Some((date, _)), _) => Cow::Owned(date_header(header, date)),
+            (None, Some((_, value))) => Cow::Owned(list_header(header, value)),
+            (None, None) => Cow::Borrowed(header),
+        };
+        let header = if filled {
+            Cow::Owned(without_placeholder_flag(&header))
+        } else {
+            header
+        };
+        match self.options.lock_after_fill.as_deref() {
+            Some(lock) if filled => self.write_locked_header(writer, &header, lock),
+            _ => {
+                for ev in header.iter() {
+                    let _ = writer.write_event(ev);
+                }
+            }
+        }
+        if let Some(fragment) = raw_content {
+            let _ = write_parsed_content(writer, fragment);
+            self.write_events(writer, control.content_end + 1, control.end + 1);
+            return;
+        }
+        self.write_events(writer, control.content_begin, control.content_begin + 1);
+        match control.r#type {
+            ContentControlType::RepeatingSection
+                if self.options.missing != MissingBehavior::Placeholder
+                    && control_entry(self.repeat_mappings, control).is_none()
+                    && self.nested_items(control).is_none() =>
+            {
+                self.write_events(writer, control.content_begin + 1, control.content_end);
+            }
+            ContentControlType::RepeatingSection => {
+                let nested_items = self.nested_items(control);
+                let default_values = Vec::new();
+                let new_values = match nested_items {
+                    Some(items) => Cow::Owned(items.iter().map(nested_texts).collect()),
+                    None => Cow::Borrowed(
+                        control_entry(self.repeat_mappings, control).unwrap_or(&default_values),
+                    ),
+                };
+                let templates = item_templates(&self.doc.control_positions, control);
+                if let Some(&first_template) = templates.first() {
+                    let section_options = self.options.sections.get(&control.tag);
+                    let template_field =
+                        section_options.and_then(|section| section.item_template_field.as_deref());
+                    if templates.len() > 1 && template_field.is_none() && !new_values.is_empty() {
+                        self.diagnose(
+                            control,
+                            format!(
+                                "section has {} item templates but no item template field, \
+                                 only the first is used",
+                                templates.len()
+                            ),
+                        );
+                    }
+                    let page_break =
+                        section_options.is_some_and(|section| section.page_break_between);
+                    let header = section_options
+                        .and_then(|section| section.header_item_tag.as_ref())
+                        .and_then(|tag| {
+                            get_contained_control(&self.doc.control_positions, control).find(|c| {
+                                &c.tag == tag
+                                    && c.r#type != ContentControlType::RepeatingSectionItem
+                                    && c.content_opened()
+                                    && c.content_closed()
+                            })
+                        });
+                    if let Some(header) = header.filter(|_| !new_values.is_empty()) {
+                        self.write_events(writer, header.begin, header.content_begin + 1);
+                        self.write_span(
+                            writer,
+                            header.content_begin + 1,
+                            header.content_end,
+                            values,
+                        );
+                        self.write_events(writer, header.content_end, header.end + 1);
+                    }
+                    for (index, new_value) in new_values.iter().enumerate() {
+                        let section_item = named_template(&templates, new_value, template_field)
+                            .unwrap_or(first_template);
+                        if page_break && index > 0 {
+                            write_page_break(writer, &section_item.level);
+                        }
+                        if let Some(items) = nested_items {
+                            self.scopes.borrow_mut().push(&items[index]);
+                        }
+                        self.write_span(
+                            writer,
+                            section_item.begin,
+                            section_item.end + 1,
+                            new_value,
+                        );
+                        if nested_items.is_some() {
+                            self.scopes.borrow_mut().pop();
+                        }
+                    }
+                }
+            }
+            ContentControlType::RepeatingSectionItem => {
+                self.write_span(
+                    writer,
+                    control.content_begin + 1,
+                    control.content_end,
+                    values,
+                );
+            }
+            _ => match (
+                match (&date, list_item) {
+                    (Some((_, text)), _) => Some(Cow::Borrowed(text.as_str())),
+                    (None, Some((display_text, _))) => Some(Cow::Borrowed(display_text.as_str())),
+                    (None, None) => self
+                        .resolve_value(values, control)
+                        .map(|value| self.transform(value, control)),
+                },
+                &self.options.missing,
+            ) {
+                (Some(new_value), _)
+                    if self.options.skip_unchanged
+                        && control_text(control, &self.doc.events) == *new_value =>
+                {
+                    self.write_events(writer, control.content_begin + 1, control.content_end);
+                }
+                (Some(new_value), _) => self.fill(writer, control, &new_value),
+                (None, MissingBehavior::Placeholder) => {
+                    self.fill(writer, control, self.options.placeholder())
+                }
+                (None, MissingBehavior::CurrentValue)
+                    if get_contained_control(&self.doc.control_positions, control)
+                        .next()
+                        .is_none()
+                        && !shows_placeholder(
+                            &self.doc.events
+                                [control.begin as usize..control.content_begin as usize],
+                        ) =>
+                {
+                    self.fill(writer, control, &control_text(control, &self.doc.events))
+                }
+                (None, MissingBehavior::KeepOriginal | MissingBehavior::CurrentValue) => {
+                    self.write_span(
+                        writer,
+                        control.content_begin + 1,
+                        control.content_end,
+                        values,
+                    );
+                }
+            },
+        }
+        self.write_events(writer, control.content_end, control.end + 1);
+    }
+}
+
+/**
+ * Fill content controls with the values of `mappings`, and repeating sections with an item per
+ * entry of `repeat_mappings`. Values are looked up by the tag of a control and, if there is
+ * none for the tag, by its alias.
+ *
+ * A repeating section keeps its own `w:sdtPr` and `w:sdtContent`, which hold one copy of an
+ * item template per entry and nothing else, see `SectionOptions::item_template_field` for
+ * sections with several templates. An empty list, like a missing one, thus leaves an empty
+ * section behind.
+ *
+ * A repeating section with a value in `mappings` but no entry in `repeat_mappings` is left
+ * empty as well, while `map_content_controls_with` fails with `DocxError::ExpectedRepeatData`.
+ */
+pub fn map_content_controls(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+) -> ZipData {
+    fill_documents(
+        data,
+        controlled,
+        mappings,
+        repeat_mappings,
+        &NestedRepeatMapping::new(),
+        &MapOptions::default(),
+        &TypedTags::default(),
+    )
+    .0
+}
+
+/**
+ * Fill content controls like `map_content_controls`, with the behavior adjusted by `options`.
+ * Fails with `DocxError::ValueTooLong` if a value exceeds its configured maximum length, and with
+ * `DocxError::ExpectedRepeatData` if a repeating section has a single value in `mappings` but no
+ * items in `repeat_mappings`.
+ */
+pub fn map_content_controls_with(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    options: &MapOptions,
+) -> Result<ZipData, DocxError> {
+    map_documents(
+        data,
+        controlled,
+        mappings,
+        repeat_mappings,
+        &NestedRepeatMapping::new(),
+        options,
+        &TypedTags::default(),
+    )
+    .map(|(mapped_data, _)| mapped_data)
+}
+
+/**
+ * Fill content controls like `map_content_controls_with`, taking the items of repeating sections
+ * from `repeat_mappings` with the items of sections nested in them, e.g.
+ * `{"Regions": [{"Region": "North", "Cities": [{"City": "Hamburg"}]}]}`. A section within an
+ * item is repeated with the list of that item, so every region gets its own cities. Sections
+ * without a list in the enclosing item use the list for their tag in `repeat_mappings`.
+ */
+pub fn map_content_controls_nested(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &NestedRepeatMapping,
+    options: &MapOptions,
+) -> Result<ZipData, DocxError> {
+    let mut rows = Vec::new();
+    flatten_nested(repeat_mappings.values().flatten(), &mut rows);
+    options.check_value_lengths(&rows)?;
+    map_documents(
+        data,
+        controlled,
+        mappings,
+        &RepeatMapping::new(),
+        repeat_mappings,
+        options,
+        &TypedTags::default(),
+    )
+    .map(|(mapped_data, _)| mapped_data)
+}
+
+/**
+ * Issue found while filling that did not prevent filling.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    /// Part of the control the issue is about.
+    pub file: String,
+    pub tag: String,
+    pub message: String,
+}
+
+/**
+ * Fill content controls like `map_content_controls_with`, also returning diagnostics for values
+ * that were filled but may not be what the template expects. These are values for dropdown
+ * lists and combo boxes matching neither the display text nor the value of any of their list
+ * items, written as plain text, and repeating sections with several item templates but no
+ * `SectionOptions::item_template_field`, of which only the first template is used, and filled
+ * controls with a `DataBinding`, whose custom XML part has to be updated as well.
+ */
+pub fn map_content_controls_with_diagnostics(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    options: &MapOptions,
+) -> Result<(ZipData, Vec<Diagnostic>), DocxError> {
+    map_documents(
+        data,
+        controlled,
+        mappings,
+        repeat_mappings,
+        &NestedRepeatMapping::new(),
+        options,
+        &TypedTags::default(),
+    )
+}
+
+enum TypedValue {
+    Preserve,
+    Text(String),
+}
+
+/**
+ * An image to place in a picture control.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageData {
+    /// Media type such as `image/png`.
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+impl ImageData {
+    /**
+     * Decode a base64 data URI such as `data:image/png;base64,iVBOR...`. Without a media type,
+     * or with `application/octet-stream`, the type is inferred from the PNG, JPEG or GIF
+     * signature of the data.
+     */
+    pub fn from_data_uri(uri: &str) -> Result<ImageData, String> {
+        let (header, data) = uri
+            .strip_prefix("data:")
+            .and_then(|rest| rest.split_once(','))
+            .ok_or("not a data URI")?;
+        let media_type = header
+            .strip_suffix(";base64")
+            .ok_or("data URI is not base64 encoded")?;
+        let media_type = media_type.split(';').next().unwrap_or_default();
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(data.trim())
+            .map_err(|e| format!("invalid base64: {}", e))?;
+        let content_type = match media_type {
+            "" | "application/octet-stream" => match bytes.as_slice() {
+                [0x89, b'P', b'N', b'G', ..] => "image/png",
+                [0xff, 0xd8, ..] => "image/jpeg",
+                [b'G', b'I', b'F', b'8', ..] => "image/gif",
+                _ => return Err("unknown image type".into()),
+            },
+            media_type => media_type,
+        };
+        image_extension(content_type)
+            .ok_or_else(|| format!("unsupported image type {}", content_type))?;
+        Ok(ImageData {
+            content_type: content_type.into(),
+            bytes,
+        })
+    }
+}
+
+fn image_extension(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/png" => Some("png"),
+        "image/jpeg" => Some("jpeg"),
+        "image/gif" => Some("gif"),
+        "image/bmp" => Some("bmp"),
+        "image/tiff" => Some("tiff"),
+        _ => None,
+    }
+}
+
+fn is_image_value(object: &serde_json::Map<String, MappingValue>) -> bool {
+    object.get("type").and_then(MappingValue::as_str) == Some("image")
+}
+
+fn is_hyperlink_value(object: &serde_json::Map<String, MappingValue>) -> bool {
+    object.get("type").and_then(MappingValue::as_str) == Some("hyperlink")
+}
+
+/// Relationship type of hyperlinks.
+const HYPERLINK_RELATIONSHIP: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink";
+
+/**
+ * Ids of the relationships in a relationships part.
+ */
+fn relationship_ids(rels: &[u8]) -> HashSet<String> {
+    let mut reader = Reader::from_reader(rels);
+    let mut ids = HashSet::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Empty(e)) | Ok(Event::Start(e)) if e.name() == QName(b"Relationship") => {
+                if let Some(id) = e.try_get_attribute("Id").ok().flatten() {
+                    ids.insert(String::from_utf8_lossy(&id.value).to_string());
+                }
+            }
+            Ok(_) => {}
+        }
+    }
+    ids
+}
+
+/**
+ * External relationships of all relationships parts, such as hyperlink URLs, to audit the
+ * targets a document points to. Entries are the relationships part, the relationship id and the
+ * target, sorted by part and in document order within a part.
+ */
+pub fn external_relationships(data: &ZipData) -> Vec<(String, String, String)> {
+    let mut parts: Vec<&String> = data.keys().filter(|name| name.ends_with(".rels")).collect();
+    parts.sort();
+    let mut relationships = Vec::new();
+    for part in parts {
+        let mut reader = Reader::from_reader(data[part].as_slice());
+        loop {
+            match reader.read_event() {
+                Ok(Event::Eof) | Err(_) => break,
+                Ok(Event::Empty(e)) | Ok(Event::Start(e)) if e.name() == QName(b"Relationship") => {
+                    let attribute = |key: &str| {
+                        e.try_get_attribute(key)
+                            .ok()
+                            .flatten()
+                            .and_then(|attr| attr.unescape_value().ok().map(Cow::into_owned))
+                    };
+                    if attribute("TargetMode").as_deref() == Some("External") {
+                        relationships.push((
+                            part.clone(),
+                            attribute("Id").unwrap_or_default(),
+                            attribute("Target").unwrap_or_default(),
+                        ));
+                    }
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+    relationships
+}
+
+/**
+ * Relationships part with an external relationship added, creating the part if there is none.
+ * Fails with `DocxError::Xml` if the part named `rels_part` is malformed.
+ */
+fn with_external_relationship(
+    rels_part: &str,
+    rels: Option<&[u8]>,
+    id: &str,
+    relationship_type: &str,
+    target: &str,
+) -> Result<Vec<u8>, DocxError> {
+    let rels = rels.unwrap_or(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+            "\n",
+            r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"></Relationships>"#
+        )
+        .as_bytes(),
+    );
+    let mut relationship = BytesStart::new("Relationship");
+    relationship.push_attribute(("Id", id));
+    relationship.push_attribute(("Type", relationship_type));
+    relationship.push_attribute(("Target", target));
+    relationship.push_attribute(("TargetMode", "External"));
+    let mut reader = Reader::from_reader(rels);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(DocxError::Xml {
+                    part: rels_part.into(),
+                    position: reader.buffer_position(),
+                    source: e,
+                })
+            }
+            Ok(Event::End(e)) if e.name() == QName(b"Relationships") => {
+                let _ = writer.write_event(Event::Empty(relationship.borrow()));
+                let _ = writer.write_event(Event::End(e));
+            }
+            // a part without relationships yet, expanded to hold the new one
+            Ok(Event::Empty(e)) if e.name() == QName(b"Relationships") => {
+                let _ = writer.write_event(Event::Start(e.borrow()));
+                let _ = writer.write_event(Event::Empty(relationship.borrow()));
+                let _ = writer.write_event(Event::End(e.to_end()));
+            }
+            Ok(event) => {
+                let _ = writer.write_event(event);
+            }
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+/// Namespace of relationship ids such as the `r:id` of hyperlinks.
+const RELATIONSHIPS_NAMESPACE: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+/**
+ * A part with `xmlns:r` declared on its root element for the `r:` attributes written to it,
+ * unchanged if the root already declares it. Fails with `DocxError::Xml` if the part is
+ * malformed before its root element.
+ */
+fn with_relationships_namespace(filename: &str, part: Vec<u8>) -> Result<Vec<u8>, DocxError> {
+    let mut reader = Reader::from_reader(part.as_slice());
+    let (root, closing) = loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => break (e, 1),
+            Ok(Event::Empty(e)) => break (e, 2),
+            Ok(Event::Eof) => return Ok(part),
+            Ok(_) => {}
+            Err(e) => {
+                return Err(DocxError::Xml {
+                    part: filename.into(),
+                    position: reader.buffer_position(),
+                    source: e,
+                })
+            }
+        }
+    };
+    if root
+        .attributes()
+        .flatten()
+        .any(|attr| attr.key == QName(b"xmlns:r"))
+    {
+        return Ok(part);
+    }
+    let at = reader.buffer_position() - closing;
+    let declaration = format!(r#" xmlns:r="{}""#, RELATIONSHIPS_NAMESPACE);
+    let mut declared = part[..at].to_vec();
+    declared.extend_from_slice(declaration.as_bytes());
+    declared.extend_from_slice(&part[at..]);
+    Ok(declared)
+}
+
+/**
+ * Relationships part of a part, e.g. `word/_rels/document.xml.rels` for `word/document.xml`.
+ */
+fn relationships_part(part: &str) -> String {
+    match part.rsplit_once('/') {
+        Some((dir, file)) => format!("{}/_rels/{}.rels", dir, file),
+        None => format!("_rels/{}.rels", part),
+    }
+}
+
+/**
+ * Place images in the picture controls with their tag. The image each `a:blip` in the control
+ * embeds is replaced by a new media part next to the filled part, pointing its relationship to
+ * it and adding a content type for the image extension if needed. Other pictures sharing the
+ * relationship show the new image too, while the previous media part stays in the package.
+ */
+fn place_images(
+    data: &mut ZipData,
+    controlled: &ParsedDocuments,
+    images: &HashMap<String, ImageData>,
+) {
+    let mut replaced = HashSet::new();
+    let mut filenames: Vec<&String> = controlled.keys().collect();
+    filenames.sort();
+    for filename in filenames {
+        let doc = &controlled[filename];
+        let rels_part = relationships_part(filename);
+        for control in &doc.control_positions {
+            let Some(image) = images.get(&control.tag) else {
+                continue;
+            };
+            if !control.content_opened() || !control.content_closed() {
+                continue;
+            }
+            let embeds: Vec<String> = doc.events
+                [control.content_begin as usize..control.content_end as usize]
+                .iter()
+                .filter_map(|ev| match ev {
+                    Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"blip" => e
+                        .attributes()
+                        .flatten()
+                        .find(|attr| attr.key.local_name().as_ref() == b"embed")
+                        .map(|attr| String::from_utf8_lossy(&attr.value).to_string()),
+                    _ => None,
+                })
+                .collect();
+            for embed in embeds {
+                if !replaced.insert((rels_part.clone(), embed.clone())) {
+                    continue;
+                }
+                let Some(rels) = data.get(&rels_part) else {
+                    continue;
+                };
+                let extension = image_extension(&image.content_type).unwrap_or("png");
+                let dir = filename.rsplit_once('/').map_or("", |(dir, _)| dir);
+                let media_name = (1..)
+                    .map(|n| format!("media/control-image{}.{}", n, extension))
+                    .find(|name| !data.contains_key(&format!("{}/{}", dir, name)))
+                    .unwrap_or_default();
+                let rels = retarget_relationship(rels, &embed, &media_name);
+                data.insert(rels_part.clone(), rels);
+                data.insert(format!("{}/{}", dir, media_name), image.bytes.clone());
+                if let Some(types) = data.get("[Content_Types].xml") {
+                    let types = with_default_content_type(types, extension, &image.content_type);
+                    data.insert("[Content_Types].xml".into(), types);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Relationships part with the target of the relationship `id` set to `target`.
+ */
+fn retarget_relationship(rels: &[u8], id: &str, target: &str) -> Vec<u8> {
+    let mut reader = Reader::from_reader(rels);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Empty(e))
+                if e.name() == QName(b"Relationship")
+                    && e.try_get_attribute("Id").ok().flatten().map(|a| a.value)
+                        == Some(Cow::Borrowed(id.as_bytes())) =>
+            {
+                let _ = writer.write_event(Event::Empty(with_attribute(
+                    &e,
+                    |key| key == QName(b"Target"),
+                    target,
+                )));
+            }
+            Ok(event) => {
+                let _ = writer.write_event(event);
+            }
+        }
+    }
+    writer.into_inner().into_inner()
+}
+
+/**
+ * Content types part with a `Default` for the extension, unless one is present.
+ */
+fn with_default_content_type(types: &[u8], extension: &str, content_type: &str) -> Vec<u8> {
+    let mut reader = Reader::from_reader(types);
+    let mut events = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(event) => events.push(event.into_owned()),
+        }
+    }
+    let present = events.iter().any(|ev| match ev {
+        Event::Empty(e) if e.name() == QName(b"Default") => e
+            .try_get_attribute("Extension")
+            .ok()
+            .flatten()
+            .is_some_and(|a| a.value.eq_ignore_ascii_case(extension.as_bytes())),
+        _ => false,
+    });
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    for ev in events {
+        if !present && matches!(&ev, Event::End(e) if e.name() == QName(b"Types")) {
+            let mut default = BytesStart::new("Default");
+            default.push_attribute(("Extension", extension));
+            default.push_attribute(("ContentType", content_type));
+            let _ = writer.write_event(Event::Empty(default));
+        }
+        let _ = writer.write_event(ev);
+    }
+    writer.into_inner().into_inner()
+}
+
+/**
+ * Resolve the typed value of a tag, following `coalesce` lists of other tags. Tags already being
+ * resolved are skipped, so lists referring to each other cannot loop.
+ */
+fn resolve_typed(
+    mappings: &TypedMapping,
+    tag: &str,
+    resolving: &mut Vec<String>,
+    number_format: Option<&NumberFormat>,
+) -> Option<TypedValue> {
+    if resolving.iter().any(|t| t == tag) {
+        return None;
+    }
+    match mappings.get(tag)? {
+        MappingValue::Null => Some(TypedValue::Preserve),
+        MappingValue::String(text) => Some(TypedValue::Text(text.clone())),
+        MappingValue::Object(object) if is_image_value(object) || is_hyperlink_value(object) => {
+            None
+        }
+        MappingValue::Object(object) if object.contains_key("coalesce") => {
+            let keys = object["coalesce"].as_array()?;
+            resolving.push(tag.into());
+            let resolved = keys.iter().filter_map(|key| key.as_str()).find_map(|key| {
+                match resolve_typed(mappings, key, resolving, number_format) {
+                    Some(TypedValue::Text(text)) if !text.is_empty() => Some(text),
+                    _ => None,
+                }
+            });
+            resolving.pop();
+            resolved.map(TypedValue::Text)
+        }
+        MappingValue::Number(number) => Some(TypedValue::Text(match number_format {
+            Some(format) => format.format(number),
+            None => number.to_string(),
+        })),
+        other => Some(TypedValue::Text(other.to_string())),
+    }
+}
+
+/**
+ * Fill content controls from JSON values. Strings are filled as they are, other values as their
+ * JSON text. A `null` value leaves the control exactly as it is in the template, while tags
+ * without any value follow the `missing` behavior of `options`.
+ *
+ * Numbers are written with the `number_format` of `options` if one is set.
+ *
+ * An object `{"coalesce": ["PreferredName", "LegalName"]}` fills the control with the first
+ * non-empty value of the listed tags, and counts as missing if all of them are empty.
+ *
+ * An object `{"type": "image", "data_uri": "data:image/png;base64,..."}` places the image in the
+ * picture controls of the tag, see `ImageData::from_data_uri`. Their content is kept otherwise.
+ *
+ * An object `{"type": "hyperlink", "text": "Click", "url": "https://..."}` fills the control with
+ * a `w:hyperlink` showing the text, or the URL without one, in the `Hyperlink` character style.
+ * The URL is added as an external relationship of each part with a control of the tag.
+ */
+pub fn map_content_controls_typed(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &TypedMapping,
+    repeat_mappings: &RepeatMapping,
+    options: &MapOptions,
+) -> Result<ZipData, DocxError> {
+    let mut values = Mapping::new();
+    let mut typed = TypedTags::default();
+    let mut images = HashMap::new();
+    // hyperlinks get a relationship id unused by all parts, so the same markup fits every part
+    let mut hyperlinks: HashMap<String, (String, String)> = HashMap::new();
+    let mut used_ids: HashSet<String> = controlled
+        .keys()
+        .filter_map(|filename| data.get(&relationships_part(filename)))
+        .flat_map(|rels| relationship_ids(rels))
+        .collect();
+    for (tag, value) in mappings {
+        if let MappingValue::Object(object) = value {
+            if is_hyperlink_value(object) {
+                let url = object
+                    .get("url")
+                    .and_then(MappingValue::as_str)
+                    .filter(|url| !url.is_empty())
+                    .ok_or_else(|| DocxError::InvalidValue {
+                        tag: tag.clone(),
+                        reason: "hyperlink without url".into(),
+                    })?;
+                let text = object
+                    .get("text")
+                    .and_then(MappingValue::as_str)
+                    .unwrap_or(url);
+                let id = (1..)
+                    .map(|n| format!("rIdLink{}", n))
+                    .find(|id| !used_ids.contains(id))
+                    .unwrap_or_default();
+                used_ids.insert(id.clone());
+                values.insert(
+                    tag.clone(),
+                    format!(
+                        concat!(
+                            r#"<w:hyperlink r:id="{}" w:history="1"><w:r><w:rPr>"#,
+                            r#"<w:rStyle w:val="Hyperlink"/></w:rPr><w:t xml:space="preserve">{}</w:t>"#,
+                            r#"</w:r></w:hyperlink>"#
+                        ),
+                        id,
+                        quick_xml::escape::escape(text)
+                    ),
+                );
+                typed.generated.insert(tag.clone(), values[tag].clone());
+                hyperlinks.insert(tag.clone(), (id, url.to_string()));
+                continue;
+            }
+            if is_image_value(object) {
+                let uri = object
+                    .get("data_uri")
+                    .and_then(MappingValue::as_str)
+                    .unwrap_or_default();
+                let image =
+                    ImageData::from_data_uri(uri).map_err(|reason| DocxError::InvalidValue {
+                        tag: tag.clone(),
+                        reason,
+                    })?;
+                images.insert(tag.clone(), image);
+                typed.preserved.insert(tag.clone());
+                continue;
+            }
+        }
+        match resolve_typed(
+            mappings,
+            tag,
+            &mut Vec::new(),
+            options.number_format.as_ref(),
+        ) {
+            Some(TypedValue::Preserve) => {
+                typed.preserved.insert(tag.clone());
+            }
+            Some(TypedValue::Text(text)) => {
+                values.insert(tag.clone(), text);
+            }
+            None => {}
+        }
+    }
+    let (mut mapped_data, _) = map_documents(
+        data,
+        controlled,
+        &values,
+        repeat_mappings,
+        &NestedRepeatMapping::new(),
+        options,
+        &typed,
+    )?;
+    place_images(&mut mapped_data, controlled, &images);
+    for (filename, doc) in controlled {
+        if !options.files.includes(filename) {
+            continue;
+        }
+        let rels_part = relationships_part(filename);
+        let links: BTreeSet<_> = doc
+            .control_positions
+            .iter()
+            .filter_map(|control| control_entry(&hyperlinks, control))
+            .collect();
+        if links.is_empty() {
+            continue;
+        }
+        for (id, url) in links {
+            let rels = with_external_relationship(
+                &rels_part,
+                mapped_data.get(&rels_part).map(Vec::as_slice),
+                id,
+                HYPERLINK_RELATIONSHIP,
+                url,
+            )?;
+            mapped_data.insert(rels_part.clone(), rels);
+        }
+        if let Some(part) = mapped_data.remove(filename) {
+            mapped_data.insert(
+                filename.clone(),
+                with_relationships_namespace(filename, part)?,
+            );
+        }
+    }
+    Ok(mapped_data)
+}
+
+/**
+ * Fill content controls by their position instead of their tag, for templates whose controls
+ * lack tags. Controls are numbered across the package: parts in the order of their names, e.g.
+ * `word/document.xml` before `word/footer1.xml` before `word/header1.xml`, and within a part in
+ * the order their `w:sdt` start tags appear, so an outer control comes before the controls
+ * nested in it. Repeating sections and their items take no value and are not numbered.
+ *
+ * The Nth control is filled with `values[N]`, strings as they are and other values as their JSON
+ * text. Controls with a `null` value or past the end of `values` keep their content. Filling a
+ * control replaces the controls nested in it along with the rest of its content.
+ */
+pub fn map_by_index(data: &ZipData, values: &[MappingValue]) -> Result<ZipData, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut filenames: Vec<&String> = controlled.keys().collect();
+    filenames.sort();
+    let options = MapOptions {
+        missing: MissingBehavior::KeepOriginal,
+        ..Default::default()
+    };
+    let repeat_mappings = RepeatMapping::new();
+    let mut remaining = values.iter();
+    let mut mapped_data = data.clone();
+    for filename in filenames {
+        let doc = &controlled[filename];
+        let mut controls: Vec<&ContentControlPosition> = doc
+            .control_positions
+            .iter()
+            .filter(|c| {
+                !matches!(
+                    c.r#type,
+                    ContentControlType::RepeatingSection | ContentControlType::RepeatingSectionItem
+                )
+            })
+            .collect();
+        controls.sort_by_key(|c| c.begin);
+        let texts: Vec<(i64, String)> = controls
+            .iter()
+            .zip(&mut remaining)
+            .filter_map(|(control, value)| match value {
+                MappingValue::Null => None,
+                MappingValue::String(text) => Some((control.begin, text.clone())),
+                other => Some((control.begin, other.to_string())),
+            })
+            .collect();
+        let mut context = MapContext::new(doc, &repeat_mappings, &options);
+        context.positional = texts.iter().map(|(i, t)| (*i, t.as_str())).collect();
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        context.write_span(&mut writer, 0, doc.events.len() as i64, &Mapping::new());
+        mapped_data.insert(
+            filename.clone(),
+            doc.encode(writer.into_inner().into_inner()),
+        );
+    }
+    Ok(mapped_data)
+}
+
+/**
+ * Turn a filled document back into a template, the inverse of filling. Every control not holding
+ * other controls gets its content replaced by a single run of `placeholder_text`, or of its tag
+ * if none is given, while the `w:sdt` elements with their properties are kept. Repeating sections
+ * are reduced to their first item. Checkboxes and controls of unsupported types, e.g. pictures,
+ * keep their content.
+ */
+pub fn to_template(data: &ZipData, placeholder_text: Option<&str>) -> Result<ZipData, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let options = MapOptions {
+        missing: MissingBehavior::KeepOriginal,
+        ..Default::default()
+    };
+    let mut mapped_data = data.clone();
+    for (filename, doc) in &controlled {
+        let controls = &doc.control_positions;
+        let repeat_mappings: RepeatMapping = controls
+            .iter()
+            .filter(|c| c.r#type == ContentControlType::RepeatingSection)
+            .map(|c| (c.tag.clone(), vec![Mapping::new()]))
+            .collect();
+        let mut context = MapContext::new(doc, &repeat_mappings, &options);
+        context.positional = controls
+            .iter()
+            .filter(|c| {
+                !matches!(
+                    c.r#type,
+                    ContentControlType::RepeatingSection
+                        | ContentControlType::RepeatingSectionItem
+                        | ContentControlType::Checkbox
+                        | ContentControlType::Unsupported
+                ) && get_contained_control(controls, c).next().is_none()
+            })
+            .map(|c| (c.begin, placeholder_text.unwrap_or(&c.tag)))
+            .collect();
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        context.write_span(&mut writer, 0, doc.events.len() as i64, &Mapping::new());
+        mapped_data.insert(
+            filename.clone(),
+            doc.encode(writer.into_inner().into_inner()),
+        );
+    }
+    Ok(mapped_data)
+}
+
+/**
+ * Reset a template to a blank state, emptying the content of every control not holding other
+ * controls while keeping the `w:sdt` elements with their properties, so they can still be filled
+ * in Word. Unlike `remove_content_controls`, the controls stay. This is `to_template` with an
+ * empty placeholder text, so repeating sections are reduced to their first item as well.
+ */
+pub fn clear_control_content(data: &ZipData) -> Result<ZipData, DocxError> {
+    to_template(data, Some(""))
+}
+
+/**
+ * Tags of typed values that are not filled from the mapping values as given.
+ */
+#[derive(Default)]
+struct TypedTags {
+    /// Tags whose controls are kept exactly as they are.
+    preserved: HashSet<String>,
+    /// Markup generated for typed values such as hyperlinks by tag, which is written as markup
+    /// even with `MapOptions::escape`, as that only applies to the values of the caller.
+    generated: Mapping,
+}
+
+fn map_documents(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    nested: &NestedRepeatMapping,
+    options: &MapOptions,
+    typed: &TypedTags,
+) -> Result<(ZipData, Vec<Diagnostic>), DocxError> {
+    options.check_lengths(mappings, repeat_mappings)?;
+    for (filename, doc) in controlled {
+        if options.files.includes(filename) {
+            check_repeat_data(doc, mappings, repeat_mappings, nested)?;
+        }
+    }
+    Ok(fill_documents(
+        data,
+        controlled,
+        mappings,
+        repeat_mappings,
+        nested,
+        options,
+        typed,
+    ))
+}
+
+/**
+ * Fill the parts of a package like `map_documents`, without checking the values first. The
+ * diagnostics are ordered by part and within a part by control.
+ */
+fn fill_documents(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    nested: &NestedRepeatMapping,
+    options: &MapOptions,
+    typed: &TypedTags,
+) -> (ZipData, Vec<Diagnostic>) {
+    debug_assert_parsed_from(data, controlled);
+    let map_file = |(filename, data): (&String, &Vec<u8>)| {
+        let (mapped, diagnostics) = map_package_part(
+            filename,
+            controlled,
+            mappings,
+            repeat_mappings,
+            nested,
+            options,
+            typed,
+        )
+        .unwrap_or_else(|| (data.clone(), Vec::new()));
+        (filename.clone(), mapped, diagnostics)
+    };
+    #[cfg(feature = "rayon")]
+    let mapped: Vec<_> = {
+        use rayon::prelude::*;
+        data.par_iter().map(map_file).collect()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let mapped: Vec<_> = data.iter().map(map_file).collect();
+    let mut mapped_data = ZipData::new();
+    let mut diagnostics = Vec::new();
+    for (filename, part, part_diagnostics) in mapped {
+        mapped_data.insert(filename, part);
+        diagnostics.extend(part_diagnostics);
+    }
+    diagnostics.sort_by(|a: &Diagnostic, b: &Diagnostic| a.file.cmp(&b.file));
+    (mapped_data, diagnostics)
+}
+
+/**
+ * Check that the parts were parsed from `data`. Parts parsed from a different package, or with
+ * names normalized differently, would silently stay unfilled.
+ */
+fn debug_assert_parsed_from(data: &ZipData, controlled: &ParsedDocuments) {
+    debug_assert!(
+        controlled
+            .keys()
+            .all(|filename| data.contains_key(filename)),
+        "parsed parts {:?} are not in the package",
+        controlled
+            .keys()
+            .filter(|filename| !data.contains_key(*filename))
+            .collect::<Vec<_>>()
+    );
+}
+
+/**
+ * The filled content of a part of a package with the diagnostics found while filling it, `None`
+ * for parts kept as they are, being without content controls or excluded by `MapOptions::files`.
+ */
+fn map_package_part(
+    filename: &str,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    nested: &NestedRepeatMapping,
+    options: &MapOptions,
+    typed: &TypedTags,
+) -> Option<(Vec<u8>, Vec<Diagnostic>)> {
+    controlled
+        .get(filename)
+        .filter(|_| options.files.includes(filename))
+        .map(|doc| {
+            map_part(
+                filename,
+                doc,
+                mappings,
+                repeat_mappings,
+                nested,
+                options,
+                typed,
+            )
+        })
+}
+
+/**
+ * Fail with `DocxError::ExpectedRepeatData` for a repeating section of the part given a single
+ * value in `mappings` but no items, a data shape mistake rather than an empty section.
+ */
+fn check_repeat_data(
+    doc: &DocumentData,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    nested: &NestedRepeatMapping,
+) -> Result<(), DocxError> {
+    match doc.control_positions.iter().find(|c| {
+        c.r#type == ContentControlType::RepeatingSection
+            && control_entry(repeat_mappings, c).is_none()
+            && control_entry(nested, c).is_none()
+            && control_entry(mappings, c).is_some()
+    }) {
+        Some(section) => Err(DocxError::ExpectedRepeatData {
+            tag: section.tag.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn map_part(
+    filename: &str,
+    doc: &DocumentData,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    nested: &NestedRepeatMapping,
+    options: &MapOptions,
+    typed: &TypedTags,
+) -> (Vec<u8>, Vec<Diagnostic>) {
+    let unlocked;
+    let doc = if options.strip_locks {
+        unlocked = doc.without_locks();
+        &unlocked
+    } else {
+        doc
+    };
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let context = MapContext::new(doc, repeat_mappings, options)
+        .preserve(&typed.preserved)
+        .nested(nested)
+        .generated(&typed.generated)
+        .file(filename);
+    context.write_span(&mut writer, 0, doc.events.len() as i64, mappings);
+    (
+        doc.encode(writer.into_inner().into_inner()),
+        context.diagnostics.into_inner(),
+    )
+}
+
+/**
+ * Fill content controls like `map_content_controls_with` and write the filled package to `out`
+ * right away. Each part with content controls is filled and written before the next one, so only
+ * one filled part is buffered at a time, and all other parts, such as media, are written
+ * straight from `data` without being copied.
+ */
+pub fn map_content_controls_to<W: Write + Seek>(
+    data: &ZipData,
+    controlled: &ParsedDocuments,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    options: &MapOptions,
+    out: &mut W,
+) -> Result<(), DocxError> {
+    options.check_lengths(mappings, repeat_mappings)?;
+    for (filename, doc) in controlled {
+        if options.files.includes(filename) {
+            check_repeat_data(doc, mappings, repeat_mappings, &NestedRepeatMapping::new())?;
+        }
+    }
+    debug_assert_parsed_from(data, controlled);
+    let mut writer = zip::ZipWriter::new(out);
+    let file_options = FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(0o755);
+    for (filename, part) in data {
+        writer.start_file(filename, file_options)?;
+        match map_package_part(
+            filename,
+            controlled,
+            mappings,
+            repeat_mappings,
+            &NestedRepeatMapping::new(),
+            options,
+            &TypedTags::default(),
+        ) {
+            Some((mapped, _)) => writer.write_all(&mapped)?,
+            None => writer.write_all(part)?,
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/**
+ * Fill the content controls of a document read from `input` and write the result to `output`,
+ * one zip entry at a time.
+ *
+ * Unlike `list_zip_contents` followed by `map_content_controls` and `zip_dir`, the package is
+ * never held in memory as a whole. Only the part being filled is decompressed, parsed and
+ * buffered, and it is written and dropped before the next entry is read. Entries without content
+ * controls, such as media, are copied in their compressed form without being decompressed. The
+ * memory used is thus bounded by the largest part with content controls, not the package size.
+ * Entries are written in the order of the input archive.
+ */
+pub fn process_docx<R, W>(
+    input: R,
+    output: W,
+    mappings: &Mapping,
+    repeat_mappings: &RepeatMapping,
+    options: &MapOptions,
+) -> Result<(), DocxError>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    options.check_lengths(mappings, repeat_mappings)?;
+    let mut archive = zip::ZipArchive::new(input)?;
+    let mut writer = zip::ZipWriter::new(output);
+    let file_options = FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(0o755);
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() || !file.name().ends_with(".xml") {
+            writer.raw_copy_file(file)?;
+            continue;
+        }
+        let name = file.name().to_string();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        drop(file);
+        writer.start_file(name.as_str(), file_options)?;
+        if options.files.includes(&name) && has_content_control(&content) {
+            let doc = parse_document(&name, &content)?;
+            check_repeat_data(&doc, mappings, repeat_mappings, &NestedRepeatMapping::new())?;
+            let (mapped, _) = map_part(
+                &name,
+                &doc,
+                mappings,
+                repeat_mappings,
+                &NestedRepeatMapping::new(),
+                options,
+                &TypedTags::default(),
+            );
+            writer.write_all(&mapped)?;
+        } else {
+            writer.write_all(&content)?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/**
+ * Common formatting of the content of a control, taken from the properties of its text-bearing
+ * run and its first paragraph.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ControlProps {
+    pub bold: bool,
+    pub italic: bool,
+    /// Underline type such as `single`, if underlined.
+    pub underline: Option<String>,
+    /// Font size in half points.
+    pub size: Option<u32>,
+    pub color: Option<String>,
+    pub run_style: Option<String>,
+    pub paragraph_style: Option<String>,
+    pub justification: Option<String>,
+}
+
+/**
+ * Whether a toggle property such as `w:b` is set, which it is unless `w:val` turns it off.
+ */
+fn is_toggled(element: &BytesStart) -> bool {
+    attribute_value(element, b"w:val").is_none_or(|value| is_on(&value))
+}
+
+/**
+ * Direct children of a properties element, given the events of the element.
+ */
+fn property_children<'a>(properties: &'a [Event<'a>]) -> impl Iterator<Item = &'a BytesStart<'a>> {
+    let mut depth = 0;
+    properties.iter().filter_map(move |event| {
+        let child = match event {
+            Event::Start(e) | Event::Empty(e) if depth == 1 => Some(e),
+            _ => None,
+        };
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        child
+    })
+}
+
+impl ControlProps {
+    fn new(control: &ContentControlPosition, events: &[Event]) -> Self {
+        let mut props = ControlProps::default();
+        if control.has_run_params() {
+            let run_params =
+                &events[control.run_params_start as usize..control.run_params_end as usize];
+            for child in property_children(run_params) {
+                let value = || attribute_value(child, b"w:val");
+                match child.name().as_ref() {
+                    b"w:b" => props.bold = is_toggled(child),
+                    b"w:i" => props.italic = is_toggled(child),
+                    b"w:u" => props.underline = value().filter(|u| u != "none"),
+                    b"w:sz" => props.size = value().and_then(|sz| sz.parse().ok()),
+                    b"w:color" => props.color = value(),
+                    b"w:rStyle" => props.run_style = value(),
+                    _ => {}
+                }
+            }
+        }
+        if control.has_paragraph_params() {
+            let paragraph_params = &events
+                [control.paragraph_params_start as usize..control.paragraph_params_end as usize];
+            for child in property_children(paragraph_params) {
+                match child.name().as_ref() {
+                    b"w:pStyle" => props.paragraph_style = attribute_value(child, b"w:val"),
+                    b"w:jc" => props.justification = attribute_value(child, b"w:val"),
+                    _ => {}
+                }
+            }
+        }
+        props
+    }
+}
+
+/**
+ * Read back the formatting of the content of every control, keyed by tag, to confirm a filled
+ * document kept the intended styling. For tags used by several controls the first one is used.
+ */
+pub fn filled_control_properties(
+    data: &ZipData,
+) -> Result<HashMap<String, ControlProps>, DocxError> {
+    let controlled = get_content_controls(data)?;
+    let mut parts: Vec<&String> = controlled.keys().collect();
+    parts.sort();
+    let mut properties = HashMap::new();
+    for part in parts {
+        let doc = &controlled[part];
+        for control in &doc.control_positions {
+            if control.content_opened() && control.content_closed() {
+                properties
+                    .entry(control.tag.clone())
+                    .or_insert_with(|| ControlProps::new(control, &doc.events));
+            }
+        }
+    }
+    Ok(properties)
+}
+
+/**
+ * Replacements of `{{key}}` markers in the joined text of a paragraph, as the new text for each of
+ * the text nodes. Returns `None` if the paragraph has no marker with a key in `mappings`.
+ */
+fn replace_markers(texts: &[String], mappings: &Mapping) -> Option<Vec<String>> {
+    let joined = texts.concat();
+    let mut replacements = Vec::new();
+    let mut search = 0;
+    while let Some(start) = joined[search..].find("{{").map(|i| i + search) {
+        let Some(end) = joined[start..].find("}}").map(|i| i + start + 2) else {
+            break;
+        };
+        match mappings.get(joined[start + 2..end - 2].trim()) {
+            Some(value) => {
+                replacements.push((start, end, value.as_str()));
+                search = end;
+            }
+            None => search = start + 2,
+        }
+    }
+    if replacements.is_empty() {
+        return None;
+    }
+    let mut offsets = Vec::with_capacity(texts.len());
+    let mut offset = 0;
+    for text in texts {
+        offsets.push(offset);
+        offset += text.len();
+    }
+    // text located at a position belongs to the last node starting at or before it
+    let node_at = |pos: usize| offsets.partition_point(|&o| o <= pos).saturating_sub(1);
+    let mut replaced = vec![String::new(); texts.len()];
+    let copy = |replaced: &mut Vec<String>, from: usize, to: usize| {
+        for (i, text) in texts.iter().enumerate() {
+            let begin = from.max(offsets[i]);
+            let end = to.min(offsets[i] + text.len());
+            if begin < end {
+                replaced[i].push_str(&joined[begin..end]);
+            }
+        }
+    };
+    let mut pos = 0;
+    for (start, end, value) in replacements {
+        copy(&mut replaced, pos, start);
+        replaced[node_at(start)].push_str(value);
+        pos = end;
+    }
+    copy(&mut replaced, pos, joined.len());
+    Some(replaced)
+}
+
+/**
+ * Replace `{{key}}` text markers with the values of `mappings`, for simple templates without
+ * content controls. This is independent of content control filling, controls are not touched.
+ *
+ * Markers are matched in the joined text of all runs of a paragraph, so markers Word split over
+ * several runs are found. The value takes the formatting of the run the marker starts in, while
+ * the remaining parts of the marker are removed from the following runs. Markers with a key not
+ * in `mappings` are kept.
+ *
+ * Parts are read and written back in their declared encoding, and fail as in
+ * `get_content_controls` if they cannot be parsed.
+ */
+pub fn simple_replace(data: &ZipData, mappings: &Mapping) -> Result<ZipData, DocxError> {
+    let mut replaced_data = ZipData::new();
+    for (filename, content) in data {
+        if !filename.ends_with(".xml") || !content.windows(2).any(|w| w == b"{{") {
+            replaced_data.insert(filename.into(), content.clone());
+            continue;
+        }
+        let mut doc = parse_document(filename, content)?;
+        let events = std::mem::take(&mut doc.events);
+        // text nodes of the open paragraphs, as (index of w:t, index of text)
+        let mut paragraphs: Vec<Vec<(usize, usize)>> = Vec::new();
+        let mut text_start = None;
+        let mut updates: HashMap<usize, String> = HashMap::new();
+        for (i, event) in events.iter().enumerate() {
+            match event {
+                Event::Start(e) if e.name() == QName(b"w:p") => paragraphs.push(Vec::new()),
+                Event::Start(e) if e.name() == QName(b"w:t") => text_start = Some(i),
+                Event::End(e) if e.name() == QName(b"w:t") => text_start = None,
+                Event::Text(_) => {
+                    if let (Some(start), Some(paragraph)) = (text_start, paragraphs.last_mut()) {
+                        paragraph.push((start, i));
+                    }
+                }
+                Event::End(e) if e.name() == QName(b"w:p") => {
+                    let nodes = paragraphs.pop().unwrap_or_default();
+                    let texts: Vec<String> = nodes
+                        .iter()
+                        .map(|(_, i)| match &events[*i] {
+                            Event::Text(t) => {
+                                t.unescape().map(|t| t.to_string()).unwrap_or_default()
+                            }
+                            _ => String::new(),
+                        })
+                        .collect();
+                    if let Some(replaced) = replace_markers(&texts, mappings) {
+                        for ((_, i), text) in nodes.iter().zip(replaced) {
+                            updates.insert(*i, text);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        let mut text_start: Option<BytesStart> = None;
+        for (i, event) in events.into_iter().enumerate() {
+            match event {
+                // the start of a text is held back until it is known whether the replaced text
+                // needs whitespace preserved
+                Event::Start(e) if e.name() == QName(b"w:t") => {
+                    text_start = Some(e);
+                    continue;
+                }
+                Event::Text(_) if updates.contains_key(&i) => {
+                    let text = &updates[&i];
+                    if let Some(mut start) = text_start.take() {
+                        if text.trim() != text && attribute_value(&start, b"xml:space").is_none() {
+                            start.push_attribute(("xml:space", "preserve"));
+                        }
+                        let _ = writer.write_event(Event::Start(start));
+                    }
+                    let _ = writer.write_event(Event::Text(BytesText::new(text)));
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(start) = text_start.take() {
+                let _ = writer.write_event(Event::Start(start));
+            }
+            let _ = writer.write_event(event);
+        }
+        replaced_data.insert(
+            filename.into(),
+            doc.encode(writer.into_inner().into_inner()),
+        );
+    }
+    Ok(replaced_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{BufReader, BufWriter};
+    use tempfile::tempfile;
+
+    fn load_path(path: &str) -> ZipData {
+        let fname = std::path::Path::new(&path);
+        let file = fs::File::open(fname).unwrap();
+        let reader = BufReader::new(file);
+        list_zip_contents(reader).unwrap()
+    }
+
+    fn document_with_body(body: &str) -> ZipData {
+        let document = format!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" xmlns:w14="http://schemas.microsoft.com/office/word/2010/wordml" xmlns:w15="http://schemas.microsoft.com/office/word/2012/wordml"><w:body>{}</w:body></w:document>"#,
+            body
+        );
+        HashMap::from([("word/document.xml".into(), document.into_bytes())])
+    }
+
+    #[test]
+    fn document_state() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        for (_filename, string) in input_data {
+            if has_content_control(&string) {
+                let enc_str = str::from_utf8(&string).expect("should be utf-8 encoded string");
+                let mut reader = Reader::from_str(enc_str);
+                let mut state = DocumentState::new();
+                while !state.is_eof {
+                    let event = reader.read_event();
+                    match event {
+                        Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+                        Ok(e) => state.consume(&e),
+                    }
+                }
+                assert!(!state.controls.is_empty());
+                assert!(state.controls.iter().any(|c| c.begin >= 0
+                    && c.end >= 0
+                    && c.content_begin >= 0
+                    && c.content_end >= 0));
+            }
+        }
+    }
+
+    #[test]
+    fn full_operation() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let expected_data = load_path("tests/data/content_controlled_document_expected.docx");
+
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Sidematter".into(), "Into a brave new world".into()),
+            ("WritingDate".into(), "12.12.2012".into()),
+            ("Author".into(), "Bruce Wayne".into()),
+            ("MainContent".into(), "This is rich coming from you.".into()),
+        ]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+
+        let mut outfile = tempfile().unwrap();
+        let _ = zip_dir(&mapped_data, &mut outfile);
+        let nreader = BufReader::new(outfile);
+        let result_data = list_zip_contents(nreader).unwrap();
+
+        for (e_k, e_v) in expected_data {
+            assert_eq!(
+                String::from_utf8_lossy(&e_v),
+                String::from_utf8_lossy(&result_data[&e_k])
+            );
+        }
+    }
+
+    #[test]
+    fn run_with_params() {
+        let input_data = load_path("tests/data/run_with_params.docx");
+        let expected_data = load_path("tests/data/run_with_params_expected.docx");
+        let mappings = HashMap::from([("RunField".into(), "Something new".into())]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        let mut outfile = tempfile().unwrap();
+        let _ = zip_dir(&mapped_data, &mut outfile);
+        let nreader = BufReader::new(outfile);
+        let result_data = list_zip_contents(nreader).unwrap();
+
+        for (e_k, e_v) in expected_data {
+            assert_eq!(
+                String::from_utf8_lossy(&e_v),
+                String::from_utf8_lossy(&result_data[&e_k])
+            );
+        }
+    }
+
+    #[test]
+    fn preserve_images() {
+        let input_data = load_path("tests/data/run_with_params_imgs.docx");
+        let mappings = HashMap::from([("RunField".into(), "Something new".into())]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+
+        let file = fs::File::create("test.docx").unwrap();
+        let mut writer = BufWriter::new(file);
+        let _ = zip_dir(&mapped_data, &mut writer);
+    }
+
+    #[test]
+    fn complex_replacement() {
+        let input_data = load_path("tests/data/run_with_params_imgs.docx");
+        let mappings = HashMap::from([(
+            "RunField".into(),
+            "<w:t>Something</w:t><w:cr/><w:t>new</w:t>".into(),
+        )]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+
+        let file = fs::File::create("test2.docx").unwrap();
+        let mut writer = BufWriter::new(file);
+        let _ = zip_dir(&mapped_data, &mut writer);
+    }
+
+    #[test]
+    fn repeat_replacement() {
+        let input_data = load_path("tests/data/TownLandRiver.docx");
+        let expected_data = load_path("tests/data/TownLandRiver_expected.docx");
+        let mappings = HashMap::from([]);
+        let data = r#"
+        {
+            "Entry": [
+            {
+                "Town": "Cottbus",
+                "Land": "Brandenburg",
+                "River": "Dahme"
+            },
+            {
+                "Town": "Aachen",
+                "Land": "NRW",
+                "River": "Wurm"
+            }
+            ]
+        }
+            "#;
+        let repeat_mappings: RepeatMapping = serde_json::from_str(data).unwrap();
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+
+        let mut outfile = tempfile().unwrap();
+        let _ = zip_dir(&mapped_data, &mut outfile);
+        let nreader = BufReader::new(outfile);
+        let result_data = list_zip_contents(nreader).unwrap();
+
+        for (e_k, e_v) in expected_data {
+            assert_eq!(
+                String::from_utf8_lossy(&e_v),
+                String::from_utf8_lossy(&result_data[&e_k])
+            );
+        }
+    }
+
+    #[test]
+    fn table_replacement() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let mappings = HashMap::from([(
+            "MainContent".into(),
+            "<w:tbl>
+<w:tblPr>
+<w:tblStyle w:val=\"TableGrid\"/>
+<w:tblW w:w=\"5000\" w:type=\"pct\"/>
+</w:tblPr>
+<w:tblGrid>
+<w:gridCol w:w=\"2880\"/>
+<w:gridCol w:w=\"2880\"/>
+<w:gridCol w:w=\"2880\"/>
+</w:tblGrid>
+<w:tr>
+<w:tc>
+<w:tcPr>
+<w:tcW w:w=\"2880\" w:type=\"dxa\"/>
+</w:tcPr>
+<w:p>
+<w:r>
+<w:t>AAA</w:t>
+</w:r>
+</w:p>
+</w:tc>
+<w:tc>
+<w:tcPr>
+<w:tcW w:w=\"2880\" w:type=\"dxa\"/>
+</w:tcPr>
+<w:p>
+<w:r>
+<w:t>BBB</w:t>
+</w:r>
+</w:p>
+</w:tc>
+<w:tc>
+<w:tcPr>
+<w:tcW w:w=\"2880\" w:type=\"dxa\"/>
+</w:tcPr>
+<w:p>
+<w:r>
+<w:t>CCC</w:t>
+</w:r>
+</w:p>
+</w:tc>
+</w:tr>
+</w:tbl>"
+                .into(),
+        )]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+
+        let file = fs::File::create("test3.docx").unwrap();
+        let mut writer = BufWriter::new(file);
+        let _ = zip_dir(&mapped_data, &mut writer);
+    }
+
+    #[test]
+    fn rename_tags_then_map() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let renames = HashMap::from([("Title".into(), "DocumentTitle".into())]);
+        let renamed_data = rename_tags(&input_data, &renames).unwrap();
+
+        let controlled_documents = get_content_controls(&renamed_data).unwrap();
+        let tags: HashSet<&str> = controlled_documents
+            .values()
+            .flat_map(|doc| doc.control_positions.iter().map(|c| c.get_tag()))
+            .collect();
+        assert!(tags.contains("DocumentTitle"));
+        assert!(!tags.contains("Title"));
+        assert!(tags.contains("Author"));
+
+        let mappings = HashMap::from([("DocumentTitle".into(), "Brave New World".into())]);
+        let repeat_mappings = HashMap::from([]);
+        let mapped_data = map_content_controls(
+            &renamed_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        let document = String::from_utf8_lossy(&mapped_data["word/document.xml"]);
+        assert!(document.contains("<w:tag w:val=\"DocumentTitle\"/>"));
+        assert!(document.contains("Brave New World"));
+        assert!(!document.contains("Hello World"));
+    }
+
+    #[test]
+    fn replace_list_items() {
+        let input_data = document_with_body(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Account"/><w:dropDownList><w:listItem w:displayText="Choose an item." w:value=""/><w:listItem w:displayText="Old" w:value="old"/></w:dropDownList></w:sdtPr><w:sdtContent><w:r><w:t>Choose an item.</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        );
+        let items = vec![
+            ("DE01 1234".to_string(), "1234".to_string()),
+            ("DE02 5678".to_string(), "5678".to_string()),
+        ];
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        assert_eq!(
+            controlled_documents["word/document.xml"].control_positions[0].get_type(),
+            &ContentControlType::DropdownList
+        );
+        let updated_data = set_list_items(&input_data, "Account", &items).unwrap();
+        let document = String::from_utf8_lossy(&updated_data["word/document.xml"]);
+        assert!(document.contains(
+            r#"<w:dropDownList><w:listItem w:displayText="DE01 1234" w:value="1234"/><w:listItem w:displayText="DE02 5678" w:value="5678"/></w:dropDownList>"#
+        ));
+        assert!(!document.contains("w:value=\"old\""));
+        assert!(document.contains("<w:t>Choose an item.</w:t>"));
+    }
+
+    #[test]
+    fn control_levels() {
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Block"/></w:sdtPr><w:sdtContent><w:p><w:r><w:t>Intro </w:t></w:r>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Inline"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"</w:p></w:sdtContent></w:sdt><w:tbl>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Row"/></w:sdtPr><w:sdtContent><w:tr>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Cell"/></w:sdtPr><w:sdtContent><w:tc><w:p/></w:tc></w:sdtContent></w:sdt>"#,
+            r#"</w:tr></w:sdtContent></w:sdt></w:tbl>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let levels: HashMap<&str, ControlLevel> = controlled_documents["word/document.xml"]
+            .control_positions
+            .iter()
+            .map(|c| (c.get_tag(), c.get_level()))
+            .collect();
+        assert_eq!(
+            levels,
+            HashMap::from([
+                ("Block", ControlLevel::Block),
+                ("Inline", ControlLevel::Inline),
+                ("Row", ControlLevel::Row),
+                ("Cell", ControlLevel::Cell),
+            ])
+        );
+    }
+
+    #[test]
+    fn keep_original_ignores_placeholder_values() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let repeat_mappings = HashMap::from([]);
+
+        // a first run leaves the placeholder in controls without data
+        let first_mappings = HashMap::from([("Author".into(), "Bruce Wayne".into())]);
+        let first_run = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &first_mappings,
+            &repeat_mappings,
+        );
+        let first_document = String::from_utf8_lossy(&first_run["word/document.xml"]);
+        assert!(first_document.contains(MISSING_STR));
+
+        // re-filling the filled document with complete data replaces the placeholder
+        let refill_controls = get_content_controls(&first_run).unwrap();
+        let full_mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Sidematter".into(), "Into a brave new world".into()),
+            ("WritingDate".into(), "12.12.2012".into()),
+            ("Author".into(), "Bruce Wayne".into()),
+            ("MainContent".into(), "This is rich coming from you.".into()),
+        ]);
+        let options = MapOptions {
+            missing: MissingBehavior::KeepOriginal,
+            ..Default::default()
+        };
+        let refilled = map_content_controls_with(
+            &first_run,
+            &refill_controls,
+            &full_mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let refilled_document = String::from_utf8_lossy(&refilled["word/document.xml"]);
+        assert!(!refilled_document.contains(MISSING_STR));
+        assert!(refilled_document.contains("Brave New World"));
+
+        // placeholder values carried over from a filled document keep the template content
+        let carried_mappings = HashMap::from([
+            ("Title".into(), MISSING_STR.into()),
+            ("Author".into(), "Bruce Wayne".into()),
+        ]);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &carried_mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8_lossy(&mapped_data["word/document.xml"]);
+        assert!(!document.contains(MISSING_STR));
+        assert!(document.contains("<w:t>Hello World</w:t>"));
+    }
+
+    #[test]
+    fn fill_comment_controls() {
+        let input_data = load_path("tests/data/comment_control.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let comment_controls = &controlled_documents["word/comments.xml"].control_positions;
+        assert_eq!(comment_controls.len(), 1);
+        assert_eq!(comment_controls[0].get_tag(), "Reviewer");
+        assert_eq!(comment_controls[0].get_level(), ControlLevel::Inline);
+
+        let mappings = HashMap::from([
+            ("RunField".into(), "Something new".into()),
+            ("Reviewer".into(), "Jane Doe".into()),
+        ]);
+        let repeat_mappings = HashMap::from([]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        let comments = String::from_utf8_lossy(&mapped_data["word/comments.xml"]);
+        assert!(comments.contains("<w:t>Jane Doe</w:t>"));
+        assert!(!comments.contains("<w:t>Name</w:t>"));
+        let document = String::from_utf8_lossy(&mapped_data["word/document.xml"]);
+        assert!(document.contains("Something new"));
+    }
+
+    #[test]
+    fn remove_empty_paragraphs() {
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Summary"/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:p><w:r><w:t>First</w:t></w:r></w:p><w:p/><w:p><w:r><w:t>Third</w:t></w:r></w:p>"#,
+            r#"</w:sdtContent></w:sdt>"#,
+        ));
+        let mappings = HashMap::from([(
+            "Summary".into(),
+            concat!(
+                "<w:p><w:r><w:t>Filled</w:t></w:r></w:p><w:p></w:p><w:p/>",
+                "<w:p><w:pPr><w:pageBreakBefore/></w:pPr></w:p>"
+            )
+            .into(),
+        )]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let options = MapOptions {
+            remove_empty_paragraphs: true,
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8_lossy(&mapped_data["word/document.xml"]);
+        assert!(document.contains(concat!(
+            "<w:sdtContent><w:p><w:r><w:t>Filled</w:t></w:r></w:p>",
+            "<w:p><w:pPr><w:pageBreakBefore/></w:pPr></w:p></w:sdtContent>"
+        )));
+
+        let mappings = HashMap::from([("Summary".into(), "".into())]);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8_lossy(&mapped_data["word/document.xml"]);
+        assert_eq!(document.matches("<w:p>").count(), 1);
+    }
+
+    #[test]
+    fn annotate_events() {
+        let part = r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Outer"/></w:sdtPr><w:sdtContent><w:r><w:t>A</w:t></w:r><w:sdt><w:sdtPr><w:tag w:val="Inner"/></w:sdtPr><w:sdtContent><w:r/></w:sdtContent></w:sdt></w:sdtContent></w:sdt></w:p>"#;
+        let annotated = annotated_events(part.as_bytes()).unwrap();
+        let markers: Vec<Option<ControlMarker>> =
+            annotated.into_iter().map(|(_, marker)| marker).collect();
+        let outer = |m: fn(String) -> ControlMarker| Some(m("Outer".into()));
+        let inner = |m: fn(String) -> ControlMarker| Some(m("Inner".into()));
+        assert_eq!(
+            markers,
+            vec![
+                None,
+                None,
+                None,
+                None,
+                None,
+                outer(ControlMarker::ContentStart),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::Inside),
+                inner(ControlMarker::ContentStart),
+                inner(ControlMarker::Inside),
+                inner(ControlMarker::ContentEnd),
+                outer(ControlMarker::Inside),
+                outer(ControlMarker::ContentEnd),
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_parts() {
+        let mut input_data = load_path("tests/data/content_controlled_document.docx");
+        let document = input_data["word/document.xml"].clone();
+        input_data.insert(
+            "word/document.xml".into(),
+            document[..document.len() / 2].to_vec(),
+        );
+        match get_content_controls(&input_data) {
+            Err(DocxError::Xml { part, position, .. }) => {
+                assert_eq!(part, "word/document.xml");
+                assert!(position > 0);
+            }
+            other => panic!(
+                "expected an XML error, got {:?}",
+                other.map(|docs| docs.len())
+            ),
+        }
+        // the helpers built on the parsed controls fail the same way instead of panicking
+        let truncated = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Name"/></w:sdtPr><w:sdtContent>"#,
+            "<w:p></w:sdtContent></w:sdt>"
+        ));
+        let is_xml_error = |e: DocxError| matches!(e, DocxError::Xml { .. });
+        assert!(inventory(&truncated).is_err_and(is_xml_error));
+        assert!(to_template(&truncated, None).is_err_and(is_xml_error));
+        assert!(clear_control_content(&truncated).is_err_and(is_xml_error));
+        assert!(map_by_index(&truncated, &[]).is_err_and(is_xml_error));
+        assert!(set_list_items(&truncated, "Country", &[]).is_err_and(is_xml_error));
+        assert!(insert_after_control(&truncated, "Name", "<w:r/>").is_err_and(is_xml_error));
+        assert!(extract_control_content(&truncated, "Name").is_err_and(is_xml_error));
+        assert!(merge_adjacent_runs_in_controls(&truncated).is_err_and(is_xml_error));
+        assert!(repeating_section_schema(&truncated).is_err_and(is_xml_error));
+        assert!(max_control_depth(&truncated).is_err_and(is_xml_error));
+        assert!(control_structure_hash(&truncated).is_err_and(is_xml_error));
+        assert!(filled_control_properties(&truncated).is_err_and(is_xml_error));
+        assert!(validate_template(&truncated, &TemplateData::default()).is_err_and(is_xml_error));
+
+        let mut input_data = load_path("tests/data/content_controlled_document.docx");
+        input_data.insert("word/header1.xml".into(), b"<w:sdt>\xff</w:sdt>".to_vec());
+        assert!(matches!(
+            get_content_controls(&input_data),
+            Err(DocxError::Encoding { part, .. }) if part == "word/header1.xml"
+        ));
+        // parts without controls are not parsed
+        input_data.insert("word/header1.xml".into(), b"<w:hdr>\xff".to_vec());
+        assert!(get_content_controls(&input_data).is_ok());
+    }
+
+    #[test]
+    fn annotate_malformed_events() {
+        let result = annotated_events(b"<w:p><w:r></w:p>");
+        assert!(matches!(result, Err(DocxError::Xml { .. })));
+    }
+
+    #[test]
+    fn template_inventory() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let report = inventory(&input_data).unwrap();
+        assert_eq!(report.parts.len(), input_data.len());
+        let header_tags: Vec<&str> = report.controls["word/header1.xml"]
+            .iter()
+            .map(|c| c.tag.as_str())
+            .collect();
+        assert_eq!(header_tags, vec!["Author", "Title"]);
+        // the text box content is repeated in the drawing fallback
+        assert_eq!(
+            report.duplicate_tags,
+            BTreeMap::from([
+                ("Author".into(), 2),
+                ("Sidematter".into(), 2),
+                ("Title".into(), 2),
+                ("WritingDate".into(), 3),
+            ])
+        );
+        assert!(report.untagged_controls.is_empty());
+        assert!(report.unsupported_controls.is_empty());
+        assert!(report.diagram_controls.is_empty());
+
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:alias w:val="Logo"/><w:picture/></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Choice"/><w:comboBox><w:listItem w:displayText="Yes" w:value="1"/></w:comboBox></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+        ));
+        let report = inventory(&input_data).unwrap();
+        assert_eq!(report.untagged_controls.len(), 1);
+        assert_eq!(report.untagged_controls[0].control.alias, "Logo");
+        assert_eq!(report.unsupported_controls.len(), 1);
+        assert_eq!(
+            report.unsupported_controls[0]
+                .control
+                .type_element
+                .as_deref(),
+            Some("w:picture")
+        );
+        let choice = &report.controls["word/document.xml"][1];
+        assert_eq!(choice.r#type, ContentControlType::ComboBox);
+        assert_eq!(choice.list_items, vec![("Yes".into(), "1".into())]);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["unsupported_controls"][0]["part"], "word/document.xml");
+        assert_eq!(json["unsupported_controls"][0]["type"], "Unsupported");
+    }
+
+    #[test]
+    fn raw_content_replacement() {
+        let input_data = load_path("tests/data/run_with_params.docx");
+        let fragment =
+            r#"<w:sdtContent><w:r><w:rPr><w:b/></w:rPr><w:t>Raw</w:t></w:r></w:sdtContent>"#;
+        let mappings = HashMap::from([("RunField".into(), fragment.into())]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let options = MapOptions {
+            raw_content_replace: true,
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8_lossy(&mapped_data["word/document.xml"]);
+        assert_eq!(document.matches("<w:sdtContent>").count(), 1);
+        assert!(document.contains(&format!("</w:sdtPr>{}</w:sdt>", fragment)));
+
+        // without the option the fragment is filled into the existing content
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        let document = String::from_utf8_lossy(&mapped_data["word/document.xml"]);
+        assert_eq!(document.matches("<w:sdtContent>").count(), 2);
+
+        // values that are not markup are filled as text
+        for value in ["a < b", "<w:sdtContent>& more"] {
+            let mappings = HashMap::from([("RunField".into(), value.into())]);
+            let mapped_data = map_content_controls_with(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &repeat_mappings,
+                &options,
+            )
+            .unwrap();
+            let controlled = get_content_controls(&mapped_data).unwrap();
+            let values = get_control_values(&controlled["word/document.xml"]);
+            assert_eq!(values["RunField"], value);
+        }
+    }
+
+    #[test]
+    fn structure_hash() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let template_hash = control_structure_hash(&input_data).unwrap();
+        assert_eq!(template_hash, control_structure_hash(&input_data).unwrap());
+
+        let mappings = HashMap::from([("Title".into(), "Brave New World".into())]);
+        let repeat_mappings = HashMap::from([]);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        assert_eq!(template_hash, control_structure_hash(&mapped_data).unwrap());
+
+        let renames = HashMap::from([("Title".into(), "DocumentTitle".into())]);
+        let renamed_data = rename_tags(&input_data, &renames).unwrap();
+        assert_ne!(
+            template_hash,
+            control_structure_hash(&renamed_data).unwrap()
+        );
+    }
+
+    #[test]
+    fn repeat_page_breaks() {
+        let input_data = load_path("tests/data/repeat_page_breaks.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([]);
+        let repeat_mappings = HashMap::from([
+            (
+                "People".into(),
+                ["Ada", "Grace", "Barbara"]
+                    .iter()
+                    .map(|name| HashMap::from([("Name".into(), name.to_string())]))
+                    .collect(),
+            ),
+            (
+                "Places".into(),
+                ["Paris", "Rome"]
+                    .iter()
+                    .map(|city| HashMap::from([("City".into(), city.to_string())]))
+                    .collect(),
+            ),
+        ]);
+        let options = MapOptions {
+            sections: HashMap::from([
+                (
+                    "People".into(),
+                    SectionOptions {
+                        page_break_between: true,
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "Places".into(),
+                    SectionOptions {
+                        page_break_between: true,
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        let page_break = r#"<w:br w:type="page"/>"#;
+        assert_eq!(document.matches(page_break).count(), 3);
+        assert_eq!(
+            document
+                .matches(&format!("<w:p><w:r>{}</w:r></w:p>", page_break))
+                .count(),
+            2
+        );
+        let first_break = document.find(page_break).unwrap();
+        assert!(document.find("Ada").unwrap() < first_break);
+        let inline_break = document.rfind(page_break).unwrap();
+        assert!(document.find("Paris").unwrap() < inline_break);
+        assert!(inline_break < document.find("Rome").unwrap());
+
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains(page_break));
+    }
+
+    #[test]
+    fn env_mapping() {
+        let vars = [
+            ("CC_Title", "Hello"),
+            ("CC_Author", "Aldous Huxley"),
+            ("CC_", "no tag"),
+            ("PATH", "/usr/bin"),
+            ("XCC_Title", "wrong prefix"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let mapping = mapping_from_env("CC_", vars);
+        assert_eq!(
+            mapping,
+            HashMap::from([
+                ("Title".to_string(), "Hello".to_string()),
+                ("Author".to_string(), "Aldous Huxley".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn multi_line_text() {
+        let input_data = load_path("tests/data/multi_line_text.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let controls = &controlled_documents["word/document.xml"].control_positions;
+        assert!(controls[0].is_multi_line());
+        assert!(!controls[1].is_multi_line());
+
+        let mappings = HashMap::from([
+            ("Address".into(), "1 Main Street\nSpringfield".into()),
+            ("Name".into(), "Homer\r\nSimpson".into()),
+        ]);
+        let repeat_mappings = HashMap::from([]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(
+            r#"<w:r><w:rPr><w:b/></w:rPr><w:t xml:space="preserve">1 Main Street</w:t><w:br/><w:t xml:space="preserve">Springfield</w:t></w:r>"#
+        ));
+        assert!(document.contains("<w:t>Homer Simpson</w:t>"));
+    }
+
+    #[test]
+    fn max_lengths() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let options = MapOptions {
+            max_lengths: HashMap::from([("Title".into(), 10)]),
+            ..Default::default()
+        };
+        let repeat_mappings = HashMap::from([]);
+        let mappings = HashMap::from([("Title".into(), "Brave New".into())]);
+        map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+
+        let mappings = HashMap::from([("Title".into(), "Brave New World".into())]);
+        let result = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        );
+        match result {
+            Err(DocxError::ValueTooLong { tag, len, max }) => {
+                assert_eq!((tag.as_str(), len, max), ("Title", 15, 10));
+            }
+            _ => panic!("expected a value too long error"),
+        }
+    }
+
+    #[test]
+    fn rtl_controls() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Title".into(), "عالم جديد شجاع".into()),
+            ("Author".into(), "Aldous Huxley".into()),
+        ]);
+        let repeat_mappings = HashMap::from([]);
+        let options = MapOptions {
+            rtl: HashMap::from([("Title".into(), true)]),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        let title = document.find("عالم جديد شجاع").unwrap();
+        let run = document[..title].rfind("<w:r>").unwrap();
+        assert!(document[run..title].contains("<w:rtl/>"));
+        let author = document.find("Aldous Huxley").unwrap();
+        let run = document[..author].rfind("<w:r>").unwrap();
+        assert!(!document[run..author].contains("<w:rtl/>"));
+    }
+
+    #[test]
+    fn rtl_properties_order() {
+        let mut reader = Reader::from_str(r#"<w:rPr><w:b/><w:lang w:val="ar-SA"/></w:rPr>"#);
+        let mut events = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Eof => break,
+                e => events.push(e.into_owned()),
+            }
+        }
+        let written = |properties: Vec<Event>| {
+            let mut writer = Writer::new(Cursor::new(Vec::new()));
+            for ev in properties {
+                writer.write_event(ev).unwrap();
+            }
+            String::from_utf8(writer.into_inner().into_inner()).unwrap()
+        };
+        assert_eq!(
+            written(with_property(
+                &events,
+                "w:rPr",
+                "w:rtl",
+                run_property_neighbours("w:rtl").1
+            )),
+            r#"<w:rPr><w:b/><w:rtl/><w:lang w:val="ar-SA"/></w:rPr>"#
+        );
+        assert_eq!(
+            written(with_property(&[], "w:pPr", "w:bidi", AFTER_BIDI)),
+            "<w:pPr><w:bidi/></w:pPr>"
+        );
+    }
+
+    #[test]
+    fn typed_null_preserves() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Title".into(), MappingValue::Null),
+            (
+                "Author".into(),
+                MappingValue::String("Aldous Huxley".into()),
+            ),
+            ("WritingDate".into(), serde_json::json!(1932)),
+        ]);
+        let repeat_mappings = HashMap::from([]);
+        let mapped_data = map_content_controls_typed(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &MapOptions::default(),
+        )
+        .unwrap();
+        let original = get_content_controls(&input_data).unwrap();
+        let mapped = get_content_controls(&mapped_data).unwrap();
+        let control_xml = |doc: &DocumentData, tag: &str| {
+            let control = doc.control_positions.iter().find(|c| c.tag == tag).unwrap();
+            let mut writer = Writer::new(Cursor::new(Vec::new()));
+            for ev in &doc.events[control.begin as usize..=control.end as usize] {
+                writer.write_event(ev).unwrap();
+            }
+            String::from_utf8(writer.into_inner().into_inner()).unwrap()
+        };
+        let original_doc = &original["word/document.xml"];
+        let mapped_doc = &mapped["word/document.xml"];
+        assert_eq!(
+            control_xml(original_doc, "Title"),
+            control_xml(mapped_doc, "Title")
+        );
+        assert!(control_xml(mapped_doc, "Author").contains("Aldous Huxley"));
+        assert!(control_xml(mapped_doc, "WritingDate").contains("1932"));
+        assert!(control_xml(mapped_doc, "Sidematter").contains(MISSING_STR));
+    }
+
+    #[test]
+    fn list_media_parts() {
+        let mut input_data = load_path("tests/data/run_with_params_imgs.docx");
+        input_data.insert("word/media/orphan.png".into(), vec![0; 16]);
+        assert_eq!(
+            media_parts(&input_data),
+            vec![
+                MediaPart {
+                    name: "word/media/image1.jpeg".into(),
+                    bytes_len: 482463,
+                    referenced_by: vec!["word/document.xml".into()],
+                },
+                MediaPart {
+                    name: "word/media/orphan.png".into(),
+                    bytes_len: 16,
+                    referenced_by: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn relationship_targets() {
+        assert_eq!(
+            relationships_source("word/_rels/document.xml.rels").as_deref(),
+            Some("word/document.xml")
+        );
+        assert_eq!(relationships_source("_rels/.rels").as_deref(), Some(""));
+        assert_eq!(relationships_source("word/document.xml"), None);
+        assert_eq!(
+            resolve_target("word/glossary/document.xml", "../media/image1.png"),
+            "word/media/image1.png"
+        );
+        assert_eq!(
+            resolve_target("word/document.xml", "/customXml/item1.xml"),
+            "customXml/item1.xml"
+        );
+        assert_eq!(resolve_target("", "word/document.xml"), "word/document.xml");
+    }
+
+    #[test]
+    fn skip_unchanged_controls() {
+        let input_data = load_path("tests/data/run_with_params.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let controls = &controlled_documents["word/document.xml"].control_positions;
+        let events = &controlled_documents["word/document.xml"].events;
+        let mappings: Mapping = controls
+            .iter()
+            .map(|c| (c.tag.clone(), control_text(c, events)))
+            .collect();
+        let repeat_mappings = HashMap::from([]);
+        let options = MapOptions {
+            skip_unchanged: true,
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let original = String::from_utf8_lossy(&input_data["word/document.xml"]).to_string();
+        let filled = String::from_utf8_lossy(&mapped_data["word/document.xml"]).to_string();
+        let refilled = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        assert_ne!(
+            String::from_utf8_lossy(&refilled["word/document.xml"]),
+            original
+        );
+        assert_eq!(filled, original);
+    }
+
+    #[test]
+    fn repeating_schema() {
+        let input_data = load_path("tests/data/repeat_page_breaks.docx");
+        let schema = repeating_section_schema(&input_data).unwrap();
+        assert_eq!(
+            schema,
+            HashMap::from([
+                (
+                    "People".to_string(),
+                    SectionSchema {
+                        fields: vec!["Name".into()],
+                        ..Default::default()
+                    }
+                ),
+                (
+                    "Places".to_string(),
+                    SectionSchema {
+                        fields: vec!["City".into()],
+                        ..Default::default()
+                    }
+                ),
+            ])
+        );
+
+        let sdt = |tag: &str, pr: &str, content: &str| {
+            format!(
+                r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/>{}</w:sdtPr><w:sdtContent>{}</w:sdtContent></w:sdt>"#,
+                tag, pr, content
+            )
+        };
+        let text = |tag: &str| sdt(tag, "<w:text/>", &format!("<w:r><w:t>{}</w:t></w:r>", tag));
+        let line = |content: String| format!("<w:p>{}</w:p>", content);
+        let orders = sdt(
+            "Orders",
+            "<w15:repeatingSection/>",
+            &sdt(
+                "Order",
+                "<w15:repeatingSectionItem/>",
+                &format!(
+                    "{}{}",
+                    line(text("Number")),
+                    sdt(
+                        "Lines",
+                        "<w15:repeatingSection/>",
+                        &sdt(
+                            "Line",
+                            "<w15:repeatingSectionItem/>",
+                            &line(text("Product") + &text("Quantity")),
+                        ),
+                    )
+                ),
+            ),
+        );
+        let input_data = document_with_body(&orders);
+        assert_eq!(
+            repeating_section_schema(&input_data).unwrap(),
+            HashMap::from([(
+                "Orders".to_string(),
+                SectionSchema {
+                    fields: vec!["Number".into()],
+                    sections: HashMap::from([(
+                        "Lines".to_string(),
+                        SectionSchema {
+                            fields: vec!["Product".into(), "Quantity".into()],
+                            ..Default::default()
+                        }
+                    )]),
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn namespace_prefix() {
+        let tags = |path: &str| -> Vec<String> {
+            let input_data = load_path(path);
+            let controlled_documents = get_content_controls(&input_data).unwrap();
+            controlled_documents["word/document.xml"]
+                .control_positions
+                .iter()
+                .map(|c| c.tag.clone())
+                .collect()
+        };
+        let expected = tags("tests/data/content_controlled_document.docx");
+        assert!(expected.iter().all(|tag| !tag.is_empty()));
+        assert_eq!(tags("tests/data/namespace_prefix.docx"), expected);
+    }
+
+    #[test]
+    fn namespace_prefix_rewrite() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt xmlns:wx="http://schemas.openxmlformats.org/wordprocessingml/2006/main">"#,
+            r#"<w:sdtPr><wx:lock wx:val="sdtContentLocked"/><w:tag wx:val="Name"/><w:text/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let renamed = rename_tag(&input_data, "Name", "Client").unwrap();
+        let document = String::from_utf8(renamed["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(r#"<w:tag wx:val="Client"/>"#));
+
+        let unlocked = strip_locks(&input_data).unwrap();
+        let document = String::from_utf8(unlocked["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains("lock"));
+
+        let controlled = get_content_controls(&input_data).unwrap();
+        let mapped = map_content_controls_with(
+            &input_data,
+            &controlled,
+            &HashMap::from([("Name".into(), "Jane".into())]),
+            &HashMap::new(),
+            &MapOptions {
+                strip_locks: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains("lock"));
+        assert!(document.contains("<w:t>Jane</w:t>"));
+    }
+
+    #[test]
+    fn insert_around_controls() {
+        let control = r#"<w:sdt><w:sdtPr><w:tag w:val="Name"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt>"#;
+        let input_data = document_with_body(&format!("<w:p>{}</w:p>", control));
+        let marker = r#"<w:r><w:t>*</w:t></w:r>"#;
+
+        let after = insert_after_control(&input_data, "Name", marker).unwrap();
+        let document = String::from_utf8(after["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(&format!("<w:p>{}{}</w:p>", control, marker)));
+
+        let before = insert_before_control(&input_data, "Name", marker).unwrap();
+        let document = String::from_utf8(before["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(&format!("<w:p>{}{}</w:p>", marker, control)));
+
+        let unknown = insert_after_control(&input_data, "Unknown", marker).unwrap();
+        assert_eq!(unknown, input_data);
+
+        for fragment in ["</w:p>", r#"<w:p a="1></w:p>"#, "<w:p><w:r>", "a & b", "*"] {
+            assert!(matches!(
+                insert_before_control(&input_data, "Name", fragment),
+                Err(DocxError::InvalidValue { tag, .. }) if tag == "Name"
+            ));
+            assert!(insert_after_control(&input_data, "Name", fragment).is_err());
+        }
+    }
+
+    #[test]
+    fn control_summary() {
+        let input_data = load_path("tests/data/repeat_page_breaks.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let summary = controlled_documents["word/document.xml"].summary();
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!([
+                {"tag": "People", "alias": "People", "control_type": "RepeatingSection", "level": "Block", "child_tags": ["Item"]},
+                {"tag": "Item", "alias": "Item", "control_type": "RepeatingSectionItem", "level": "Block", "child_tags": ["Name"]},
+                {"tag": "Name", "alias": "Name", "control_type": "Text", "level": "Inline", "child_tags": []},
+                {"tag": "Places", "alias": "Places", "control_type": "RepeatingSection", "level": "Inline", "child_tags": ["Place"]},
+                {"tag": "Place", "alias": "Place", "control_type": "RepeatingSectionItem", "level": "Inline", "child_tags": ["City"]},
+                {"tag": "City", "alias": "City", "control_type": "Text", "level": "Inline", "child_tags": []},
+            ])
+        );
+    }
+
+    #[test]
+    fn text_run_params() {
+        let input_data = load_path("tests/data/leading_empty_run.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Name".into(), "Ada".into())]);
+        let repeat_mappings = HashMap::from([]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(
+            r#"<w:sdtContent><w:r><w:rPr><w:b/><w:color w:val="FF0000"/></w:rPr><w:t>Ada</w:t></w:r></w:sdtContent>"#
+        ));
+    }
+
+    #[test]
+    fn unzipped_roundtrip() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let dir = tempfile::tempdir().unwrap();
+        write_unzipped(&input_data, dir.path()).unwrap();
+        assert!(dir.path().join("word").join("document.xml").is_file());
+        assert_eq!(read_unzipped(dir.path()).unwrap(), input_data);
+
+        let escaping = HashMap::from([("../outside.xml".to_string(), Vec::new())]);
+        assert!(matches!(
+            write_unzipped(&escaping, dir.path()),
+            Err(DocxError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn whitespace_controls() {
+        let input_data = load_path("tests/data/whitespace_controls.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let repeat_mappings = HashMap::from([]);
+        let fill = |spacer: &str, gap: &str| {
+            let mappings =
+                HashMap::from([("Spacer".into(), spacer.into()), ("Gap".into(), gap.into())]);
+            let mapped_data = map_content_controls(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &repeat_mappings,
+            );
+            String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap()
+        };
+        let document = fill("Ada", "Lovelace");
+        assert!(document.contains("<w:sdtContent><w:r><w:t>Ada</w:t></w:r></w:sdtContent>"));
+        assert!(document.contains("<w:sdtContent><w:r><w:t>Lovelace</w:t></w:r></w:sdtContent>"));
+
+        let document = fill("  ", " and ");
+        assert!(document.contains(
+            r#"<w:sdtContent><w:r><w:t xml:space="preserve">  </w:t></w:r></w:sdtContent>"#
+        ));
+        assert!(document.contains(
+            r#"<w:sdtContent><w:r><w:t xml:space="preserve"> and </w:t></w:r></w:sdtContent>"#
+        ));
+    }
+
+    #[test]
+    fn control_depth() {
+        let input_data = load_path("tests/data/repeat_page_breaks.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let depths: Vec<(&str, usize)> = controlled_documents["word/document.xml"]
+            .control_positions
+            .iter()
+            .map(|c| (c.get_tag(), c.get_depth()))
+            .collect();
+        assert_eq!(
+            depths,
+            vec![
+                ("People", 1),
+                ("Item", 2),
+                ("Name", 3),
+                ("Places", 1),
+                ("Place", 2),
+                ("City", 3),
+            ]
+        );
+        assert_eq!(max_control_depth(&input_data).unwrap(), 3);
+        assert_eq!(inventory(&input_data).unwrap().max_depth, 3);
+        assert_eq!(max_control_depth(&HashMap::new()).unwrap(), 0);
+    }
+
+    #[test]
+    fn typed_coalesce() {
+        let mappings: TypedMapping = serde_json::from_value(serde_json::json!({
+            "PreferredName": "",
+            "LegalName": "Augusta Ada King",
+            "Author": {"coalesce": ["PreferredName", "LegalName"]},
+            "First": {"coalesce": ["Second"]},
+            "Second": {"coalesce": ["First"]},
+        }))
+        .unwrap();
+        let resolve = |tag: &str| match resolve_typed(&mappings, tag, &mut Vec::new(), None) {
+            Some(TypedValue::Text(text)) => Some(text),
+            _ => None,
+        };
+        assert_eq!(resolve("Author").as_deref(), Some("Augusta Ada King"));
+        assert_eq!(resolve("First"), None);
+
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls_typed(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+            &MapOptions::default(),
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("Augusta Ada King"));
+    }
+
+    #[test]
+    fn simple_marker_replace() {
+        let body = concat!(
+            r#"<w:p><w:r><w:t xml:space="preserve">Dear {{</w:t></w:r><w:r><w:rPr><w:b/></w:rPr><w:t>Na</w:t></w:r>"#,
+            r#"<w:r><w:t>me}}, see {{Unknown}} &amp; {{ Date }}</w:t></w:r></w:p>"#,
+            r#"<w:p><w:r><w:t>{{Name}}</w:t></w:r></w:p>"#,
+        );
+        let input_data = document_with_body(body);
+        let mappings = HashMap::from([
+            ("Name".into(), "Ada & co".into()),
+            ("Date".into(), "today".into()),
+        ]);
+        let replaced = simple_replace(&input_data, &mappings).unwrap();
+        let document = String::from_utf8(replaced["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:p><w:r><w:t xml:space="preserve">Dear Ada &amp; co</w:t></w:r><w:r><w:rPr><w:b/></w:rPr><w:t></w:t></w:r>"#,
+            r#"<w:r><w:t>, see {{Unknown}} &amp; today</w:t></w:r></w:p>"#,
+            r#"<w:p><w:r><w:t>Ada &amp; co</w:t></w:r></w:p>"#,
+        )));
+
+        let untouched = simple_replace(&input_data, &HashMap::new()).unwrap();
+        assert_eq!(untouched, input_data);
+    }
+
+    #[test]
+    fn read_filled_properties() {
+        let input_data = load_path("tests/data/run_with_params.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("RunField".into(), "filled".into())]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+        );
+        assert_eq!(
+            filled_control_properties(&mapped_data).unwrap()["RunField"],
+            ControlProps {
+                italic: true,
+                color: Some("FF0000".into()),
+                ..Default::default()
+            }
+        );
+
+        let body = concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Heading"/></w:sdtPr><w:sdtContent><w:p><w:pPr><w:pStyle w:val="Heading1"/>"#,
+            r#"<w:jc w:val="center"/><w:rPr><w:i/></w:rPr></w:pPr><w:r><w:rPr><w:rStyle w:val="Strong"/><w:b w:val="0"/>"#,
+            r#"<w:u w:val="single"/><w:sz w:val="28"/></w:rPr><w:t>Heading</w:t></w:r></w:p></w:sdtContent></w:sdt>"#,
+        );
+        let input_data = document_with_body(body);
+        assert_eq!(
+            filled_control_properties(&input_data).unwrap()["Heading"],
+            ControlProps {
+                underline: Some("single".into()),
+                size: Some(28),
+                run_style: Some("Strong".into()),
+                paragraph_style: Some("Heading1".into()),
+                justification: Some("center".into()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn validate_template_pack() {
+        let letter = load_path("tests/data/content_controlled_document.docx");
+        let people = load_path("tests/data/repeat_page_breaks.docx");
+        let data = TemplateData {
+            mappings: HashMap::from([
+                ("Title".into(), "Brave New World".into()),
+                ("Author".into(), "Aldous Huxley".into()),
+                ("Titel".into(), "typo".into()),
+            ]),
+            repeat_mappings: HashMap::from([(
+                "People".into(),
+                vec![HashMap::from([
+                    ("Name".into(), "Ada".into()),
+                    ("Age".into(), "36".into()),
+                ])],
+            )]),
+        };
+        let letter_tags = validate_template(&letter, &data).unwrap().unmapped_tags;
+        assert!(letter_tags.contains(&"WritingDate".to_string()));
+        assert!(!letter_tags.contains(&"Title".to_string()));
+
+        let report = validate_against_templates(&[letter.clone(), people, letter], &data).unwrap();
+        let mut expected_unmapped: Vec<String> = letter_tags;
+        expected_unmapped.push("Places".into());
+        expected_unmapped.sort();
+        assert_eq!(
+            report,
+            ValidationReport {
+                unmapped_tags: expected_unmapped,
+                unused_keys: vec!["People.Age".into(), "Titel".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn control_metadata() {
+        let body = concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:alias w:val="Customer name"/><w:tag w:val="Name"/><w:id w:val="-1234"/>"#,
+            r#"<w:lock w:val="sdtLocked"/><w15:color w:val="FF0000"/><w:text/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        );
+        let input_data = document_with_body(body);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let control = &controlled_documents["word/document.xml"].control_positions[0];
+        assert_eq!(
+            control.get_metadata(),
+            &HashMap::from([
+                ("w:alias".to_string(), "Customer name".to_string()),
+                ("w:tag".to_string(), "Name".to_string()),
+                ("w:id".to_string(), "-1234".to_string()),
+                ("w:lock".to_string(), "sdtLocked".to_string()),
+                ("w15:color".to_string(), "FF0000".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn streaming_process() {
+        let path = "tests/data/run_with_params_imgs.docx";
+        let input_data = load_path(path);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("RunField".into(), "streamed".into())]);
+        let repeat_mappings = HashMap::from([]);
+        let expected = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+        );
+
+        let mut output = Cursor::new(Vec::new());
+        process_docx(
+            BufReader::new(fs::File::open(path).unwrap()),
+            &mut output,
+            &mappings,
+            &repeat_mappings,
+            &MapOptions::default(),
+        )
+        .unwrap();
+        output.set_position(0);
+        assert_eq!(list_zip_contents(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn repeat_section_header() {
+        let input_data = load_path("tests/data/repeat_header.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([]);
+        let options = MapOptions {
+            sections: HashMap::from([(
+                "People".into(),
+                SectionOptions {
+                    header_item_tag: Some("PeopleHeader".into()),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let fill = |people: Vec<Mapping>| {
+            let repeat_mappings = HashMap::from([("People".into(), people)]);
+            let mapped_data = map_content_controls_with(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &repeat_mappings,
+                &options,
+            )
+            .unwrap();
+            String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap()
+        };
+        let person = |name: &str, role: &str| {
+            HashMap::from([
+                ("Name".to_string(), name.to_string()),
+                ("Role".to_string(), role.to_string()),
+            ])
+        };
+
+        let document = fill(vec![person("Ada", "Analyst"), person("Grace", "Admiral")]);
+        assert_eq!(
+            document.matches(r#"<w:tag w:val="PeopleHeader"/>"#).count(),
+            1
+        );
+        let header = document.find(r#"<w:tag w:val="PeopleHeader"/>"#).unwrap();
+        assert!(header < document.find("Ada").unwrap());
+        assert_eq!(document.matches("<w:tr>").count(), 3);
+
+        let document = fill(vec![]);
+        assert!(!document.contains("PeopleHeader"));
+        assert_eq!(document.matches("<w:tr>").count(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "are not in the package")]
+    fn mismatched_part_names() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let renamed_data: ZipData = input_data
+            .iter()
+            .map(|(name, content)| (name.replace('/', "\\"), content.clone()))
+            .collect();
+        map_content_controls(
+            &renamed_data,
+            &controlled_documents,
+            &HashMap::new(),
+            &HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn number_formats() {
+        let number = |value: serde_json::Value| match value {
+            MappingValue::Number(number) => number,
+            _ => unreachable!(),
+        };
+        let value = number(serde_json::json!(1234567.5));
+        assert_eq!(NumberFormat::en_us().format(&value), "1,234,567.5");
+        assert_eq!(NumberFormat::de_de().format(&value), "1.234.567,5");
+        let value = number(serde_json::json!(-1234));
+        assert_eq!(NumberFormat::en_us().format(&value), "-1,234");
+        assert_eq!(NumberFormat::de_de().format(&value), "-1.234");
+        assert_eq!(
+            NumberFormat::de_de().format(&number(serde_json::json!(999))),
+            "999"
+        );
+
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("WritingDate".into(), serde_json::json!(1234.5))]);
+        let fill = |number_format: Option<NumberFormat>| {
+            let options = MapOptions {
+                number_format,
+                ..Default::default()
+            };
+            let mapped_data = map_content_controls_typed(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &HashMap::new(),
+                &options,
+            )
+            .unwrap();
+            String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap()
+        };
+        assert!(fill(Some(NumberFormat::de_de())).contains(">1.234,5<"));
+        assert!(fill(Some(NumberFormat::en_us())).contains(">1,234.5<"));
+        assert!(fill(None).contains(">1234.5<"));
+    }
+
+    #[test]
+    fn merge_split_runs() {
+        let input_data = load_path("tests/data/split_runs.docx");
+        let merged_data = merge_adjacent_runs_in_controls(&input_data).unwrap();
+        let document = String::from_utf8(merged_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:sdtContent><w:proofErr w:type="spellStart"/><w:r w:rsidRPr="00017B11"><w:rPr><w:i/><w:color w:val="FF0000"/></w:rPr>"#,
+            r#"<w:t>{{Name}} and</w:t></w:r><w:r><w:rPr><w:b/></w:rPr><w:t xml:space="preserve"> more</w:t></w:r></w:sdtContent>"#
+        )));
+        // runs outside of controls are left alone
+        assert!(document.contains("<w:r><w:t>Outside</w:t></w:r><w:r><w:t>Runs</w:t></w:r>"));
+
+        let mappings = HashMap::from([("Name".into(), "Ada".into())]);
+        let replaced = simple_replace(&merged_data, &mappings).unwrap();
+        let document = String::from_utf8(replaced["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("<w:t>Ada and</w:t>"));
+    }
+
+    #[test]
+    fn diagram_part_controls() {
+        let mut input_data = document_with_body("<w:p/>");
+        input_data.insert(
+            "word/diagrams/data1.xml".into(),
+            concat!(
+                r#"<dgm:dataModel xmlns:dgm="http://schemas.openxmlformats.org/drawingml/2006/diagram" xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">"#,
+                r#"<dgm:ptLst><dgm:pt modelId="1"><dgm:t>"#,
+                r#"<w:sdt><w:sdtPr><w:tag w:val="Step"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Step</w:t></w:r></w:sdtContent></w:sdt>"#,
+                r#"</dgm:t></dgm:pt></dgm:ptLst></dgm:dataModel>"#,
+            )
+            .as_bytes()
+            .to_vec(),
+        );
+        let report = inventory(&input_data).unwrap();
+        assert_eq!(report.diagram_controls.len(), 1);
+        assert_eq!(report.diagram_controls[0].part, "word/diagrams/data1.xml");
+        assert_eq!(report.diagram_controls[0].control.tag, "Step");
+
+        let controlled = get_content_controls(&input_data).unwrap();
+        let mapped = map_content_controls(
+            &input_data,
+            &controlled,
+            &HashMap::from([("Step".into(), "Collect".into())]),
+            &RepeatMapping::new(),
+        );
+        let diagram = String::from_utf8(mapped["word/diagrams/data1.xml"].clone()).unwrap();
+        assert!(diagram.contains("<w:t>Collect</w:t>"));
+        assert!(diagram.ends_with("</w:sdt></dgm:t></dgm:pt></dgm:ptLst></dgm:dataModel>"));
+    }
+
+    #[test]
+    fn run_style_override() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Author".into(), "Aldous Huxley".into()),
+        ]);
+        let options = MapOptions {
+            run_style: Some("FieldValue".into()),
+            run_styles: HashMap::from([("Author".into(), "AuthorName".into())]),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        let title = document.find("Brave New World").unwrap();
+        let run = document[..title].rfind("<w:r>").unwrap();
+        assert!(document[run..title].contains(r#"<w:rPr><w:rStyle w:val="FieldValue"/>"#));
+        assert_eq!(document[run..title].matches("w:rStyle").count(), 1);
+        let author = document.find("Aldous Huxley").unwrap();
+        let run = document[..author].rfind("<w:r>").unwrap();
+        assert!(document[run..author].contains(r#"<w:rStyle w:val="AuthorName"/>"#));
+    }
+
+    #[test]
+    fn run_style_replaces_existing() {
+        let mut reader = Reader::from_str(r#"<w:rPr><w:rStyle w:val="Old"/><w:b/></w:rPr>"#);
+        let mut events = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Eof => break,
+                e => events.push(e.into_owned()),
+            }
+        }
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        for ev in with_val_property(&events, "w:rPr", "w:rStyle", "FieldValue", &[]) {
+            writer.write_event(ev).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner().into_inner()).unwrap(),
+            r#"<w:rPr><w:rStyle w:val="FieldValue"/><w:b/></w:rPr>"#
+        );
+    }
+
+    #[test]
+    fn spans_beyond_i32() {
+        let begin = i32::MAX as i64 + 1;
+        let control = ContentControlPosition {
+            begin,
+            ..ContentControlPosition::new()
+        };
+        assert!(control.is_open_at(begin + 1));
+        assert!(!control.is_open_at(begin));
+        let control = ContentControlPosition {
+            content_begin: begin + 2,
+            end: begin + 4,
+            ..control
+        };
+        assert!(control.content_opened());
+        assert!(!control.is_open_at(begin + 3));
+    }
+
+    #[test]
+    fn lock_after_fill() {
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:alias w:val="Name"/><w:tag w:val="Name"/><w:id w:val="1"/><w:text/></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Code"/><w:lock w:val="sdtLocked"/><w:text/></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Unmapped"/><w:text/></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings =
+            HashMap::from([("Name".into(), "Jane".into()), ("Code".into(), "X1".into())]);
+        let options = MapOptions {
+            lock_after_fill: Some("sdtContentLocked".into()),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document
+            .contains(r#"<w:id w:val="1"/><w:lock w:val="sdtContentLocked"/><w:text/></w:sdtPr>"#));
+        assert!(document
+            .contains(r#"<w:tag w:val="Code"/><w:lock w:val="sdtContentLocked"/><w:text/>"#));
+        assert!(!document.contains("sdtLocked"));
+        assert_eq!(document.matches("<w:lock ").count(), 2);
+
+        let relocked = get_content_controls(&mapped_data).unwrap();
+        let controls = &relocked["word/document.xml"].control_positions;
+        assert_eq!(controls[0].get_metadata()["w:lock"], "sdtContentLocked");
+    }
+
+    #[test]
+    fn value_transform() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Author".into(), "Aldous Huxley".into()),
+        ]);
+        let options = MapOptions {
+            value_transform: Some(ValueTransform::new(|value, control| {
+                if control.get_tag() == "Author" {
+                    "[redacted]".into()
+                } else {
+                    value.to_uppercase()
+                }
+            })),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("BRAVE NEW WORLD"));
+        assert!(!document.contains("Brave New World"));
+        assert!(document.contains("[redacted]"));
+        assert!(!document.contains("Aldous Huxley"));
+        // the placeholder of unmapped controls is not a mapping value
+        assert!(document.contains(MISSING_STR));
+    }
+
+    #[test]
+    fn fill_by_index() {
+        let input_data = load_path("tests/data/untagged_controls.docx");
+        let mapped_data = map_by_index(
+            &input_data,
+            &[serde_json::json!("Jane"), serde_json::json!(42)],
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        let first = document.find("Jane").unwrap();
+        let between = document.find("Between").unwrap();
+        let second = document.find("42").unwrap();
+        assert!(first < between && between < second);
+        assert!(!document.contains("First field"));
+        assert!(!document.contains("Second field"));
+
+        let mapped_data = map_by_index(
+            &input_data,
+            &[serde_json::Value::Null, serde_json::json!("Doe")],
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("First field"));
+        assert!(document.contains("Doe"));
+        let mapped_data = map_by_index(&input_data, &[serde_json::json!("Only")]).unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("Only"));
+        assert!(document.contains("Second field"));
+        assert!(!document.contains(MISSING_STR));
+    }
+
+    #[test]
+    fn control_properties_fidelity() {
+        let sdt_properties = |part: &[u8]| -> Vec<String> {
+            let part = String::from_utf8(part.to_vec()).unwrap();
+            part.split("<w:sdtPr>")
+                .skip(1)
+                .map(|rest| rest[..rest.find("</w:sdtPr>").unwrap()].to_string())
+                .collect()
+        };
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Author".into(), "Aldous Huxley".into()),
+            ("Code".into(), "X1".into()),
+        ]);
+        let options = MapOptions {
+            missing: MissingBehavior::KeepOriginal,
+            ..Default::default()
+        };
+        let mut inputs = vec![load_path("tests/data/content_controlled_document.docx")];
+        inputs.push(document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val='Code' /><w:id w:val="-5" w14:x="1"/>"#,
+            r#"<w:text w:multiLine="0"></w:text><w:lock w:val="sdtLocked"/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+        )));
+        for input_data in inputs {
+            let controlled_documents = get_content_controls(&input_data).unwrap();
+            let mapped_data = map_content_controls_with(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &RepeatMapping::new(),
+                &options,
+            )
+            .unwrap();
+            for filename in controlled_documents.keys() {
+                let original = sdt_properties(&input_data[filename]);
+                assert!(!original.is_empty());
+                assert_eq!(sdt_properties(&mapped_data[filename]), original);
+            }
+        }
+    }
+
+    #[test]
+    fn drop_empty_inline_controls() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:r><w:t xml:space="preserve">before </w:t></w:r>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Middle"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Middle</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:r><w:t xml:space="preserve"> after</w:t></w:r></w:p>"#,
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Title"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Title</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:r><w:t xml:space="preserve"> kept</w:t></w:r></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Middle".into(), "".into()), ("Title".into(), "".into())]);
+        let options = MapOptions {
+            drop_empty: true,
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains("w:sdt"));
+        assert!(document.contains(
+            r#"<w:p><w:r><w:t xml:space="preserve">before </w:t></w:r><w:r><w:t xml:space="preserve">after</w:t></w:r></w:p>"#
+        ));
+        assert!(document.contains(r#"<w:p><w:r><w:t xml:space="preserve">kept</w:t></w:r></w:p>"#));
+
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert_eq!(document.matches("<w:sdt>").count(), 2);
+
+        // whitespace indenting the elements is not the text next to the control
+        let input_data = document_with_body(concat!(
+            "\n  <w:p>\n    <w:r>\n      <w:t xml:space=\"preserve\">before </w:t>\n    </w:r>\n",
+            r#"    <w:sdt><w:sdtPr><w:tag w:val="Middle"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Middle</w:t></w:r></w:sdtContent></w:sdt>"#,
+            "\n    <w:r>\n      <w:t xml:space=\"preserve\"> after</w:t>\n    </w:r>\n  </w:p>\n",
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains("w:sdt"));
+        assert!(document.contains(r#"<w:t xml:space="preserve">before </w:t>"#));
+        assert!(document.contains(r#"<w:t xml:space="preserve">after</w:t>"#));
+        assert!(document.contains("\n    </w:r>\n    \n    <w:r>\n"));
+    }
+
+    #[test]
+    fn custom_missing_placeholder() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Title".into(), "Brave New World".into())]);
+        let options = MapOptions {
+            missing_placeholder: Some("[to be filled]".into()),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("[to be filled]"));
+        assert!(document.contains("Brave New World"));
+        assert!(!document.contains(MISSING_STR));
+
+        // values equal to the custom placeholder are read back as missing
+        let controlled_documents = get_content_controls(&mapped_data).unwrap();
+        let options = MapOptions {
+            missing: MissingBehavior::KeepOriginal,
+            ..options
+        };
+        let mappings = HashMap::from([("Title".into(), "[to be filled]".into())]);
+        let refilled = map_content_controls_with(
+            &mapped_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(refilled["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("Brave New World"));
+    }
+
+    #[test]
+    fn keep_unmapped_controls() {
+        let sdt_blocks = |part: &[u8]| -> Vec<String> {
+            let part = String::from_utf8(part.to_vec()).unwrap();
+            let mut blocks = Vec::new();
+            let mut rest = part.as_str();
+            while let Some(start) = rest.find("<w:sdt>") {
+                let end = rest[start..].find("</w:sdt>").unwrap() + start + "</w:sdt>".len();
+                blocks.push(rest[start..end].to_string());
+                rest = &rest[end..];
+            }
+            blocks
+        };
+        let options = MapOptions {
+            missing: MissingBehavior::KeepOriginal,
+            ..Default::default()
+        };
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Author".into(), "Aldous Huxley".into())]);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        for filename in controlled_documents.keys() {
+            let original = sdt_blocks(&input_data[filename]);
+            let mapped = sdt_blocks(&mapped_data[filename]);
+            assert_eq!(original.len(), mapped.len());
+            for (original, mapped) in original.iter().zip(&mapped) {
+                if original.contains(r#"<w:tag w:val="Author"/>"#) {
+                    assert!(mapped.contains("Aldous Huxley"));
+                } else {
+                    assert_eq!(original, mapped);
+                }
+            }
+        }
+
+        // repeating sections without rows keep their items
+        let input_data = load_path("tests/data/repeat_header.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &Mapping::new(),
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            mapped_data["word/document.xml"],
+            input_data["word/document.xml"]
+        );
+    }
+
+    #[test]
+    fn properties_after_content() {
+        let input_data = load_path("tests/data/swapped_properties.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let controls = &controlled_documents["word/document.xml"].control_positions;
+        let found: Vec<(&str, ContentControlType)> = controls
+            .iter()
+            .map(|c| (c.get_tag(), c.get_type().clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("Swapped", ContentControlType::Text),
+                ("Outer", ContentControlType::RichText),
+                ("Inner", ContentControlType::Text),
+                ("Regular", ContentControlType::Date),
+            ]
+        );
+        assert_eq!(controls[0].get_alias(), "Swapped");
+        assert_eq!(controls[2].get_depth(), 2);
+
+        let mappings = HashMap::from([
+            ("Swapped".into(), "Filled".into()),
+            ("Inner".into(), "Nested".into()),
+        ]);
+        let options = MapOptions {
+            missing: MissingBehavior::KeepOriginal,
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(
+            r#"<w:sdtContent><w:p><w:r><w:t>Filled</w:t></w:r></w:p></w:sdtContent><w:sdtPr><w:alias w:val="Swapped"/>"#
+        ));
+        assert!(document.contains("<w:t>Nested</w:t>"));
+    }
+
+    #[test]
+    fn extract_content() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let content = extract_control_content(&input_data, "Author")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "<w:r><w:t>Foo Bar</w:t></w:r>"
+        );
+        assert_eq!(
+            extract_control_content(&input_data, "Unknown").unwrap(),
+            None
+        );
+
+        // lift the rich content of one document into a control of another
+        let input_data = load_path("tests/data/run_with_params.docx");
+        let content = extract_control_content(&input_data, "RunField")
+            .unwrap()
+            .unwrap();
+        let target = load_path("tests/data/content_controlled_document.docx");
+        let fragment = format!(
+            "<w:sdtContent>{}</w:sdtContent>",
+            String::from_utf8(content.clone()).unwrap()
+        );
+        let options = MapOptions {
+            raw_content_replace: true,
+            missing: MissingBehavior::KeepOriginal,
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &target,
+            &get_content_controls(&target).unwrap(),
+            &HashMap::from([("Title".into(), fragment)]),
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let header = String::from_utf8(mapped_data["word/header1.xml"].clone()).unwrap();
+        assert!(header.contains(str::from_utf8(&content).unwrap()));
+    }
+
+    #[test]
+    fn strict_mapping_validation() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Autor".into(), "Aldous Huxley".into()),
+            ("Date".into(), "12.12.2012".into()),
+        ]);
+        assert_eq!(
+            validate_mappings(&controlled_documents, &mappings),
+            Err(vec!["Autor".to_string(), "Date".to_string()])
+        );
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Author".into(), "Aldous Huxley".into()),
+        ]);
+        assert_eq!(validate_mappings(&controlled_documents, &mappings), Ok(()));
+        assert_eq!(
+            unmapped_tags(&controlled_documents, &mappings),
+            vec!["MainContent", "Sidematter", "WritingDate"]
+        );
+    }
+
+    #[test]
+    fn image_data_uri() {
+        let png = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR4nGP4z8AAAAMBAQDJ/pLvAAAAAElFTkSuQmCC";
+        let input_data = load_path("tests/data/picture_control.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = TypedMapping::from([
+            (
+                "Logo".into(),
+                serde_json::json!({"type": "image", "data_uri": format!("data:image/png;base64,{}", png)}),
+            ),
+            ("Caption".into(), serde_json::json!("Our logo")),
+        ]);
+        let mapped_data = map_content_controls_typed(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &MapOptions::default(),
+        )
+        .unwrap();
+        let image = &mapped_data["word/media/control-image1.png"];
+        assert_eq!(&image[..4], b"\x89PNG");
+        assert_eq!(image.len(), 69);
+        let rels = String::from_utf8(mapped_data["word/_rels/document.xml.rels"].clone()).unwrap();
+        assert!(rels.contains(r#"Id="rId100" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/image" Target="media/control-image1.png""#));
+        let types = String::from_utf8(mapped_data["[Content_Types].xml"].clone()).unwrap();
+        assert!(types.contains(r#"<Default Extension="png" ContentType="image/png"/></Types>"#));
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert_eq!(document.matches(r#"<a:blip r:embed="rId100"/>"#).count(), 1);
+        assert!(document.contains("Our logo"));
+        assert!(!document.contains(MISSING_STR));
+
+        // the type is inferred from the data without a media type
+        let image = ImageData::from_data_uri(&format!("data:;base64,{}", png)).unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert!(ImageData::from_data_uri("data:image/png,plain").is_err());
+        let mappings = TypedMapping::from([(
+            "Logo".into(),
+            serde_json::json!({"type": "image", "data_uri": "data:image/webp;base64,AAAA"}),
+        )]);
+        assert!(matches!(
+            map_content_controls_typed(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &RepeatMapping::new(),
+                &MapOptions::default(),
+            ),
+            Err(DocxError::InvalidValue { tag, .. }) if tag == "Logo"
+        ));
+    }
+
+    #[test]
+    fn escape_plain_values() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Title".into(), "Jack & Jill".into()),
+            ("Author".into(), "a < b > c".into()),
+            ("Sidematter".into(), r#"Say "hi" & 'bye'"#.into()),
+            ("WritingDate".into(), "AT&amp;T".into()),
+        ]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap()
+            + str::from_utf8(&mapped_data["word/header1.xml"]).unwrap();
+        assert!(document.contains("<w:t>Jack &amp; Jill</w:t>"));
+        assert!(document.contains("<w:t>a &lt; b &gt; c</w:t>"));
+        assert!(document.contains("<w:t>Say &quot;hi&quot; &amp; &apos;bye&apos;</w:t>"));
+        assert!(document.contains("<w:t>AT&amp;amp;T</w:t>"));
+
+        // the escaped text reads back as the original values
+        let controlled = get_content_controls(&mapped_data).unwrap();
+        let doc = &controlled["word/document.xml"];
+        let title = doc
+            .control_positions
+            .iter()
+            .find(|c| c.get_tag() == "Title")
+            .unwrap();
+        let text = control_text(title, &doc.events);
+        assert_eq!(text, "Jack & Jill");
+    }
+
+    #[test]
+    fn affected_by_partial_mappings() {
+        let sdt = |tag: &str, pr: &str, content: &str| {
+            format!(
+                r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/>{}</w:sdtPr><w:sdtContent>{}</w:sdtContent></w:sdt>"#,
+                tag, pr, content
+            )
+        };
+        let text = |tag: &str| sdt(tag, "<w:text/>", &format!("<w:r><w:t>{}</w:t></w:r>", tag));
+        let body = format!(
+            "<w:p>{}{}</w:p>{}",
+            text("Name"),
+            text("Date"),
+            sdt(
+                "People",
+                "<w15:repeatingSection/>",
+                &sdt(
+                    "Person",
+                    "<w15:repeatingSectionItem/>",
+                    &format!("<w:p>{}{}</w:p>", text("First"), text("Last")),
+                ),
+            )
+        );
+        let input_data = document_with_body(&body);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let file = |tag: &str| ("word/document.xml".to_string(), tag.to_string());
+
+        let mappings = HashMap::from([
+            ("Name".to_string(), "Jane".to_string()),
+            ("First".to_string(), "Not in a section".to_string()),
+        ]);
+        assert_eq!(
+            affected_controls(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &HashMap::new()
+            ),
+            vec![file("Name")]
+        );
+
+        let repeat_mappings = HashMap::from([(
+            "People".to_string(),
+            vec![HashMap::from([("Last".to_string(), "Doe".to_string())])],
+        )]);
+        assert_eq!(
+            affected_controls(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &repeat_mappings
+            ),
+            vec![file("Name"), file("People"), file("Person"), file("Last")]
+        );
+    }
+
+    #[test]
+    fn checkbox() {
+        let input_data = load_path("tests/data/checkbox.docx");
+        let expected_data = load_path("tests/data/checkbox_expected.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        assert!(controlled_documents["word/document.xml"]
+            .control_positions
+            .iter()
+            .all(|c| c.r#type == ContentControlType::Checkbox));
+        assert_eq!(
+            ContentControlType::parse_string(&ContentControlType::Checkbox.to_string()),
+            Some(ContentControlType::Checkbox)
+        );
+        let mappings = HashMap::from([
+            ("Agreed".to_string(), "true".to_string()),
+            ("Newsletter".to_string(), "maybe".to_string()),
+            ("Declined".to_string(), "0".to_string()),
+        ]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&expected_data["word/document.xml"]),
+            String::from_utf8_lossy(&mapped_data["word/document.xml"])
+        );
+    }
+
+    #[test]
+    fn no_proof_runs() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Code"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:rPr><w:b/><w:sz w:val="20"/></w:rPr><w:t>Code</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Note"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:t>Note</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Code".into(), "XJ-42b".into()),
+            ("Note".into(), "Checked".into()),
+        ]);
+        let options = MapOptions {
+            no_proof: true,
+            no_proof_tags: HashMap::from([("Note".into(), false)]),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(
+            r#"<w:r><w:rPr><w:b/><w:noProof/><w:sz w:val="20"/></w:rPr><w:t>XJ-42b</w:t></w:r>"#
+        ));
+        assert!(document.contains("<w:r><w:t>Checked</w:t></w:r>"));
+        assert_eq!(document.matches("w:noProof").count(), 1);
+    }
+
+    #[test]
+    fn map_by_alias() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:alias w:val="Customer name"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:alias w:val="Order date"/><w:tag w:val="date_1"/><w:text/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:r><w:t>Date</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:alias w:val="Total"/><w:tag w:val="total"/><w:text/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:r><w:t>Total</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let controls = &controlled_documents["word/document.xml"].control_positions;
+        assert_eq!(controls[0].get_tag(), "");
+        assert_eq!(controls[0].get_alias(), "Customer name");
+        let mappings = HashMap::from([
+            ("Customer name".into(), "Jane Doe".into()),
+            ("Order date".into(), "2024-05-01".into()),
+            ("total".into(), "by tag".into()),
+            ("Total".into(), "by alias".into()),
+        ]);
+        assert_eq!(validate_mappings(&controlled_documents, &mappings), Ok(()));
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("<w:t>Jane Doe</w:t>"));
+        assert!(document.contains("<w:t>2024-05-01</w:t>"));
+        assert!(document.contains("<w:t>by tag</w:t>"));
+        assert!(!document.contains("by alias"));
+    }
+
+    #[test]
+    fn template_round_trip() {
+        let template = load_path("tests/data/content_controlled_document.docx");
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Sidematter".into(), "Into a brave new world".into()),
+            ("WritingDate".into(), "12.12.2012".into()),
+            ("Author".into(), "Bruce Wayne".into()),
+            ("MainContent".into(), "This is rich coming from you.".into()),
+        ]);
+        let fill = |data: &ZipData| {
+            let controlled_documents = get_content_controls(data).unwrap();
+            map_content_controls(data, &controlled_documents, &mappings, &HashMap::new())
+        };
+        let filled = fill(&template);
+
+        let extracted = to_template(&filled, Some("Enter text")).unwrap();
+        let controlled_documents = get_content_controls(&extracted).unwrap();
+        let tags = |data: &ZipData| {
+            let mut tags: Vec<String> = get_content_controls(data)
+                .unwrap()
+                .values()
+                .flat_map(|doc| doc.control_positions.iter().map(|c| c.tag.clone()))
+                .collect();
+            tags.sort();
+            tags
+        };
+        assert_eq!(tags(&extracted), tags(&template));
+        for doc in controlled_documents.values() {
+            for control in &doc.control_positions {
+                assert_eq!(control_text(control, &doc.events), "Enter text");
+            }
+        }
+        assert_eq!(fill(&extracted), filled);
+
+        let by_tag = to_template(&filled, None).unwrap();
+        let controlled_documents = get_content_controls(&by_tag).unwrap();
+        let doc = &controlled_documents["word/document.xml"];
+        for control in &doc.control_positions {
+            assert_eq!(control_text(control, &doc.events), control.tag);
+        }
+
+        let template = load_path("tests/data/repeat_header.docx");
+        let controlled_documents = get_content_controls(&template).unwrap();
+        let person = |name: &str| HashMap::from([("Name".to_string(), name.to_string())]);
+        let repeat_mappings = HashMap::from([("People".into(), vec![person("A"), person("B")])]);
+        let filled = map_content_controls(
+            &template,
+            &controlled_documents,
+            &HashMap::new(),
+            &repeat_mappings,
+        );
+        let extracted = to_template(&filled, None).unwrap();
+        let controlled_documents = get_content_controls(&extracted).unwrap();
+        let items = controlled_documents["word/document.xml"]
+            .control_positions
+            .iter()
+            .filter(|c| c.tag == "Person")
+            .count();
+        assert_eq!(items, 1);
+    }
+
+    #[test]
+    fn control_values() {
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Address"/></w:sdtPr><w:sdtContent><w:p>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Street"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:t>Main </w:t></w:r><w:r><w:t>St</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:r><w:t xml:space="preserve">, </w:t></w:r>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="City"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:t>Springfield</w:t></w:r></w:sdtContent></w:sdt></w:p></w:sdtContent></w:sdt>"#,
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Note"/></w:sdtPr><w:sdtContent><w:r/>"#,
+            r#"</w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        assert_eq!(
+            get_control_values(&controlled_documents["word/document.xml"]),
+            HashMap::from([
+                ("Address".to_string(), "Main St, Springfield".to_string()),
+                ("Street".to_string(), "Main St".to_string()),
+                ("City".to_string(), "Springfield".to_string()),
+                ("Note".to_string(), String::new()),
+            ])
+        );
+    }
+
+    #[test]
+    fn date_controls() {
+        let date = |tag: &str, format: &str| {
+            format!(
+                concat!(
+                    r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/><w:date w:fullDate="2000-01-01T00:00:00Z">"#,
+                    r#"{}<w:lid w:val="de-DE"/><w:storeMappedDataAs w:val="dateTime"/>"#,
+                    r#"<w:calendar w:val="gregorian"/></w:date></w:sdtPr><w:sdtContent><w:r>"#,
+                    r#"<w:t>01.01.2000</w:t></w:r></w:sdtContent></w:sdt>"#
+                ),
+                tag, format
+            )
+        };
+        let input_data = document_with_body(&format!(
+            "<w:p>{}{}{}</w:p>",
+            date("Signed", r#"<w:dateFormat w:val="dd.MM.yyyy"/>"#),
+            date("Due", r#"<w:dateFormat w:val="d MMMM yyyy"/>"#),
+            date("Sent", r#"<w:dateFormat w:val="dd.MM.yyyy"/>"#),
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Signed".into(), "2012-12-24".into()),
+            ("Due".into(), "2013-01-05T10:00:00Z".into()),
+            ("Sent".into(), "next week".into()),
+        ]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(r#"<w:date w:fullDate="2012-12-24T00:00:00Z">"#));
+        assert!(document.contains("<w:t>24.12.2012</w:t>"));
+        assert!(document.contains(r#"<w:date w:fullDate="2013-01-05T10:00:00Z">"#));
+        assert!(document.contains("<w:t>5 January 2013</w:t>"));
+        assert!(document.contains(r#"<w:date w:fullDate="2000-01-01T00:00:00Z">"#));
+        assert!(document.contains("<w:t>next week</w:t>"));
+        assert_eq!(document.matches(r#"<w:lid w:val="de-DE"/>"#).count(), 3);
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn legacy_encoding() {
+        let input_data = load_path("tests/data/legacy_encoding.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let values = get_control_values(&controlled_documents["word/document.xml"]);
+        assert_eq!(values["Customer"], "J\u{fc}rgen M\u{fc}ller");
+        assert_eq!(values["Amount"], "0 \u{20ac}");
+        let mappings = HashMap::from([
+            ("Customer".into(), "Zo\u{eb} \u{3a9}".into()),
+            ("Amount".into(), "12 \u{20ac}".into()),
+        ]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+        );
+        let document = &mapped_data["word/document.xml"];
+        assert!(document.starts_with(br#"<?xml version="1.0" encoding="windows-1252""#));
+        assert!(find_subsequence(document, b"<w:t>Zo\xeb &#937;</w:t>").is_some());
+        assert!(find_subsequence(document, b"<w:t>12 \x80</w:t>").is_some());
+        assert!(find_subsequence(document, b"<w:t xml:space=\"preserve\">Kunde: </w:t>").is_some());
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn legacy_encoding_rewrite() {
+        let input_data = load_path("tests/data/legacy_encoding.docx");
+        let renamed_data = rename_tag(&input_data, "Customer", "Client").unwrap();
+        let document = &renamed_data["word/document.xml"];
+        assert!(document.starts_with(br#"<?xml version="1.0" encoding="windows-1252""#));
+        let values =
+            get_control_values(&get_content_controls(&renamed_data).unwrap()["word/document.xml"]);
+        assert_eq!(values["Client"], "J\u{fc}rgen M\u{fc}ller");
+        assert!(!values.contains_key("Customer"));
+
+        let removed_data = remove_content_controls(&input_data).unwrap();
+        let document = &removed_data["word/document.xml"];
+        assert!(find_subsequence(document, b"w:sdt").is_none());
+        assert!(find_subsequence(document, b"J\xfcrgen M\xfcller").is_some());
+        assert!(find_subsequence(document, b"0 \x80").is_some());
+    }
+
+    #[cfg(not(feature = "encoding"))]
+    #[test]
+    fn legacy_encoding_unsupported() {
+        let input_data = load_path("tests/data/legacy_encoding.docx");
+        match get_content_controls(&input_data) {
+            Err(DocxError::Encoding { part, .. }) => assert_eq!(part, "word/document.xml"),
+            other => panic!("expected an encoding error, got {:?}", other.err()),
+        }
+        assert!(matches!(
+            remove_content_controls(&input_data),
+            Err(DocxError::Encoding { .. })
+        ));
+    }
+
+    #[test]
+    fn scalar_for_repeating_section() {
+        let input_data = load_path("tests/data/repeat_header.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("People".into(), "Alice, Bob".into())]);
+        let result = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+            &MapOptions::default(),
+        );
+        match result {
+            Err(DocxError::ExpectedRepeatData { tag }) => assert_eq!(tag, "People"),
+            _ => panic!("expected an error for repeat data given as a single value"),
+        }
+        // without a result the section is left empty as before
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+        );
+        let mapped_controlled = get_content_controls(&mapped_data).unwrap();
+        let controls = &mapped_controlled["word/document.xml"].control_positions;
+        let section = controls.iter().find(|c| c.tag == "People").unwrap();
+        assert_eq!(get_contained_control(controls, section).count(), 0);
+        let repeat_mappings = HashMap::from([("People".into(), vec![])]);
+        assert!(map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &MapOptions::default(),
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn list_selection() {
+        let list = |tag: &str, element: &str| {
+            format!(
+                concat!(
+                    r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/><{}>"#,
+                    r#"<w:listItem w:displayText="Choose an item." w:value=""/>"#,
+                    r#"<w:listItem w:displayText="Germany" w:value="DE"/>"#,
+                    r#"<w:listItem w:displayText="France" w:value="FR"/></{}></w:sdtPr>"#,
+                    r#"<w:sdtContent><w:r><w:t>Choose an item.</w:t></w:r></w:sdtContent></w:sdt>"#
+                ),
+                tag, element, element
+            )
+        };
+        let input_data = document_with_body(&format!(
+            "<w:p>{}{}{}</w:p>",
+            list("Country", "w:dropDownList"),
+            list("Origin", "w:comboBox"),
+            list("Destination", "w:dropDownList"),
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Country".into(), "Germany".into()),
+            ("Origin".into(), "FR".into()),
+            ("Destination".into(), "Spain".into()),
+        ]);
+        let (mapped_data, diagnostics) = map_content_controls_with_diagnostics(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+            &MapOptions::default(),
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(r#"<w:dropDownList w:lastValue="DE">"#));
+        assert!(document.contains("<w:t>Germany</w:t>"));
+        assert!(document.contains(r#"<w:comboBox w:lastValue="FR">"#));
+        assert!(document.contains("<w:t>France</w:t>"));
+        assert!(document.contains("<w:t>Spain</w:t>"));
+        assert_eq!(document.matches("w:lastValue").count(), 2);
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                file: "word/document.xml".into(),
+                tag: "Destination".into(),
+                message: "value 'Spain' is not one of the list items, filled as plain text".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn diagnostics_follow_filling() {
+        let list = |tag: &str| {
+            format!(
+                concat!(
+                    r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/><w:dropDownList>"#,
+                    r#"<w:listItem w:displayText="Germany" w:value="DE"/></w:dropDownList></w:sdtPr>"#,
+                    r#"<w:sdtContent><w:r><w:t>Choose an item.</w:t></w:r></w:sdtContent></w:sdt>"#
+                ),
+                tag
+            )
+        };
+        let input_data = document_with_body(&format!(
+            concat!(
+                "<w:p>{}</w:p>",
+                r#"<w:sdt><w:sdtPr><w:tag w:val="Stops"/><w15:repeatingSection/></w:sdtPr><w:sdtContent>"#,
+                r#"<w:sdt><w:sdtPr><w:tag w:val="Stop"/><w15:repeatingSectionItem/></w:sdtPr>"#,
+                "<w:sdtContent><w:p>{}</w:p></w:sdtContent></w:sdt></w:sdtContent></w:sdt>"
+            ),
+            list("Country"),
+            list("City"),
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Country".into(), "TBD".into())]);
+        let repeat_mappings = HashMap::from([(
+            "Stops".into(),
+            vec![
+                HashMap::from([("City".into(), "de".into())]),
+                HashMap::from([("City".into(), "es".into())]),
+                HashMap::from([("City".into(), "es".into())]),
+            ],
+        )]);
+        let options = MapOptions {
+            value_transform: Some(ValueTransform::new(|value, _| value.to_uppercase())),
+            ..MapOptions::new()
+                .missing(MissingBehavior::KeepOriginal)
+                .missing_placeholder("TBD")
+        };
+        let (mapped_data, diagnostics) = map_content_controls_with_diagnostics(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("<w:t>Choose an item.</w:t>"));
+        assert!(document.contains("<w:t>Germany</w:t>"));
+        // the kept placeholder and the transformed "de" are not reported, nor is "ES" twice
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                file: "word/document.xml".into(),
+                tag: "City".into(),
+                message: "value 'ES' is not one of the list items, filled as plain text".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn typed_hyperlink() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let rels = String::from_utf8(input_data["word/_rels/document.xml.rels"].clone()).unwrap();
+        let mappings: TypedMapping = serde_json::from_str(
+            r#"{"Title": {"type": "hyperlink", "text": "Tom & Jerry", "url": "https://example.com/?a=1&b=2"}}"#,
+        )
+        .unwrap();
+        let mapped_data = map_content_controls_typed(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+            &MapOptions::default(),
+        )
+        .unwrap();
+        let mapped_rels =
+            String::from_utf8(mapped_data["word/_rels/document.xml.rels"].clone()).unwrap();
+        assert!(!rels.contains("rIdLink1"));
+        assert!(mapped_rels.contains(concat!(
+            r#"<Relationship Id="rIdLink1" "#,
+            r#"Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink" "#,
+            r#"Target="https://example.com/?a=1&amp;b=2" TargetMode="External"/></Relationships>"#
+        )));
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(r#"<w:hyperlink r:id="rIdLink1" w:history="1">"#));
+        assert!(document
+            .contains(r#"<w:t xml:space="preserve">Tom &amp; Jerry</w:t></w:r></w:hyperlink>"#));
+
+        // escaping applies to the values given, not to the markup generated for typed values
+        let mappings: TypedMapping = serde_json::from_str(concat!(
+            r#"{"Title": {"type": "hyperlink", "text": "Tom", "url": "https://example.com"}, "#,
+            r#""Sidematter": "<w:r><w:t>Raw</w:t></w:r>"}"#,
+        ))
+        .unwrap();
+        let escaped_data = map_content_controls_typed(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+            &MapOptions::new().escape(true),
+        )
+        .unwrap();
+        let document = String::from_utf8(escaped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(r#"<w:hyperlink r:id="rIdLink1" w:history="1">"#));
+        assert!(document.contains("&lt;w:r&gt;&lt;w:t&gt;Raw&lt;/w:t&gt;&lt;/w:r&gt;"));
+
+        let mappings: TypedMapping =
+            serde_json::from_str(r#"{"Title": {"type": "hyperlink", "text": "No target"}}"#)
+                .unwrap();
+        assert!(matches!(
+            map_content_controls_typed(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &HashMap::new(),
+                &MapOptions::default(),
+            ),
+            Err(DocxError::InvalidValue { tag, .. }) if tag == "Title"
+        ));
+    }
+
+    #[test]
+    fn typed_hyperlink_relationships() {
+        let mut input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Link"/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:t>Link</w:t></w:r></w:sdtContent></w:sdt></w:p>"#
+        ));
+        let header = input_data["word/document.xml"].clone();
+        input_data.insert("word/header1.xml".into(), header);
+        let empty_rels = concat!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+            r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"/>"#
+        );
+        input_data.insert("word/_rels/document.xml.rels".into(), empty_rels.into());
+        input_data.insert("word/_rels/header1.xml.rels".into(), empty_rels.into());
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings: TypedMapping = serde_json::from_str(
+            r#"{"Link": {"type": "hyperlink", "text": "Example", "url": "https://example.com"}}"#,
+        )
+        .unwrap();
+        let mapped_data = map_content_controls_typed(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &HashMap::new(),
+            &MapOptions::new().files(FileFilter::DocumentOnly),
+        )
+        .unwrap();
+        let rels = String::from_utf8(mapped_data["word/_rels/document.xml.rels"].clone()).unwrap();
+        assert!(rels.contains(concat!(
+            r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+            r#"<Relationship Id="rIdLink1" "#,
+            r#"Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink" "#,
+            r#"Target="https://example.com" TargetMode="External"/></Relationships>"#
+        )));
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"xmlns:w15="http://schemas.microsoft.com/office/word/2012/wordml" "#,
+            r#"xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><w:body>"#
+        )));
+        assert_eq!(document.matches("xmlns:r=").count(), 1);
+        assert!(document.contains(r#"<w:hyperlink r:id="rIdLink1" w:history="1">"#));
+        // the excluded header and its relationships are left alone
+        assert_eq!(
+            mapped_data["word/header1.xml"],
+            input_data["word/header1.xml"]
+        );
+        assert_eq!(
+            mapped_data["word/_rels/header1.xml.rels"],
+            input_data["word/_rels/header1.xml.rels"]
+        );
+
+        let mut malformed_data = input_data.clone();
+        malformed_data.insert(
+            "word/_rels/document.xml.rels".into(),
+            b"<Relationships><Relationship Id=\"rId1\"></Relationships>".to_vec(),
+        );
+        assert!(matches!(
+            map_content_controls_typed(
+                &malformed_data,
+                &controlled_documents,
+                &mappings,
+                &HashMap::new(),
+                &MapOptions::default(),
+            ),
+            Err(DocxError::Xml { part, .. }) if part == "word/_rels/document.xml.rels"
+        ));
+    }
+
+    #[test]
+    fn list_external_relationships() {
+        let mut input_data = load_path("tests/data/content_controlled_document.docx");
+        assert_eq!(external_relationships(&input_data), vec![]);
+        let rels = with_external_relationship(
+            "word/_rels/document.xml.rels",
+            input_data
+                .get("word/_rels/document.xml.rels")
+                .map(Vec::as_slice),
+            "rId90",
+            HYPERLINK_RELATIONSHIP,
+            "https://example.com/?a=1&b=2",
+        )
+        .unwrap();
+        input_data.insert("word/_rels/document.xml.rels".into(), rels);
+        let header_rels = with_external_relationship(
+            "word/_rels/header9.xml.rels",
+            None,
+            "rId1",
+            HYPERLINK_RELATIONSHIP,
+            "mailto:someone@example.com",
+        )
+        .unwrap();
+        input_data.insert("word/_rels/header9.xml.rels".into(), header_rels);
+        assert_eq!(
+            external_relationships(&input_data),
+            vec![
+                (
+                    "word/_rels/document.xml.rels".to_string(),
+                    "rId90".to_string(),
+                    "https://example.com/?a=1&b=2".to_string()
+                ),
+                (
+                    "word/_rels/header9.xml.rels".to_string(),
+                    "rId1".to_string(),
+                    "mailto:someone@example.com".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn run_format_overrides_prototype() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Total"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:rPr><w:i/><w:sz w:val="20"/></w:rPr><w:t>Total</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Note"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:rPr><w:i/></w:rPr><w:t>Note</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Total".into(), "1,234.00".into()),
+            ("Note".into(), "Paid".into()),
+        ]);
+        let options = MapOptions {
+            run_formats: HashMap::from([(
+                "Total".into(),
+                RunFormat {
+                    bold: true,
+                    color: Some("C00000".into()),
+                    ..Default::default()
+                },
+            )]),
+            no_proof: true,
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:r><w:rPr><w:b/><w:noProof/><w:color w:val="C00000"/></w:rPr>"#,
+            r#"<w:t>1,234.00</w:t></w:r>"#
+        )));
+        assert!(document.contains("<w:r><w:rPr><w:i/><w:noProof/></w:rPr><w:t>Paid</w:t></w:r>"));
+    }
+
+    #[test]
+    fn repeated_sdt_content() {
+        let input_data = load_path("tests/data/repeated_sdt_content.docx");
+        match get_content_controls(&input_data) {
+            Err(DocxError::MalformedControl { part, tag, reason }) => {
+                assert_eq!(part, "word/document.xml");
+                assert_eq!(tag, "Recipient");
+                assert_eq!(reason, "more than one w:sdtContent");
+            }
+            other => panic!("expected a malformed control, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn expansion_counts() {
+        let input_data = load_path("tests/data/TownLandRiver.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let repeat_mappings: RepeatMapping = serde_json::from_str(
+            r#"{"Entry": [
+                {"Town": "Cottbus", "Land": "Brandenburg", "River": "Dahme"},
+                {"Town": "Aachen", "Land": "NRW", "River": "Wurm"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            repeat_expansion_counts(&controlled_documents, &repeat_mappings),
+            HashMap::from([("Entry".to_string(), 2)])
+        );
+        assert_eq!(
+            repeat_expansion_counts(&controlled_documents, &RepeatMapping::new()),
+            HashMap::from([("Entry".to_string(), 0)])
+        );
+
+        let sdt = |tag: &str, pr: &str, content: &str| {
+            format!(
+                r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/>{}</w:sdtPr><w:sdtContent>{}</w:sdtContent></w:sdt>"#,
+                tag, pr, content
+            )
+        };
+        let section = |tag: &str, content: &str| {
+            sdt(
+                tag,
+                "<w15:repeatingSection/>",
+                &sdt("Item", "<w15:repeatingSectionItem/>", content),
+            )
+        };
+        let text = sdt("Name", "<w:text/>", "<w:p><w:r><w:t>Name</w:t></w:r></w:p>");
+        let input_data = document_with_body(&section("Orders", &section("Lines", &text)));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let item = |name: &str| HashMap::from([("Name".to_string(), name.to_string())]);
+        let repeat_mappings = HashMap::from([
+            ("Orders".to_string(), vec![item("A"), item("B"), item("C")]),
+            ("Lines".to_string(), vec![item("1"), item("2")]),
+        ]);
+        assert_eq!(
+            repeat_expansion_counts(&controlled_documents, &repeat_mappings),
+            HashMap::from([("Orders".to_string(), 3), ("Lines".to_string(), 6)])
+        );
+    }
+
+    #[test]
+    fn clear_placeholder_flag() {
+        let input_data = load_path("tests/data/placeholder_flag.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Recipient".into(), "Jane Doe".into())]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        let subject = document.find(r#"<w:tag w:val="Subject"/>"#).unwrap();
+        assert!(document[..subject].contains("Jane Doe"));
+        assert!(!document[..subject].contains("w:showingPlcHdr"));
+        // the unmapped control only gets the missing placeholder and keeps its flag
+        assert!(document[subject..].contains("<w:showingPlcHdr/>"));
+    }
+
+    #[test]
+    fn inherit_run_properties() {
+        let input_data = load_path("tests/data/run_with_control_params.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("RunField".into(), "colored text".into())]);
+        let map = |options: &MapOptions| {
+            let mapped_data = map_content_controls_with(
+                &input_data,
+                &controlled_documents,
+                &mappings,
+                &RepeatMapping::new(),
+                options,
+            )
+            .unwrap();
+            String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap()
+        };
+        assert!(map(&MapOptions::default())
+            .contains("<w:sdtContent><w:r><w:t>colored text</w:t></w:r></w:sdtContent>"));
+        let options = MapOptions {
+            inherit_run_properties: true,
+            ..Default::default()
+        };
+        assert!(map(&options).contains(concat!(
+            r#"<w:sdtContent><w:r><w:rPr><w:i/><w:iCs/><w:color w:val="FF0000"/></w:rPr>"#,
+            "<w:t>colored text</w:t></w:r></w:sdtContent>"
+        )));
+
+        // a formatted run before the first text wins over the properties of the control
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:rPr><w:i/></w:rPr><w:tag w:val="Total"/><w:text/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:r><w:rPr><w:b/><w:color w:val="0000FF"/></w:rPr><w:tab/></w:r>"#,
+            r#"<w:r><w:t>0.00</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Total".into(), "12.50".into())]);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(
+            r#"<w:r><w:rPr><w:b/><w:color w:val="0000FF"/></w:rPr><w:t>12.50</w:t></w:r>"#
+        ));
+    }
+
+    #[test]
+    fn nested_repeating_sections() {
+        let input_data = load_path("tests/data/nested_sections.docx");
+        let expected_data = load_path("tests/data/nested_sections_expected.docx");
+        let repeat_mappings: NestedRepeatMapping = serde_json::from_str(
+            r#"{"Regions": [
+                {"RegionName": "North", "Cities": [{"CityName": "Hamburg"}, {"CityName": "Kiel"}]},
+                {"RegionName": "South", "Cities": [{"CityName": "Munich"}]},
+                {"RegionName": "East", "Cities": []}
+            ]}"#,
+        )
+        .unwrap();
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mapped_data = map_content_controls_nested(
+            &input_data,
+            &controlled_documents,
+            &Mapping::new(),
+            &repeat_mappings,
+            &MapOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&mapped_data["word/document.xml"]),
+            String::from_utf8_lossy(&expected_data["word/document.xml"])
+        );
+    }
+
+    #[test]
+    fn empty_repeat_list() {
+        let input_data = load_path("tests/data/TownLandRiver.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let repeat_mappings = HashMap::from([("Entry".to_string(), Vec::new())]);
+        let mapped_data = map_content_controls(
+            &input_data,
+            &controlled_documents,
+            &Mapping::new(),
+            &repeat_mappings,
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains("w15:repeatingSectionItem"));
+        assert!(!document.contains("Elbe"));
+        assert!(document.contains(concat!(
+            r#"<w:tag w:val="Entry"/><w:id w:val="1780218204"/><w15:repeatingSection/></w:sdtPr>"#,
+            r#"<w:sdtEndPr><w:rPr><w:color w:val="auto"/></w:rPr></w:sdtEndPr>"#,
+            "<w:sdtContent></w:sdtContent></w:sdt></w:tbl>"
+        )));
+
+        // the emptied section is still found as one
+        let controlled = get_content_controls(&mapped_data).unwrap();
+        let controls = &controlled["word/document.xml"].control_positions;
+        assert_eq!(controls.len(), 1);
+        assert_eq!(controls[0].get_tag(), "Entry");
+        assert_eq!(
+            controls[0].get_type(),
+            &ContentControlType::RepeatingSection
+        );
+    }
+
+    #[test]
+    fn multiple_item_templates() {
+        let sdt = |tag: &str, pr: &str, content: &str| {
+            format!(
+                r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/>{}</w:sdtPr><w:sdtContent>{}</w:sdtContent></w:sdt>"#,
+                tag, pr, content
+            )
+        };
+        let text = |tag: &str| sdt(tag, "<w:text/>", &format!("<w:r><w:t>{}</w:t></w:r>", tag));
+        let item = "<w15:repeatingSectionItem/>";
+        let body = sdt(
+            "Lines",
+            "<w15:repeatingSection/>",
+            &format!(
+                "{}{}",
+                sdt("Detail", item, &format!("<w:p>{}</w:p>", text("Name"))),
+                sdt(
+                    "Subtotal",
+                    item,
+                    &format!(
+                        "<w:p><w:r><w:t>Subtotal </w:t></w:r>{}</w:p>",
+                        text("Amount")
+                    )
+                ),
+            ),
+        );
+        let input_data = document_with_body(&body);
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let row = |fields: &[(&str, &str)]| -> Mapping {
+            fields
+                .iter()
+                .map(|(tag, value)| (tag.to_string(), value.to_string()))
+                .collect()
+        };
+        let repeat_mappings = HashMap::from([(
+            "Lines".to_string(),
+            vec![
+                row(&[("Kind", "Detail"), ("Name", "Apples")]),
+                row(&[("Kind", "Subtotal"), ("Amount", "3.50")]),
+                row(&[("Name", "Pears")]),
+            ],
+        )]);
+        let options = MapOptions {
+            sections: HashMap::from([(
+                "Lines".to_string(),
+                SectionOptions {
+                    item_template_field: Some("Kind".into()),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let (mapped_data, diagnostics) = map_content_controls_with_diagnostics(
+            &input_data,
+            &controlled_documents,
+            &Mapping::new(),
+            &repeat_mappings,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(diagnostics, Vec::new());
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        let positions: Vec<usize> = ["Apples", "Subtotal </w:t>", "3.50", "Pears"]
+            .iter()
+            .map(|text| document.find(text).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(document.matches(r#"<w:tag w:val="Detail"/>"#).count(), 2);
+        assert_eq!(document.matches(r#"<w:tag w:val="Subtotal"/>"#).count(), 1);
+
+        let (mapped_data, diagnostics) = map_content_controls_with_diagnostics(
+            &input_data,
+            &controlled_documents,
+            &Mapping::new(),
+            &repeat_mappings,
+            &MapOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                file: "word/document.xml".into(),
+                tag: "Lines".into(),
+                message: "section has 2 item templates but no item template field, \
+                          only the first is used"
+                    .into(),
+            }]
+        );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains("Subtotal"));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_matches_sequential() {
+        let mut input_data = load_path("tests/data/content_controlled_document.docx");
+        let document = input_data["word/document.xml"].clone();
+        for i in 1..30 {
+            input_data.insert(format!("word/header{}.xml", i), document.clone());
+        }
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mut parsed = 0;
+        for (filename, part) in input_data
+            .iter()
+            .filter(|(_, part)| has_content_control(part))
+        {
+            let doc = parse_document(filename, part).unwrap();
+            assert_eq!(controlled_documents[filename].events, doc.events);
+            assert_eq!(controlled_documents[filename].summary(), doc.summary());
+            parsed += 1;
+        }
+        assert_eq!(controlled_documents.len(), parsed);
+
+        let mappings = HashMap::from([
+            ("Title".into(), "Brave New World".into()),
+            ("Sidematter".into(), "Parallel".into()),
+        ]);
+        let options = MapOptions::new().missing(MissingBehavior::Placeholder);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let sequential: ZipData = input_data
+            .iter()
+            .map(|(filename, part)| {
+                let mapped = map_package_part(
+                    filename,
+                    &controlled_documents,
+                    &mappings,
+                    &RepeatMapping::new(),
+                    &NestedRepeatMapping::new(),
+                    &options,
+                    &TypedTags::default(),
+                );
+                let mapped = mapped.map(|(mapped, _)| mapped);
+                (filename.clone(), mapped.unwrap_or_else(|| part.clone()))
+            })
+            .collect();
+        assert_eq!(mapped_data, sequential);
+    }
+
+    #[test]
+    fn map_to_writer() {
+        let input_data = load_path("tests/data/run_with_params_imgs.docx");
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("RunField".into(), "<b>streamed</b> & more".into())]);
+        let options = MapOptions::new()
+            .missing(MissingBehavior::Placeholder)
+            .missing_placeholder("TBD")
+            .escape(true)
+            .inline_markup(true);
+        let mut output = Cursor::new(Vec::new());
+        map_content_controls_to(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+            &mut output,
+        )
+        .unwrap();
+        output.set_position(0);
+        let expected = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        assert_ne!(expected, input_data);
+        assert_eq!(list_zip_contents(output).unwrap(), expected);
     }
-    mapped_data
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use pretty_assertions::assert_eq;
-    use std::collections::HashMap;
-    use std::fs;
-    use std::io::{BufReader, BufWriter};
-    use tempfile::tempfile;
 
-    fn load_path(path: &str) -> ZipData {
-        let fname = std::path::Path::new(&path);
-        let file = fs::File::open(fname).unwrap();
-        let reader = BufReader::new(file);
-        list_zip_contents(reader).unwrap()
+    #[test]
+    fn options_builder() {
+        let mut input_data = document_with_body(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Note"/></w:sdtPr><w:sdtContent><w:r><w:t>Note</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        );
+        let header = input_data["word/document.xml"].clone();
+        input_data.insert("word/header1.xml".into(), header.clone());
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Note".into(), "<w:b/>".into())]);
+        let options = MapOptions::new()
+            .missing(MissingBehavior::KeepOriginal)
+            .escape(true)
+            .files(FileFilter::DocumentOnly);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("<w:t>&lt;w:b/&gt;</w:t>"));
+        assert_eq!(mapped_data["word/header1.xml"], header);
     }
 
     #[test]
-    fn document_state() {
-        let input_data = load_path("tests/data/content_controlled_document.docx");
-        for (_filename, string) in input_data {
-            if has_content_control(&string) {
-                let enc_str = str::from_utf8(&string).expect("should be utf-8 encoded string");
-                let mut reader = Reader::from_str(enc_str);
-                let mut state = DocumentState::new();
-                while !state.is_eof {
-                    let event = reader.read_event();
-                    match event {
-                        Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                        Ok(e) => state.consume(&e),
-                    }
-                }
-                assert!(!state.controls.is_empty());
-                assert!(state.controls.iter().any(|c| c.begin >= 0
-                    && c.end >= 0
-                    && c.content_begin >= 0
-                    && c.content_end >= 0));
-            }
-        }
+    fn file_filter_streaming() {
+        let mut input_data = document_with_body(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Note"/></w:sdtPr><w:sdtContent><w:r><w:t>Note</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        );
+        let header = input_data["word/document.xml"].clone();
+        input_data.insert("word/header1.xml".into(), header.clone());
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([("Note".into(), "Filled".into())]);
+        let options = MapOptions::new().files(FileFilter::DocumentOnly);
+        let expected = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(expected["word/header1.xml"], header);
+        assert_ne!(expected["word/document.xml"], header);
+
+        let mut output = Cursor::new(Vec::new());
+        map_content_controls_to(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+            &mut output,
+        )
+        .unwrap();
+        output.set_position(0);
+        assert_eq!(list_zip_contents(output).unwrap(), expected);
+
+        let mut input = Cursor::new(Vec::new());
+        zip_dir(&input_data, &mut input).unwrap();
+        input.set_position(0);
+        let mut output = Cursor::new(Vec::new());
+        process_docx(
+            input,
+            &mut output,
+            &mappings,
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        output.set_position(0);
+        assert_eq!(list_zip_contents(output).unwrap(), expected);
     }
 
     #[test]
-    fn full_operation() {
-        let input_data = load_path("tests/data/content_controlled_document.docx");
-        let expected_data = load_path("tests/data/content_controlled_document_expected.docx");
+    fn end_properties() {
+        let input_data = load_path("tests/data/end_properties.docx");
+        let name_end =
+            r#"<w:sdtEndPr><w:rPr><w:b w:val="0"/><w:color w:val="auto"/></w:rPr></w:sdtEndPr>"#;
+        let terms_end = r#"<w:sdtEndPr><w:rPr><w:i w:val="0"/></w:rPr></w:sdtEndPr>"#;
+        let empty = document_with_body(
+            r#"<w:p><w:sdt><w:sdtPr/><w:sdtEndPr/><w:sdtContent><w:r><w:t>A</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        );
+        let document = String::from_utf8(
+            remove_content_controls(&empty).unwrap()["word/document.xml"].clone(),
+        )
+        .unwrap();
+        assert!(document.contains("<w:body><w:p><w:r><w:t>A</w:t></w:r></w:p></w:body>"));
+        let formatted = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr/><w:sdtEndPr><w:rPr><w:b w:val="0"/></w:rPr></w:sdtEndPr>"#,
+            r#"<w:sdtContent><w:r><w:t>A</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"<w:r><w:rPr><w:i/></w:rPr><w:t>B</w:t></w:r></w:p>"#,
+        ));
+        let document = String::from_utf8(
+            remove_content_controls(&formatted).unwrap()["word/document.xml"].clone(),
+        )
+        .unwrap();
+        assert!(document.contains(
+            "<w:p><w:r><w:t>A</w:t></w:r><w:r><w:rPr><w:i/></w:rPr><w:t>B</w:t></w:r></w:p>"
+        ));
 
+        let controlled_documents = get_content_controls(&input_data).unwrap();
         let mappings = HashMap::from([
-            ("Title".into(), "Brave New World".into()),
-            ("Sidematter".into(), "Into a brave new world".into()),
-            ("WritingDate".into(), "12.12.2012".into()),
-            ("Author".into(), "Bruce Wayne".into()),
-            ("MainContent".into(), "This is rich coming from you.".into()),
+            ("Name".into(), "Ada".into()),
+            ("Terms".into(), "Accepted".into()),
         ]);
-        let repeat_mappings = HashMap::from([]);
-        let controlled_documents = get_content_controls(&input_data);
         let mapped_data = map_content_controls(
             &input_data,
             &controlled_documents,
             &mappings,
-            &repeat_mappings,
+            &RepeatMapping::new(),
         );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(&format!("</w:sdtPr>{}<w:sdtContent>", name_end)));
+        assert!(document.contains(&format!("</w:sdtPr>{}<w:sdtContent>", terms_end)));
 
-        let mut outfile = tempfile().unwrap();
-        let _ = zip_dir(&mapped_data, &mut outfile);
-        let nreader = BufReader::new(outfile);
-        let result_data = list_zip_contents(nreader).unwrap();
-
-        for (e_k, e_v) in expected_data {
-            assert_eq!(
-                String::from_utf8_lossy(&e_v),
-                String::from_utf8_lossy(&result_data[&e_k])
-            );
-        }
+        // the end properties belong to the control and move to the run following it
+        let removed = remove_content_controls(&input_data).unwrap();
+        let document = String::from_utf8(removed["word/document.xml"].clone()).unwrap();
+        assert!(!document.contains("w:sdtEndPr"));
+        assert!(document.contains(concat!(
+            r#"<w:r><w:rPr><w:b/></w:rPr><w:t>Name</w:t></w:r>"#,
+            r#"<w:r><w:rPr><w:b w:val="0"/><w:color w:val="auto"/></w:rPr><w:t>, welcome.</w:t></w:r></w:p>"#,
+            r#"<w:p><w:r><w:rPr><w:i/></w:rPr><w:t>Terms</w:t></w:r></w:p><w:sectPr>"#
+        )));
     }
 
     #[test]
-    fn run_with_params() {
-        let input_data = load_path("tests/data/run_with_params.docx");
-        let expected_data = load_path("tests/data/run_with_params_expected.docx");
-        let mappings = HashMap::from([("RunField".into(), "Something new".into())]);
-        let repeat_mappings = HashMap::from([]);
-        let controlled_documents = get_content_controls(&input_data);
-        let mapped_data = map_content_controls(
+    fn data_binding() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Customer"/>"#,
+            r#"<w:dataBinding w:prefixMappings="xmlns:ns0='urn:invoice'" w:xpath="/ns0:invoice[1]/ns0:customer[1]" w:storeItemID="{B2B1E9C1-5B56-4F8A-9C47-0F0BD3E5D1A3}"/>"#,
+            r#"<w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Customer</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Date"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Date</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let controls = &controlled_documents["word/document.xml"].control_positions;
+        assert_eq!(
+            controls[0].get_data_binding(),
+            Some(&DataBinding {
+                xpath: "/ns0:invoice[1]/ns0:customer[1]".into(),
+                store_item_id: "{B2B1E9C1-5B56-4F8A-9C47-0F0BD3E5D1A3}".into(),
+                prefix_mappings: "xmlns:ns0='urn:invoice'".into(),
+            })
+        );
+        assert_eq!(controls[1].get_data_binding(), None);
+
+        let mappings = HashMap::from([
+            ("Customer".into(), "ACME".into()),
+            ("Date".into(), "today".into()),
+        ]);
+        let (_, diagnostics) = map_content_controls_with_diagnostics(
             &input_data,
             &controlled_documents,
             &mappings,
-            &repeat_mappings,
+            &RepeatMapping::new(),
+            &MapOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                file: "word/document.xml".into(),
+                tag: "Customer".into(),
+                message: "control is bound to /ns0:invoice[1]/ns0:customer[1] in custom XML part \
+                          {B2B1E9C1-5B56-4F8A-9C47-0F0BD3E5D1A3}, which Word may restore unless \
+                          it is updated too"
+                    .into(),
+            }]
         );
-        let mut outfile = tempfile().unwrap();
-        let _ = zip_dir(&mapped_data, &mut outfile);
-        let nreader = BufReader::new(outfile);
-        let result_data = list_zip_contents(nreader).unwrap();
+    }
 
-        for (e_k, e_v) in expected_data {
-            assert_eq!(
-                String::from_utf8_lossy(&e_v),
-                String::from_utf8_lossy(&result_data[&e_k])
-            );
-        }
+    #[test]
+    fn remove_matching_controls() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Internal"/></w:sdtPr><w:sdtContent><w:r><w:t>Note </w:t></w:r>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Name"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"</w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:sdt><w:sdtContent><w:p><w:r><w:t>Late</w:t></w:r></w:p></w:sdtContent><w:sdtPr><w:tag w:val="Internal"/></w:sdtPr></w:sdt>"#,
+        ));
+        let removed =
+            remove_content_controls_matching(&input_data, |c| c.get_tag() == "Internal").unwrap();
+        let document = String::from_utf8(removed["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:body><w:p><w:r><w:t>Note </w:t></w:r>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Name"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"</w:p><w:p><w:r><w:t>Late</w:t></w:r></w:p></w:body>"#,
+        )));
+        let malformed = document_with_body("<w:sdt><w:p></w:sdt>");
+        assert!(matches!(
+            remove_content_controls_matching(&malformed, |_| true),
+            Err(DocxError::Xml { .. })
+        ));
     }
 
     #[test]
-    fn preserve_images() {
-        let input_data = load_path("tests/data/run_with_params_imgs.docx");
-        let mappings = HashMap::from([("RunField".into(), "Something new".into())]);
-        let repeat_mappings = HashMap::from([]);
-        let controlled_documents = get_content_controls(&input_data);
+    fn clear_content() {
+        let input_data = load_path("tests/data/content_controlled_document.docx");
+        let cleared = clear_control_content(&input_data).unwrap();
+        let controlled = get_content_controls(&input_data).unwrap();
+        let cleared_controlled = get_content_controls(&cleared).unwrap();
+        let tags = |docs: &ParsedDocuments| -> Vec<String> {
+            docs["word/document.xml"]
+                .control_positions
+                .iter()
+                .map(|c| c.get_tag().to_string())
+                .collect()
+        };
+        assert_eq!(tags(&cleared_controlled), tags(&controlled));
+        let values = get_control_values(&cleared_controlled["word/document.xml"]);
+        assert!(values.values().all(String::is_empty), "{:?}", values);
+        let document = String::from_utf8(cleared["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:tag w:val="WritingDate"/><w:id w:val="-2016296941"/><w:placeholder><w:docPart w:val="DefaultPlaceholder_-1854013440"/></w:placeholder><w:text/></w:sdtPr>"#,
+            "<w:sdtContent><w:r><w:t></w:t></w:r></w:sdtContent></w:sdt>"
+        )));
+    }
+
+    #[test]
+    fn direct_children() {
+        let sdt = |tag: &str, content: &str| {
+            format!(
+                r#"<w:sdt><w:sdtPr><w:tag w:val="{}"/></w:sdtPr><w:sdtContent>{}</w:sdtContent></w:sdt>"#,
+                tag, content
+            )
+        };
+        let body = sdt(
+            "Outer",
+            &format!(
+                "{}{}",
+                sdt("Middle", &format!("<w:p>{}</w:p>", sdt("Inner", "<w:r/>"))),
+                sdt("Sibling", "<w:p/>")
+            ),
+        );
+        let input_data = document_with_body(&body);
+        let controlled = get_content_controls(&input_data).unwrap();
+        let controls = &controlled["word/document.xml"].control_positions;
+        let children = |tag: &str| -> Vec<&str> {
+            let control = controls.iter().find(|c| c.get_tag() == tag).unwrap();
+            get_direct_children(controls, control)
+                .map(ContentControlPosition::get_tag)
+                .collect()
+        };
+        assert_eq!(children("Outer"), vec!["Middle", "Sibling"]);
+        assert_eq!(children("Middle"), vec!["Inner"]);
+        assert!(children("Inner").is_empty());
+        let outer = &controls[0];
+        assert_eq!(get_contained_control(controls, outer).count(), 3);
+    }
+
+    #[test]
+    fn strip_control_locks() {
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Name"/><w:lock w:val="contentLocked"/><w:text/></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Code"/><w:lock w:val="sdtLocked"/><w:text/></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Note"/><w:text/></w:sdtPr><w:sdtContent><w:p/></w:sdtContent></w:sdt>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let controls = &controlled_documents["word/document.xml"].control_positions;
+        let locks: Vec<(Option<&str>, bool)> = controls
+            .iter()
+            .map(|c| (c.get_lock(), c.is_locked()))
+            .collect();
+        assert_eq!(
+            locks,
+            vec![
+                (Some("contentLocked"), true),
+                (Some("sdtLocked"), false),
+                (None, false)
+            ]
+        );
+
+        let options = MapOptions {
+            strip_locks: true,
+            lock_after_fill: Some("sdtContentLocked".into()),
+            ..Default::default()
+        };
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &HashMap::from([("Name".into(), "Ada".into())]),
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(
+            r#"<w:tag w:val="Name"/><w:lock w:val="sdtContentLocked"/><w:text/></w:sdtPr>"#
+        ));
+        assert!(document.contains(r#"<w:tag w:val="Code"/><w:text/></w:sdtPr>"#));
+        assert_eq!(document.matches("<w:lock ").count(), 1);
+
+        let document =
+            String::from_utf8(strip_locks(&input_data).unwrap()["word/document.xml"].clone())
+                .unwrap();
+        assert!(!document.contains("w:lock"));
+        assert!(document.contains(r#"<w:tag w:val="Name"/><w:text/></w:sdtPr>"#));
+    }
+
+    #[test]
+    fn line_breaks_and_tabs() {
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Address"/></w:sdtPr><w:sdtContent><w:p><w:r><w:t>Address</w:t></w:r></w:p></w:sdtContent></w:sdt>"#,
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Name"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Raw"/></w:sdtPr><w:sdtContent><w:p><w:r><w:t>Raw</w:t></w:r></w:p></w:sdtContent></w:sdt>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Address".into(), "1 Main Street\nSpringfield\r\nUSA".into()),
+            ("Name".into(), "Homer\tSimpson\nJr.".into()),
+            ("Raw".into(), "<w:r><w:t>a\nb</w:t></w:r>".into()),
+        ]);
         let mapped_data = map_content_controls(
             &input_data,
             &controlled_documents,
             &mappings,
-            &repeat_mappings,
+            &RepeatMapping::new(),
         );
-
-        let file = fs::File::create("test.docx").unwrap();
-        let mut writer = BufWriter::new(file);
-        let _ = zip_dir(&mapped_data, &mut writer);
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert_eq!(document.matches("<w:br/>").count(), 2);
+        assert!(document.contains(concat!(
+            r#"<w:r><w:t xml:space="preserve">1 Main Street</w:t><w:br/>"#,
+            r#"<w:t xml:space="preserve">Springfield</w:t><w:br/><w:t xml:space="preserve">USA</w:t></w:r>"#
+        )));
+        // single line controls get the newline collapsed but keep the tab
+        assert!(document.contains(concat!(
+            r#"<w:r><w:t xml:space="preserve">Homer</w:t><w:tab/>"#,
+            r#"<w:t xml:space="preserve">Simpson Jr.</w:t></w:r>"#
+        )));
+        assert!(document.contains("<w:r><w:t>a\nb</w:t></w:r>"));
     }
 
     #[test]
-    fn complex_replacement() {
-        let input_data = load_path("tests/data/run_with_params_imgs.docx");
-        let mappings = HashMap::from([(
-            "RunField".into(),
-            "<w:t>Something</w:t><w:cr/><w:t>new</w:t>".into(),
-        )]);
-        let repeat_mappings = HashMap::from([]);
-        let controlled_documents = get_content_controls(&input_data);
+    fn blank_lines_split_paragraphs() {
+        let input_data = document_with_body(concat!(
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Body"/></w:sdtPr><w:sdtContent><w:p><w:pPr><w:jc w:val="both"/></w:pPr>"#,
+            r#"<w:r><w:t>Body</w:t></w:r></w:p></w:sdtContent></w:sdt>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Single"/></w:sdtPr><w:sdtContent><w:p><w:r><w:t>Single</w:t></w:r></w:p></w:sdtContent></w:sdt>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Body".into(), "Para A\n\n\r\nPara B\nstill B".into()),
+            ("Single".into(), "Line A\nLine B".into()),
+        ]);
         let mapped_data = map_content_controls(
             &input_data,
             &controlled_documents,
             &mappings,
-            &repeat_mappings,
+            &RepeatMapping::new(),
         );
-
-        let file = fs::File::create("test2.docx").unwrap();
-        let mut writer = BufWriter::new(file);
-        let _ = zip_dir(&mapped_data, &mut writer);
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:sdtContent><w:p><w:pPr><w:jc w:val="both"/></w:pPr><w:r><w:t>Para A</w:t></w:r></w:p>"#,
+            r#"<w:p><w:pPr><w:jc w:val="both"/></w:pPr><w:r><w:t xml:space="preserve">Para B</w:t><w:br/>"#,
+            r#"<w:t xml:space="preserve">still B</w:t></w:r></w:p></w:sdtContent>"#
+        )));
+        assert!(document.contains(concat!(
+            r#"<w:sdtContent><w:p><w:r><w:t xml:space="preserve">Line A</w:t><w:br/>"#,
+            r#"<w:t xml:space="preserve">Line B</w:t></w:r></w:p></w:sdtContent>"#
+        )));
     }
 
     #[test]
-    fn repeat_replacement() {
-        let input_data = load_path("tests/data/TownLandRiver.docx");
-        let expected_data = load_path("tests/data/TownLandRiver_expected.docx");
-        let mappings = HashMap::from([]);
-        let data = r#"
-        {
-            "Entry": [
-            {
-                "Town": "Cottbus",
-                "Land": "Brandenburg",
-                "River": "Dahme"
-            },
-            {
-                "Town": "Aachen",
-                "Land": "NRW",
-                "River": "Wurm"
-            }
-            ]
-        }
-            "#;
-        let repeat_mappings: RepeatMapping = serde_json::from_str(data).unwrap();
-        let controlled_documents = get_content_controls(&input_data);
+    fn inline_markup() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Formula"/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:rPr><w:rFonts w:ascii="Arial"/><w:sz w:val="20"/></w:rPr><w:t>Formula</w:t></w:r>"#,
+            r#"</w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Note"/></w:sdtPr><w:sdtContent><w:r><w:t>Note</w:t></w:r></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let mappings = HashMap::from([
+            ("Formula".into(), "H<sub>2</sub>O is **wet**".into()),
+            ("Note".into(), "2 * 3 < 7".into()),
+        ]);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &mappings,
+            &RepeatMapping::new(),
+            &MapOptions::new().inline_markup(true),
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:sdtContent><w:r><w:rPr><w:rFonts w:ascii="Arial"/><w:sz w:val="20"/></w:rPr><w:t xml:space="preserve">H</w:t></w:r>"#,
+            r#"<w:r><w:rPr><w:rFonts w:ascii="Arial"/><w:sz w:val="20"/><w:vertAlign w:val="subscript"/></w:rPr><w:t xml:space="preserve">2</w:t></w:r>"#,
+            r#"<w:r><w:rPr><w:rFonts w:ascii="Arial"/><w:sz w:val="20"/></w:rPr><w:t xml:space="preserve">O is </w:t></w:r>"#,
+            r#"<w:r><w:rPr><w:rFonts w:ascii="Arial"/><w:b/><w:sz w:val="20"/></w:rPr><w:t xml:space="preserve">wet</w:t></w:r></w:sdtContent>"#
+        )));
+        assert!(
+            document.contains("<w:sdtContent><w:r><w:t>2 * 3 &lt; 7</w:t></w:r></w:sdtContent>")
+        );
+
+        // without the option the markup is written as typed
         let mapped_data = map_content_controls(
             &input_data,
             &controlled_documents,
             &mappings,
-            &repeat_mappings,
+            &RepeatMapping::new(),
         );
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains("H&lt;sub&gt;2&lt;/sub&gt;O is **wet**"));
+    }
 
-        let mut outfile = tempfile().unwrap();
-        let _ = zip_dir(&mapped_data, &mut outfile);
-        let nreader = BufReader::new(outfile);
-        let result_data = list_zip_contents(nreader).unwrap();
+    #[test]
+    fn missing_current_value() {
+        let input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Country"/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:rPr><w:i/></w:rPr><w:t>Ger</w:t></w:r><w:r><w:rPr><w:b/></w:rPr><w:t>many</w:t></w:r>"#,
+            r#"</w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Box"/></w:sdtPr><w:sdtContent><w:p><w:r><w:t>Keep </w:t></w:r>"#,
+            r#"<w:sdt><w:sdtPr><w:tag w:val="Name"/><w:text/></w:sdtPr><w:sdtContent><w:r><w:t>Name</w:t></w:r></w:sdtContent></w:sdt>"#,
+            r#"</w:p></w:sdtContent></w:sdt>"#,
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="City"/><w:showingPlcHdr/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:rPr><w:rStyle w:val="PlaceholderText"/></w:rPr><w:t>Click here to enter text.</w:t></w:r>"#,
+            r#"</w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let controlled_documents = get_content_controls(&input_data).unwrap();
+        let options = MapOptions::new().missing(MissingBehavior::CurrentValue);
+        let mapped_data = map_content_controls_with(
+            &input_data,
+            &controlled_documents,
+            &HashMap::from([("Name".into(), "Ada".into())]),
+            &RepeatMapping::new(),
+            &options,
+        )
+        .unwrap();
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        // the text is written again as a single run with the properties of the first one
+        assert!(document.contains(
+            r#"<w:sdtContent><w:r><w:rPr><w:i/></w:rPr><w:t>Germany</w:t></w:r></w:sdtContent>"#
+        ));
+        assert!(document.contains(concat!(
+            r#"<w:sdtContent><w:p><w:r><w:t>Keep </w:t></w:r><w:sdt><w:sdtPr><w:tag w:val="Name"/><w:text/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:r><w:t>Ada</w:t></w:r></w:sdtContent></w:sdt></w:p></w:sdtContent>"#
+        )));
+        // placeholder text is not a current value, the control keeps it and its flag
+        assert!(document.contains(concat!(
+            r#"<w:tag w:val="City"/><w:showingPlcHdr/><w:text/></w:sdtPr><w:sdtContent>"#,
+            r#"<w:r><w:rPr><w:rStyle w:val="PlaceholderText"/></w:rPr><w:t>Click here to enter text.</w:t></w:r>"#,
+            r#"</w:sdtContent>"#
+        )));
+    }
 
-        for (e_k, e_v) in expected_data {
-            assert_eq!(
-                String::from_utf8_lossy(&e_v),
-                String::from_utf8_lossy(&result_data[&e_k])
-            );
-        }
+    #[test]
+    fn duplicate_tags() {
+        let mut input_data = document_with_body(concat!(
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Name"/></w:sdtPr><w:sdtContent><w:r/></w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:p><w:sdt><w:sdtPr><w:tag w:val="Date"/></w:sdtPr><w:sdtContent><w:r/></w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:p><w:sdt><w:sdtPr/><w:sdtContent><w:r/></w:sdtContent></w:sdt></w:p>"#,
+            r#"<w:p><w:sdt><w:sdtPr/><w:sdtContent><w:r/></w:sdtContent></w:sdt></w:p>"#,
+        ));
+        let header = input_data["word/document.xml"].clone();
+        input_data.insert("word/header1.xml".into(), header);
+        let controlled = get_content_controls(&input_data).unwrap();
+        assert_eq!(
+            find_duplicate_tags(&controlled),
+            HashMap::from([("Name".into(), 2), ("Date".into(), 2)])
+        );
     }
 
     #[test]
-    fn table_replacement() {
+    fn rename_single_tag() {
         let input_data = load_path("tests/data/content_controlled_document.docx");
-        let mappings = HashMap::from([(
-            "MainContent".into(),
-            "<w:tbl>
-<w:tblPr>
-<w:tblStyle w:val=\"TableGrid\"/>
-<w:tblW w:w=\"5000\" w:type=\"pct\"/>
-</w:tblPr>
-<w:tblGrid>
-<w:gridCol w:w=\"2880\"/>
-<w:gridCol w:w=\"2880\"/>
-<w:gridCol w:w=\"2880\"/>
-</w:tblGrid>
-<w:tr>
-<w:tc>
-<w:tcPr>
-<w:tcW w:w=\"2880\" w:type=\"dxa\"/>
-</w:tcPr>
-<w:p>
-<w:r>
-<w:t>AAA</w:t>
-</w:r>
-</w:p>
-</w:tc>
-<w:tc>
-<w:tcPr>
-<w:tcW w:w=\"2880\" w:type=\"dxa\"/>
-</w:tcPr>
-<w:p>
-<w:r>
-<w:t>BBB</w:t>
-</w:r>
-</w:p>
-</w:tc>
-<w:tc>
-<w:tcPr>
-<w:tcW w:w=\"2880\" w:type=\"dxa\"/>
-</w:tcPr>
-<w:p>
-<w:r>
-<w:t>CCC</w:t>
-</w:r>
-</w:p>
-</w:tc>
-</w:tr>
-</w:tbl>"
-                .into(),
-        )]);
-        let repeat_mappings = HashMap::from([]);
-        let controlled_documents = get_content_controls(&input_data);
+        let renamed = rename_tag(&input_data, "Title", "DocumentTitle").unwrap();
+        let controlled_documents = get_content_controls(&renamed).unwrap();
+        let tags: Vec<&str> = controlled_documents["word/document.xml"]
+            .control_positions
+            .iter()
+            .map(ContentControlPosition::get_tag)
+            .collect();
+        assert!(tags.contains(&"DocumentTitle"));
+        assert!(!tags.contains(&"Title"));
+
+        let mappings = HashMap::from([("DocumentTitle".into(), "Annual report".into())]);
         let mapped_data = map_content_controls(
-            &input_data,
+            &renamed,
             &controlled_documents,
             &mappings,
-            &repeat_mappings,
+            &RepeatMapping::new(),
         );
-
-        let file = fs::File::create("test3.docx").unwrap();
-        let mut writer = BufWriter::new(file);
-        let _ = zip_dir(&mapped_data, &mut writer);
+        let document = String::from_utf8(mapped_data["word/document.xml"].clone()).unwrap();
+        assert!(document.contains(concat!(
+            r#"<w:tag w:val="DocumentTitle"/><w:id w:val="-1348251203"/><w:placeholder><w:docPart w:val="DefaultPlaceholder_-1854013440"/></w:placeholder><w:text/></w:sdtPr>"#,
+            r#"<w:sdtContent><w:p><w:pPr><w:pStyle w:val="Titel"/></w:pPr><w:r><w:t>Annual report</w:t></w:r></w:p></w:sdtContent>"#
+        )));
     }
 }
@@ -0,0 +1,58 @@
+//! Checksums of the parts of a package, for downstream systems to verify the exact parts they
+//! received.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::ZipData;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Manifest {
+    /// Part names with the lowercase hex SHA-256 of their content, sorted by name.
+    pub parts: Vec<(String, String)>,
+}
+
+/**
+ * Compute the SHA-256 of every part of the package.
+ */
+pub fn manifest(data: &ZipData) -> Manifest {
+    let mut parts: Vec<(String, String)> = data
+        .iter()
+        .map(|(name, bytes)| {
+            let digest = Sha256::digest(bytes);
+            let hex = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            (name.clone(), hex)
+        })
+        .collect();
+    parts.sort();
+    Manifest { parts }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn part_checksums() {
+        let data = HashMap::from([
+            ("word/document.xml".into(), b"abc".to_vec()),
+            ("[Content_Types].xml".into(), Vec::new()),
+        ]);
+        let manifest = manifest(&data);
+        assert_eq!(
+            manifest.parts,
+            vec![
+                (
+                    "[Content_Types].xml".into(),
+                    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".into()
+                ),
+                (
+                    "word/document.xml".into(),
+                    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".into()
+                ),
+            ]
+        );
+    }
+}
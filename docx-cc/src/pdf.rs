@@ -0,0 +1,99 @@
+//! Conversion of filled documents to PDF.
+//!
+//! No renderer is bundled, conversion goes through the `PdfRenderer` trait so callers can plug in
+//! whichever engine they have. With the `libreoffice` feature, `LibreOfficeRenderer` converts by
+//! running LibreOffice in headless mode.
+
+use crate::DocxError;
+
+/**
+ * Engine converting a `.docx` document to PDF.
+ */
+pub trait PdfRenderer {
+    fn render(&self, docx: &[u8]) -> Result<Vec<u8>, DocxError>;
+}
+
+#[cfg(feature = "libreoffice")]
+pub use libreoffice::LibreOfficeRenderer;
+
+#[cfg(feature = "libreoffice")]
+mod libreoffice {
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::PdfRenderer;
+    use crate::DocxError;
+
+    static CONVERSIONS: AtomicUsize = AtomicUsize::new(0);
+
+    /**
+     * Renderer running `soffice --headless --convert-to pdf` on a temporary copy of the document.
+     */
+    #[derive(Debug, Clone)]
+    pub struct LibreOfficeRenderer {
+        /// LibreOffice executable, `soffice` from the `PATH` by default.
+        pub program: PathBuf,
+    }
+
+    impl Default for LibreOfficeRenderer {
+        fn default() -> Self {
+            LibreOfficeRenderer {
+                program: "soffice".into(),
+            }
+        }
+    }
+
+    impl PdfRenderer for LibreOfficeRenderer {
+        fn render(&self, docx: &[u8]) -> Result<Vec<u8>, DocxError> {
+            let dir = std::env::temp_dir().join(format!(
+                "docx-cc-{}-{}",
+                std::process::id(),
+                CONVERSIONS.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&dir)?;
+            let result = convert(&self.program, &dir, docx);
+            let _ = std::fs::remove_dir_all(&dir);
+            result
+        }
+    }
+
+    fn convert(program: &PathBuf, dir: &PathBuf, docx: &[u8]) -> Result<Vec<u8>, DocxError> {
+        let input = dir.join("document.docx");
+        std::fs::write(&input, docx)?;
+        let output = Command::new(program)
+            .arg("--headless")
+            .arg("--convert-to")
+            .arg("pdf")
+            .arg("--outdir")
+            .arg(dir)
+            .arg(&input)
+            .output()
+            .map_err(|e| {
+                DocxError::Render(format!("could not run {}: {}", program.display(), e))
+            })?;
+        if !output.status.success() {
+            return Err(DocxError::Render(format!(
+                "{} failed with {}: {}",
+                program.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        std::fs::read(dir.join("document.pdf"))
+            .map_err(|e| DocxError::Render(format!("no PDF written: {}", e)))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn missing_program() {
+            let renderer = LibreOfficeRenderer {
+                program: "/nonexistent/soffice".into(),
+            };
+            assert!(matches!(renderer.render(b""), Err(DocxError::Render(_))));
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use std::fs;
-use std::io::{BufReader, BufWriter};
-use docx_cc;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
 use clap::{Parser, Subcommand};
+use docx_cc::PdfRenderer;
+
+mod repeat_csv;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about=None)]
@@ -9,36 +13,293 @@ struct Args {
     #[command(subcommand)]
     command: Commands,
 
+    /// Template to work on, required by all commands except fill-job, or - to read it from stdin
     #[arg(short, long)]
-    template_path: String,
+    template_path: Option<String>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     Clear {
+        /// File to write the document to, or - for stdout
+        #[arg(last=true)]
+        output_path: String,
+    },
+    /// Fill the content controls of the template and write the result
+    ///
+    /// Values are read from a JSON mappings file and from environment variables starting with
+    /// the env prefix. For tags given by both, the mappings file wins unless --env-overrides is
+    /// set.
+    Map {
+        /// JSON file mapping tags to values, or of the shape {"mappings": {...}, "repeat_mappings": {...}} mapping the tags of repeating sections to lists of items
+        #[arg(short, long)]
+        mappings: Option<String>,
+        /// Fill controls from environment variables with this prefix, e.g. CC_Title for Title
+        #[arg(long)]
+        env_prefix: Option<String>,
+        /// Let environment variables take precedence over the mappings file
+        #[arg(long)]
+        env_overrides: bool,
+        /// Fill a repeating section from a CSV file with a header row of tags, one item per row, e.g. Orders=orders.csv
+        #[arg(long, value_name = "SECTION=PATH", value_parser = repeat_csv::parse_section_file)]
+        repeat_csv: Vec<(String, String)>,
+        /// Write the filled document as PDF, converted with LibreOffice
+        #[arg(long)]
+        pdf: bool,
+        /// Write the SHA-256 of every part of the filled document to this JSON file
+        #[arg(long)]
+        manifest: Option<String>,
+        /// File to write the document to, or - for stdout
         #[arg(last=true)]
         output_path: String,
+    },
+    /// Fill a template from a job archive containing template.docx and data.json
+    ///
+    /// data.json holds a JSON object of tag to value. Arrays of objects fill the repeating
+    /// section of that tag, one item per object, and null values leave the control as it is.
+    FillJob {
+        #[arg(short, long)]
+        job: String,
+        /// File to write the document to, or - for stdout
+        #[arg(last=true)]
+        output_path: String,
+    },
+    /// Fill the template once for every row returned by a SQLite query
+    ///
+    /// Columns are mapped to the tags of the same name. Documents are written to the output
+    /// directory, named after the template, or "document" when reading it from stdin, with the row
+    /// number appended.
+    #[cfg(feature = "sqlite")]
+    Batch {
+        /// SQLite database to read the rows from
+        #[arg(long)]
+        sqlite: String,
+        #[arg(long)]
+        query: String,
+        #[arg(long)]
+        out_dir: String,
+    },
+    /// Extract the parts of the template into a directory
+    Unzip {
+        #[arg(last=true)]
+        output_dir: String,
+    },
+    /// Package the parts in a directory, e.g. written by unzip, as a document
+    Zip {
+        #[arg(short, long)]
+        dir: String,
+        /// File to write the document to, or - for stdout
+        #[arg(last=true)]
+        output_path: String,
+    },
+    /// Print the content controls of the template as JSON, grouped by part
+    ///
+    /// Every control lists the tags of the controls nested in it under "children".
+    List,
+    /// Write a JSON report of the parts and content controls of the template
+    Inventory {
+        #[arg(short, long)]
+        out: String,
+    },
+}
+
+/// Read a whole file, or stdin for -. Stdin is buffered as the archive reader has to seek.
+fn read_input(path: &str) -> Cursor<Vec<u8>> {
+    let mut bytes = Vec::new();
+    if path == "-" {
+        std::io::stdin().lock().read_to_end(&mut bytes).unwrap();
+    } else {
+        bytes = fs::read(path).unwrap();
     }
+    Cursor::new(bytes)
+}
+
+/// Write to a file, or to stdout for -. Rust writes stdout as bytes, also on Windows, so the
+/// archive is not changed by line ending translation.
+fn write_output(path: &str, bytes: &[u8]) {
+    if path == "-" {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes).unwrap();
+        stdout.flush().unwrap();
+    } else {
+        fs::write(path, bytes).unwrap();
+    }
+}
+
+fn write_zip(data: &docx_cc::ZipData, path: &str) {
+    let mut output = Cursor::new(Vec::new());
+    docx_cc::zip_dir(data, &mut output).unwrap();
+    write_output(path, output.get_ref());
 }
 
 fn load_path(path: &str) -> docx_cc::ZipData {
-    let fname = std::path::Path::new(&path);
-    let file = fs::File::open(fname).unwrap();
-    let reader = BufReader::new(file);
-    docx_cc::list_zip_contents(reader).unwrap()
+    docx_cc::list_zip_contents(read_input(path)).unwrap()
+}
+
+fn template_path(path: &Option<String>) -> &str {
+    path.as_deref().expect("--template-path is required for this command")
+}
+
+fn load_template(path: &Option<String>) -> docx_cc::ZipData {
+    load_path(template_path(path))
+}
+
+fn write_manifest(data: &docx_cc::ZipData, path: &str) {
+    let writer = BufWriter::new(fs::File::create(path).unwrap());
+    serde_json::to_writer_pretty(writer, &docx_cc::manifest(data)).unwrap();
+}
+
+/// Contents of a mappings file, either both kinds of mappings or just the values by tag.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum MappingsFile {
+    Combined(CombinedMappings),
+    Flat(docx_cc::Mapping),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CombinedMappings {
+    mappings: docx_cc::Mapping,
+    #[serde(default)]
+    repeat_mappings: docx_cc::RepeatMapping,
+}
+
+fn json_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn fill_job(job_path: &str) -> docx_cc::ZipData {
+    let job = load_path(job_path);
+    let template = job.get("template.docx").expect("job should contain template.docx");
+    let data = docx_cc::list_zip_contents(std::io::Cursor::new(template)).unwrap();
+    let values: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(job.get("data.json").expect("job should contain data.json")).unwrap();
+
+    let mut mappings = docx_cc::TypedMapping::new();
+    let mut repeat_mappings = docx_cc::RepeatMapping::new();
+    for (tag, value) in values {
+        match value {
+            serde_json::Value::Array(items) => {
+                let rows = items.iter().filter_map(|item| item.as_object()).map(|item| {
+                    item.iter().map(|(tag, value)| (tag.clone(), json_text(value))).collect()
+                }).collect();
+                repeat_mappings.insert(tag, rows);
+            }
+            other => {
+                mappings.insert(tag, other);
+            }
+        }
+    }
+    let controlled = docx_cc::get_content_controls(&data).unwrap();
+    docx_cc::map_content_controls_typed(&data, &controlled, &mappings, &repeat_mappings, &docx_cc::MapOptions::default()).unwrap()
 }
 
 fn main() {
     let args = Args::parse();
 
-    let data = load_path(&args.template_path);
-
     match args.command {
         Commands::Clear { output_path } => {
-            let result = docx_cc::remove_content_controls(&data);
-            let output_file = fs::File::create(output_path).unwrap();
-            let mut writer = BufWriter::new(output_file);
-            let _ = docx_cc::zip_dir(&result, &mut writer);
+            let data = load_template(&args.template_path);
+            let result = docx_cc::remove_content_controls(&data).unwrap();
+            write_zip(&result, &output_path);
+        }
+        Commands::Map { mappings, env_prefix, env_overrides, repeat_csv, pdf, manifest, output_path } => {
+            let template = read_input(template_path(&args.template_path));
+            let (file_mappings, mut repeat_mappings) = match mappings {
+                Some(path) => match serde_json::from_reader(BufReader::new(fs::File::open(path).unwrap())).unwrap() {
+                    MappingsFile::Combined(combined) => (combined.mappings, combined.repeat_mappings),
+                    MappingsFile::Flat(mappings) => (mappings, docx_cc::RepeatMapping::new()),
+                },
+                None => (docx_cc::Mapping::new(), docx_cc::RepeatMapping::new()),
+            };
+            let env_mappings = match env_prefix {
+                Some(prefix) => docx_cc::mapping_from_env(&prefix, std::env::vars()),
+                None => docx_cc::Mapping::new(),
+            };
+            let (mut values, overrides) = if env_overrides {
+                (file_mappings, env_mappings)
+            } else {
+                (env_mappings, file_mappings)
+            };
+            values.extend(overrides);
+            if !repeat_csv.is_empty() {
+                let schema = docx_cc::repeating_section_schema(&docx_cc::list_zip_contents(template.clone()).unwrap()).unwrap();
+                for (section, path) in repeat_csv {
+                    let items = repeat_csv::read_section_items(&path, &section, &schema).unwrap_or_else(|e| panic!("{}", e));
+                    repeat_mappings.insert(section, items);
+                }
+            }
+            let mut docx = Cursor::new(Vec::new());
+            docx_cc::process_docx(template, &mut docx, &values, &repeat_mappings, &docx_cc::MapOptions::default()).unwrap();
+            if pdf {
+                let rendered = docx_cc::LibreOfficeRenderer::default().render(docx.get_ref()).unwrap();
+                write_output(&output_path, &rendered);
+            } else {
+                write_output(&output_path, docx.get_ref());
+            }
+            if let Some(manifest_path) = manifest {
+                docx.set_position(0);
+                write_manifest(&docx_cc::list_zip_contents(docx).unwrap(), &manifest_path);
+            }
+        }
+        Commands::FillJob { job, output_path } => {
+            let result = fill_job(&job);
+            write_zip(&result, &output_path);
+        }
+        #[cfg(feature = "sqlite")]
+        Commands::Batch { sqlite, query, out_dir } => {
+            let template_path = template_path(&args.template_path);
+            let template = read_input(template_path);
+            let records = sqlite::query_records(&sqlite, &query).unwrap();
+            let stem = match template_path {
+                "-" => "document".to_string(),
+                path => std::path::Path::new(path).file_stem().unwrap().to_string_lossy().to_string(),
+            };
+            fs::create_dir_all(&out_dir).unwrap();
+            for (index, record) in records.iter().enumerate() {
+                let input = template.clone();
+                let output_path = std::path::Path::new(&out_dir).join(format!("{}-{}.docx", stem, index + 1));
+                let output = BufWriter::new(fs::File::create(output_path).unwrap());
+                docx_cc::process_docx(input, output, record, &docx_cc::RepeatMapping::new(), &docx_cc::MapOptions::default()).unwrap();
+            }
+        }
+        Commands::Unzip { output_dir } => {
+            let data = load_template(&args.template_path);
+            docx_cc::write_unzipped(&data, std::path::Path::new(&output_dir)).unwrap();
+        }
+        Commands::Zip { dir, output_path } => {
+            let data = docx_cc::read_unzipped(std::path::Path::new(&dir)).unwrap();
+            write_zip(&data, &output_path);
+        }
+        Commands::List => {
+            let data = load_template(&args.template_path);
+            let controlled = docx_cc::get_content_controls(&data).unwrap();
+            let mut listing = serde_json::Map::new();
+            let mut filenames: Vec<&String> = controlled.keys().collect();
+            filenames.sort();
+            for filename in filenames {
+                let controls = &controlled[filename].control_positions;
+                let entries = controls.iter().map(|control| {
+                    let mut entry = serde_json::to_value(control).unwrap();
+                    let children: Vec<&str> = docx_cc::get_contained_control(controls, control).map(|child| child.get_tag()).collect();
+                    entry["children"] = serde_json::json!(children);
+                    entry
+                }).collect();
+                listing.insert(filename.clone(), serde_json::Value::Array(entries));
+            }
+            let writer = BufWriter::new(std::io::stdout().lock());
+            serde_json::to_writer_pretty(writer, &listing).unwrap();
+            println!();
+        }
+        Commands::Inventory { out } => {
+            let data = load_template(&args.template_path);
+            let report = docx_cc::inventory(&data).unwrap();
+            let output_file = fs::File::create(out).unwrap();
+            let writer = BufWriter::new(output_file);
+            serde_json::to_writer_pretty(writer, &report).unwrap();
         }
     }
 }
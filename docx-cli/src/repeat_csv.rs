@@ -0,0 +1,31 @@
+//! Items of repeating sections read from CSV files, for the --repeat-csv option of the map command.
+
+use std::collections::HashMap;
+
+/// Parse a `SectionTag=path.csv` argument into the section tag and path.
+pub fn parse_section_file(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((tag, path)) if !tag.is_empty() && !path.is_empty() => Ok((tag.to_string(), path.to_string())),
+        _ => Err(format!("expected SectionTag=path.csv, got {:?}", value)),
+    }
+}
+
+/// Read the items of a repeating section, one per row. The header row names the tag each column fills, columns mapping to no control within the section item or given twice are rejected, while controls without a column count as missing.
+pub fn read_section_items(path: &str, section: &str, schema: &HashMap<String, docx_cc::SectionSchema>) -> Result<Vec<docx_cc::Mapping>, String> {
+    let fields = &schema.get(section).ok_or_else(|| format!("{}: the template has no repeating section {:?}", path, section))?.fields;
+    let mut reader = csv::Reader::from_path(path).map_err(|e| format!("{}: {}", path, e))?;
+    let headers = reader.headers().map_err(|e| format!("{}: {}", path, e))?.clone();
+    let unknown: Vec<&str> = headers.iter().filter(|column| !fields.iter().any(|field| field == column)).collect();
+    if !unknown.is_empty() {
+        return Err(format!("{}: columns {:?} are not tags within the repeating section {:?}, expected any of {:?}", path, unknown, section, fields));
+    }
+    if let Some(duplicate) = headers.iter().enumerate().find_map(|(index, column)| headers.iter().skip(index + 1).any(|other| other == column).then_some(column)) {
+        return Err(format!("{}: column {:?} is given more than once", path, duplicate));
+    }
+    let mut items = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("{}: {}", path, e))?;
+        items.push(headers.iter().zip(record.iter()).map(|(tag, value)| (tag.to_string(), value.to_string())).collect());
+    }
+    Ok(items)
+}
@@ -0,0 +1,61 @@
+//! Records for the batch command, read from a SQLite database.
+
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+
+/// Run the query and return each row as a mapping of column name to value. NULL and BLOB values are left out, so their controls count as missing.
+pub fn query_records(path: &str, query: &str) -> rusqlite::Result<Vec<docx_cc::Mapping>> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement = connection.prepare(query)?;
+    let columns: Vec<(String, Option<String>)> = statement.columns().iter().map(|column| (column.name().to_string(), column.decl_type().map(str::to_ascii_uppercase))).collect();
+    let mut rows = statement.query([])?;
+    let mut records = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut record = docx_cc::Mapping::new();
+        for (index, (name, decl_type)) in columns.iter().enumerate() {
+            if let Some(text) = column_text(row.get_ref(index)?, decl_type.as_deref()) {
+                record.insert(name.clone(), text);
+            }
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Text of a column value. SQLite has no date type, so columns declared as a date or time have numbers read as Unix time (INTEGER) or Julian day (REAL) and written as ISO 8601, while text dates are taken as they are.
+fn column_text(value: ValueRef, decl_type: Option<&str>) -> Option<String> {
+    let with_time = decl_type.is_some_and(|t| t.contains("TIME"));
+    let is_date = with_time || decl_type.is_some_and(|t| t.contains("DATE"));
+    match value {
+        ValueRef::Null | ValueRef::Blob(_) => None,
+        ValueRef::Integer(seconds) if is_date => Some(iso_datetime(seconds, with_time)),
+        ValueRef::Real(julian_day) if is_date => Some(iso_datetime(((julian_day - 2440587.5) * 86400.0).round() as i64, with_time)),
+        ValueRef::Integer(number) => Some(number.to_string()),
+        ValueRef::Real(number) => Some(number.to_string()),
+        ValueRef::Text(text) => Some(String::from_utf8_lossy(text).into_owned()),
+    }
+}
+
+/// Format Unix time as an ISO 8601 date, with the time of day if `with_time` is set.
+fn iso_datetime(seconds: i64, with_time: bool) -> String {
+    docx_cc::Date::from_unix(seconds).format(if with_time { "yyyy-MM-dd HH:mm:ss" } else { "yyyy-MM-dd" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_columns() {
+        assert_eq!(column_text(ValueRef::Integer(1709229909), Some("DATETIME")).unwrap(), "2024-02-29 18:05:09");
+        assert_eq!(column_text(ValueRef::Integer(1709229909), Some("DATE")).unwrap(), "2024-02-29");
+        assert_eq!(column_text(ValueRef::Integer(-86400), Some("DATE")).unwrap(), "1969-12-31");
+        // Julian day 2460370.25 is 2024-02-29 18:00 UTC
+        assert_eq!(column_text(ValueRef::Real(2460370.25), Some("TIMESTAMP")).unwrap(), "2024-02-29 18:00:00");
+        assert_eq!(column_text(ValueRef::Real(2440587.5), Some("DATE")).unwrap(), "1970-01-01");
+        assert_eq!(column_text(ValueRef::Text(b"24.12.2012"), Some("DATE")).unwrap(), "24.12.2012");
+        assert_eq!(column_text(ValueRef::Integer(1709229909), Some("INTEGER")).unwrap(), "1709229909");
+        assert_eq!(column_text(ValueRef::Real(2.5), None).unwrap(), "2.5");
+        assert_eq!(column_text(ValueRef::Null, Some("DATE")), None);
+    }
+}
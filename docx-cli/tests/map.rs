@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::process::{Command, Stdio};
+
+fn document_xml(path: &std::path::Path) -> String {
+    let data = docx_cc::list_zip_contents(fs::File::open(path).unwrap()).unwrap();
+    String::from_utf8(data["word/document.xml"].clone()).unwrap()
+}
+
+#[test]
+fn map_with_mappings_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let mappings_path = dir.path().join("mappings.json");
+    let output_path = dir.path().join("output.docx");
+    fs::write(&mappings_path, r#"{"mappings": {"Title": "Brave New World", "Author": "Aldous Huxley"}, "repeat_mappings": {}}"#).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_docx-cli"))
+        .args(["--template-path", "../docx-cc/tests/data/content_controlled_document.docx", "map", "--mappings"])
+        .arg(&mappings_path)
+        .arg("--")
+        .arg(&output_path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let document = document_xml(&output_path);
+    assert!(document.contains("Brave New World"));
+
+    let template = docx_cc::list_zip_contents(fs::File::open("../docx-cc/tests/data/content_controlled_document.docx").unwrap()).unwrap();
+    let controlled = docx_cc::get_content_controls(&template).unwrap();
+    let mappings = HashMap::from([("Title".to_string(), "Brave New World".to_string()), ("Author".to_string(), "Aldous Huxley".to_string())]);
+    let expected = docx_cc::map_content_controls(&template, &controlled, &mappings, &HashMap::new());
+    assert_eq!(document, String::from_utf8(expected["word/document.xml"].clone()).unwrap());
+}
+
+#[test]
+fn list_controls() {
+    let output = Command::new(env!("CARGO_BIN_EXE_docx-cli"))
+        .args(["--template-path", "../docx-cc/tests/data/repeat_header.docx", "list"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let listing: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let controls = listing["word/document.xml"].as_array().unwrap();
+    assert_eq!(controls[0]["tag"], "People");
+    assert_eq!(controls[0]["type"], "RepeatingSection");
+    assert_eq!(controls[0]["children"], serde_json::json!(["PeopleHeader", "Person", "Name", "Role"]));
+    assert_eq!(controls[2]["tag"], "Person");
+    assert_eq!(controls[2]["depth"], 2);
+    assert_eq!(controls[2]["children"], serde_json::json!(["Name", "Role"]));
+    assert_eq!(listing["word/header1.xml"][0]["tag"], "Author");
+}
+
+#[test]
+fn clear_stdin_to_stdout() {
+    let template = fs::read("../docx-cc/tests/data/content_controlled_document.docx").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_docx-cli"))
+        .args(["--template-path", "-", "clear", "--", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&template).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let cleared = docx_cc::list_zip_contents(Cursor::new(output.stdout)).unwrap();
+    let expected = docx_cc::remove_content_controls(&docx_cc::list_zip_contents(Cursor::new(template)).unwrap()).unwrap();
+    assert_eq!(cleared, expected);
+}
+
+fn map_with_repeat_csv(dir: &std::path::Path, csv: &str) -> std::process::Output {
+    let csv_path = dir.join("people.csv");
+    fs::write(&csv_path, csv).unwrap();
+    let mut repeat_csv = std::ffi::OsString::from("People=");
+    repeat_csv.push(&csv_path);
+    Command::new(env!("CARGO_BIN_EXE_docx-cli"))
+        .args(["--template-path", "../docx-cc/tests/data/repeat_header.docx", "map", "--repeat-csv"])
+        .arg(repeat_csv)
+        .arg("--")
+        .arg(dir.join("output.docx"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn repeat_csv_quoted_fields() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = map_with_repeat_csv(dir.path(), "Name,Role\n\"Doe, Jane\",\"Lead\nand founder\"\nBob,Developer\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let document = document_xml(&dir.path().join("output.docx"));
+    assert!(document.contains("Doe, Jane"));
+    assert!(document.contains("Lead"));
+    assert!(document.contains("and founder"));
+    assert!(document.contains("Developer"));
+    assert!(document.find("Doe, Jane").unwrap() < document.find("Bob").unwrap());
+}
+
+#[test]
+fn repeat_csv_missing_column() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = map_with_repeat_csv(dir.path(), "Name\nAlice\nBob\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let document = document_xml(&dir.path().join("output.docx"));
+    assert!(document.contains("Alice"));
+    assert!(document.contains("Bob"));
+}
+
+#[test]
+fn repeat_csv_rejected_columns() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = map_with_repeat_csv(dir.path(), "Name,Name\nAlice,Bob\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(r#"column "Name" is given more than once"#));
+
+    let output = map_with_repeat_csv(dir.path(), "Name,Salary\nAlice,1000\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(r#"columns ["Salary"] are not tags within the repeating section "People""#));
+    assert!(!dir.path().join("output.docx").exists());
+}
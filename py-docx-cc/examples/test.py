@@ -1,5 +1,5 @@
 from _typeshed import ProfileFunction
-from py_docx_cc import get_content_controls, map_content_controls
+from py_docx_cc import get_content_controls, map_content_controls, map_content_controls_path
 
 
 with open("./lb_complex.docx", "rb") as f:
@@ -11,6 +11,7 @@ for key, meta in controls.items():
     print(key)
     print(meta.children_tags)
     print(meta.types)
+    print(meta.values)
 
 with open("./lb_complex.docx", "rb") as f:
     data = f.read()
@@ -20,3 +21,5 @@ mapped = map_content_controls(data, {}, {"Hauptbefund": [{"Gen": "ABC1"}, {"Gen"
 
 with open("./lb_complex_mapped.docx", "wb") as f:
     f.write(mapped)
+
+map_content_controls_path("./lb_complex.docx", "./lb_complex_mapped_path.docx", {}, {"Hauptbefund": [{"Gen": "ABC1"}]})
@@ -1,45 +1,67 @@
 use std::collections::HashMap;
+use std::fs;
 use std::io;
 use std::borrow::Cow;
+use pyo3::create_exception;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 
-#[pyfunction]
-fn map_content_controls<'a>(template_data: Vec<u8>, mappings: docx_cc::Mapping, repeat_mappings: docx_cc::RepeatMapping) -> Cow<'a, [u8]> {
+create_exception!(py_docx_cc, DocxError, PyValueError, "A document cannot be read or filled, e.g. as it is not a docx archive or its XML is malformed.");
+
+fn docx_error(e: impl Into<docx_cc::DocxError>) -> PyErr {
+    DocxError::new_err(e.into().to_string())
+}
+
+fn load_data(template_data: Vec<u8>) -> PyResult<docx_cc::ZipData> {
     let cursor = io::Cursor::new(template_data);
     let reader = io::BufReader::new(cursor);
-    let data = docx_cc::list_zip_contents(reader).unwrap();
-    let controlled_docs = docx_cc::get_content_controls(&data);
-    let mapped_data = docx_cc::map_content_controls(&data, &controlled_docs, &mappings, &repeat_mappings);
+    docx_cc::list_zip_contents(reader).map_err(docx_error)
+}
+
+fn zip_data<'a>(data: &docx_cc::ZipData) -> PyResult<Cow<'a, [u8]>> {
     let mut buffer: Vec<u8> = Vec::new();
     let mut outc = io::Cursor::new(&mut buffer);
-    let _ = docx_cc::zip_dir(&mapped_data, &mut outc);
+    docx_cc::zip_dir(data, &mut outc).map_err(docx_error)?;
 
-    Cow::Owned(buffer)
+    Ok(Cow::Owned(buffer))
 }
 
 #[pyfunction]
-fn remove_content_controls<'a>(template_data: Vec<u8>) -> Cow<'a, [u8]> {
-    let cursor = io::Cursor::new(template_data);
-    let reader = io::BufReader::new(cursor);
-    let data = docx_cc::list_zip_contents(reader).unwrap();
-    let result = docx_cc::remove_content_controls(&data);
-    let mut buffer: Vec<u8> = Vec::new();
-    let mut outc = io::Cursor::new(&mut buffer);
-    let _ = docx_cc::zip_dir(&result, &mut outc);
+fn map_content_controls<'a>(template_data: Vec<u8>, mappings: docx_cc::Mapping, repeat_mappings: docx_cc::RepeatMapping) -> PyResult<Cow<'a, [u8]>> {
+    let data = load_data(template_data)?;
+    let controlled_docs = docx_cc::get_content_controls(&data).map_err(docx_error)?;
+    let mapped_data = docx_cc::map_content_controls_with(&data, &controlled_docs, &mappings, &repeat_mappings, &docx_cc::MapOptions::default()).map_err(docx_error)?;
+    zip_data(&mapped_data)
+}
 
-    Cow::Owned(buffer)
+/// Fill the template at `template_path` and write the result to `output_path`. The template is
+/// streamed from disk rather than passed as bytes, so large templates are never held in Python.
+#[pyfunction]
+fn map_content_controls_path(template_path: String, output_path: String, mappings: docx_cc::Mapping, repeat_mappings: docx_cc::RepeatMapping) -> PyResult<()> {
+    let input = io::BufReader::new(fs::File::open(template_path)?);
+    let output = io::BufWriter::new(fs::File::create(output_path)?);
+    docx_cc::process_docx(input, output, &mappings, &repeat_mappings, &docx_cc::MapOptions::default()).map_err(docx_error)
+}
+
+#[pyfunction]
+fn remove_content_controls<'a>(template_data: Vec<u8>) -> PyResult<Cow<'a, [u8]>> {
+    let data = load_data(template_data)?;
+    let result = docx_cc::remove_content_controls(&data).map_err(docx_error)?;
+    zip_data(&result)
 }
 
 #[pyclass(get_all)]
 pub struct ContentControlMetadata {
     pub types: Vec<String>,
-    pub children_tags: Vec<String>
+    pub children_tags: Vec<String>,
+    /// Current text of every control with the tag, in the order of types
+    pub values: Vec<String>
 }
 
 impl ContentControlMetadata {
     fn new() -> Self {
         ContentControlMetadata {
-            types: Vec::new(), children_tags: Vec::new()
+            types: Vec::new(), children_tags: Vec::new(), values: Vec::new()
         }
     }
 
@@ -50,20 +72,23 @@ impl ContentControlMetadata {
     fn add_child(&mut self, child_tag: String) {
         self.children_tags.push(child_tag)
     }
+
+    fn add_value(&mut self, value: String) {
+        self.values.push(value)
+    }
 }
 
 #[pyfunction]
 fn get_content_controls(template_data: Vec<u8>) -> PyResult<HashMap<String, ContentControlMetadata>> {
-    let cursor = io::Cursor::new(template_data);
-    let reader = io::BufReader::new(cursor);
-    let data = docx_cc::list_zip_contents(reader).unwrap();
-    let controlled_docs = docx_cc::get_content_controls(&data);
+    let data = load_data(template_data)?;
+    let controlled_docs = docx_cc::get_content_controls(&data).map_err(docx_error)?;
 
     let mut result = HashMap::new();
     for (_name, docdata) in controlled_docs {
         for control in docdata.control_positions.iter() {
             let entry = result.entry(control.get_tag().to_string()).or_insert(ContentControlMetadata::new());
             entry.add_type(control.get_type().to_string());
+            entry.add_value(docdata.get_text(control));
             for contained_control in docx_cc::get_contained_control(&docdata.control_positions, control) {
                 entry.add_child(contained_control.get_tag().to_string())
             }
@@ -72,13 +97,24 @@ fn get_content_controls(template_data: Vec<u8>) -> PyResult<HashMap<String, Cont
     Ok(result)
 }
 
+/// Tags used by more than one control, with the number of controls using them. These are merged
+/// into a single entry by `get_content_controls`.
+#[pyfunction]
+fn find_duplicate_tags(template_data: Vec<u8>) -> PyResult<HashMap<String, usize>> {
+    let data = load_data(template_data)?;
+    let controlled_docs = docx_cc::get_content_controls(&data).map_err(docx_error)?;
+    Ok(docx_cc::find_duplicate_tags(&controlled_docs))
+}
 
 /// A Python module implemented in Rust.
 #[pymodule]
 #[pyo3(name = "py_docx_cc")]
 fn py_docx_cc(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("DocxError", m.py().get_type_bound::<DocxError>())?;
     m.add_function(wrap_pyfunction!(remove_content_controls, m)?)?;
     m.add_function(wrap_pyfunction!(map_content_controls, m)?)?;
+    m.add_function(wrap_pyfunction!(map_content_controls_path, m)?)?;
     m.add_function(wrap_pyfunction!(get_content_controls, m)?)?;
+    m.add_function(wrap_pyfunction!(find_duplicate_tags, m)?)?;
     Ok(())
 }